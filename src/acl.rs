@@ -0,0 +1,233 @@
+use std::{fmt, str};
+
+use crate::{db, errors};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    Read,
+    ReadWrite,
+}
+
+impl Permission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::Read => "read",
+            Permission::ReadWrite => "read_write",
+        }
+    }
+}
+
+impl str::FromStr for Permission {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Permission::Read),
+            "read_write" => Ok(Permission::ReadWrite),
+            other => Err(format!("Unknown permission: `{}`", other)),
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Principal {
+    User(i32),
+    Group(String),
+}
+
+impl Principal {
+    /// Values for the `user_id` and `group_name` columns of `acl_entries`.
+    pub fn columns(&self) -> (Option<i32>, Option<&str>) {
+        match self {
+            Principal::User(user_id) => (Some(*user_id), None),
+            Principal::Group(group_name) => (None, Some(group_name)),
+        }
+    }
+}
+
+/// `path` without empty or `.` components, or `None` if it has a `..`
+/// component, so that entries are only ever matched against plain paths.
+pub fn normalize_path(path: &str) -> Option<String> {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => return None,
+            component => components.push(component),
+        }
+    }
+    Some(components.join("/"))
+}
+
+/// Whether `prefix` is `path` or one of its parent directories.
+fn is_path_prefix(prefix: &str, path: &str) -> bool {
+    prefix.is_empty()
+        || path == prefix
+        || (path.starts_with(prefix) && path[prefix.len()..].starts_with('/'))
+}
+
+/// Permissions of one session within a namespace. A namespace without any
+/// ACL entries is open to everyone; otherwise the entry with the longest
+/// matching path decides, and paths without a matching entry are hidden.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessControl {
+    restricted: bool,
+    entries: Vec<(String, Permission)>,
+}
+
+impl AccessControl {
+    pub fn deny_all() -> Self {
+        Self {
+            restricted: true,
+            entries: Vec::new(),
+        }
+    }
+
+    pub async fn load(
+        database: &dyn db::Database,
+        namespace: &db::namespaces::Namespace,
+        user: Option<&db::users::User>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if !database.namespace_has_entries(namespace.id()).await? {
+            return Ok(Self {
+                restricted: false,
+                entries: Vec::new(),
+            });
+        }
+        let entries = match user {
+            Some(user) => database.entries_for_user(namespace.id(), user).await?,
+            None => Vec::new(),
+        };
+        Ok(Self {
+            restricted: true,
+            entries,
+        })
+    }
+
+    pub fn permission(&self, path: &str) -> Option<Permission> {
+        let path = normalize_path(path)?;
+        if !self.restricted {
+            return Some(Permission::ReadWrite);
+        }
+        self.entries
+            .iter()
+            .filter(|(prefix, _)| is_path_prefix(prefix, &path))
+            .max_by_key(|(prefix, permission)| (prefix.len(), *permission))
+            .map(|(_, permission)| *permission)
+    }
+
+    pub fn can_read(&self, path: &str) -> bool {
+        self.permission(path).is_some()
+    }
+
+    pub fn check_write(&self, path: &str) -> Result<(), errors::ServerTcpError> {
+        match self.permission(path) {
+            Some(Permission::ReadWrite) => Ok(()),
+            _ => Err(errors::ServerTcpError::PermissionDenied(path.to_string())),
+        }
+    }
+
+    /// Like `check_write`, but also fails if an entry below `path` grants
+    /// less, for operations that take everything below `path` with them.
+    pub fn check_write_tree(&self, path: &str) -> Result<(), errors::ServerTcpError> {
+        self.check_write(path)?;
+        let path = normalize_path(path).unwrap_or_default();
+        for (prefix, _) in &self.entries {
+            if *prefix != path
+                && is_path_prefix(&path, prefix)
+                && self.permission(prefix) != Some(Permission::ReadWrite)
+            {
+                return Err(errors::ServerTcpError::PermissionDenied(prefix.to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn restricted(entries: &[(&str, Permission)]) -> AccessControl {
+        AccessControl {
+            restricted: true,
+            entries: entries
+                .iter()
+                .map(|(path, permission)| (path.to_string(), *permission))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn normalize_path_drops_empty_and_current_components() {
+        assert_eq!(normalize_path("/a//b/./c/"), Some("a/b/c".to_string()));
+        assert_eq!(normalize_path(""), Some(String::new()));
+        assert_eq!(normalize_path("a/../b"), None);
+    }
+
+    #[test]
+    fn longest_matching_prefix_decides() {
+        let access = restricted(&[
+            ("", Permission::Read),
+            ("shared", Permission::ReadWrite),
+            ("shared/archive", Permission::Read),
+        ]);
+        assert_eq!(access.permission("top.txt"), Some(Permission::Read));
+        assert_eq!(access.permission("shared"), Some(Permission::ReadWrite));
+        assert_eq!(
+            access.permission("shared/a.txt"),
+            Some(Permission::ReadWrite)
+        );
+        assert_eq!(
+            access.permission("shared/archive/a"),
+            Some(Permission::Read)
+        );
+        assert_eq!(access.permission("sharedother"), Some(Permission::Read));
+    }
+
+    #[test]
+    fn paths_without_an_entry_are_hidden() {
+        let access = restricted(&[("docs", Permission::Read)]);
+        assert!(access.can_read("docs/a.txt"));
+        assert!(!access.can_read("docsother"));
+        assert!(!access.can_read("other"));
+        assert!(access.check_write("docs/a.txt").is_err());
+    }
+
+    #[test]
+    fn traversal_does_not_match_a_prefix() {
+        let access = restricted(&[("public", Permission::ReadWrite)]);
+        assert!(!access.can_read("public/../private"));
+        assert!(access.check_write("public/../private").is_err());
+        assert!(!AccessControl {
+            restricted: false,
+            entries: Vec::new(),
+        }
+        .can_read("../outside"));
+    }
+
+    #[test]
+    fn equal_prefixes_take_the_wider_permission() {
+        let access = restricted(&[("a", Permission::Read), ("a", Permission::ReadWrite)]);
+        assert!(access.check_write("a/b").is_ok());
+    }
+
+    #[test]
+    fn check_write_tree_considers_entries_below() {
+        let access = restricted(&[
+            ("projects", Permission::ReadWrite),
+            ("projects/locked", Permission::Read),
+        ]);
+        assert!(access.check_write("projects").is_ok());
+        assert!(access.check_write_tree("projects").is_err());
+        assert!(access.check_write_tree("projects/open").is_ok());
+        assert!(access.check_write_tree("projects/locked").is_err());
+        assert!(access.check_write_tree("projectsother").is_err());
+    }
+}
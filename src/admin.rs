@@ -0,0 +1,329 @@
+//! Operator commands run from the `hcs_server` binary instead of serving, so
+//! users, devices, namespaces, quotas, access control and the change log can
+//! be managed without touching the database directly.
+
+use std::error::Error;
+use std::io::{self, BufRead, Write};
+use std::path;
+
+use crate::{acl, compaction, config, db, fsck};
+
+/// Creates every table and the default namespace.
+pub async fn init_db(
+    config: &config::ServerConfig,
+    database: &dyn db::Database,
+) -> Result<(), Box<dyn Error>> {
+    migrate(database).await?;
+    db::namespaces::ensure_default_namespace(
+        config.file_handler_config(),
+        config.storage_config(),
+        database,
+    )
+    .await?;
+    println!("Database initialized");
+    Ok(())
+}
+
+/// Applies pending schema migrations.
+pub async fn migrate(database: &dyn db::Database) -> Result<(), Box<dyn Error>> {
+    let applied = database.migrate().await?;
+    println!(
+        "Applied {} migrations, schema is at version {}",
+        applied,
+        database.latest_schema_version()
+    );
+    Ok(())
+}
+
+pub async fn add_user(username: &str, database: &dyn db::Database) -> Result<(), Box<dyn Error>> {
+    let password = read_password()?;
+    let user = db::users::create_user(username, &password, database).await?;
+    println!("Created user `{}` with id {}", user.username(), user.id());
+    Ok(())
+}
+
+pub async fn remove_user(
+    username: &str,
+    database: &dyn db::Database,
+) -> Result<(), Box<dyn Error>> {
+    if !database.remove_user(username).await? {
+        return Err(format!("No user named `{}`", username).into());
+    }
+    println!("Removed user `{}`", username);
+    Ok(())
+}
+
+pub async fn set_password(
+    username: &str,
+    database: &dyn db::Database,
+) -> Result<(), Box<dyn Error>> {
+    let password = read_password()?;
+    if !db::users::set_password(username, &password, database).await? {
+        return Err(format!("No user named `{}`", username).into());
+    }
+    println!("Changed password of `{}`", username);
+    Ok(())
+}
+
+pub async fn list_devices(database: &dyn db::Database) -> Result<(), Box<dyn Error>> {
+    for device in database.list_devices().await? {
+        println!(
+            "{}\t{}\t{}\tlast seen {}{}",
+            device.device_id(),
+            device.hostname(),
+            device.client_version(),
+            device.last_seen(),
+            if device.revoked() {
+                "\trevoked"
+            } else if !device.approved() {
+                "\tawaiting approval"
+            } else {
+                ""
+            }
+        );
+    }
+    Ok(())
+}
+
+pub async fn approve_device(
+    device_id: &str,
+    database: &dyn db::Database,
+) -> Result<(), Box<dyn Error>> {
+    if !database.approve_device(device_id).await? {
+        return Err(format!("No device with id `{}`", device_id).into());
+    }
+    println!("Approved device `{}`", device_id);
+    Ok(())
+}
+
+pub async fn revoke_device(
+    device_id: &str,
+    database: &dyn db::Database,
+) -> Result<(), Box<dyn Error>> {
+    if !database.revoke_device(device_id).await? {
+        return Err(format!("No device with id `{}`", device_id).into());
+    }
+    println!("Revoked device `{}`", device_id);
+    Ok(())
+}
+
+pub async fn create_namespace(
+    name: &str,
+    storage_directory: &path::Path,
+    end_to_end: bool,
+    config: &config::ServerConfig,
+    database: &dyn db::Database,
+) -> Result<(), Box<dyn Error>> {
+    if database.get_namespace(name).await?.is_some() {
+        return Err(format!("Namespace `{}` already exists", name).into());
+    }
+    let namespace = db::namespaces::create_namespace(
+        name,
+        storage_directory,
+        end_to_end,
+        config.storage_config(),
+        database,
+    )
+    .await?;
+    println!(
+        "Created namespace `{}` in `{}`",
+        namespace.name(),
+        namespace.storage_directory().display()
+    );
+    Ok(())
+}
+
+pub async fn list_namespaces(
+    config: &config::ServerConfig,
+    database: &dyn db::Database,
+) -> Result<(), Box<dyn Error>> {
+    for namespace in db::namespaces::list_namespaces(config.storage_config(), database).await? {
+        let quota = match database.get_quota(namespace.name()).await? {
+            Some(quota) => format!("\tquota {} bytes", quota),
+            None => String::new(),
+        };
+        println!(
+            "{}\t{}{}{}",
+            namespace.name(),
+            namespace.storage_directory().display(),
+            if namespace.is_end_to_end() {
+                "\tend-to-end"
+            } else {
+                ""
+            },
+            quota
+        );
+    }
+    Ok(())
+}
+
+pub async fn set_quota(
+    namespace: &str,
+    quota_bytes: u64,
+    config: &config::ServerConfig,
+    database: &dyn db::Database,
+) -> Result<(), Box<dyn Error>> {
+    let namespace = get_namespace(namespace, config, database).await?;
+    database.set_quota(namespace.name(), quota_bytes).await?;
+    println!(
+        "Set quota of `{}` to {} bytes",
+        namespace.name(),
+        quota_bytes
+    );
+    Ok(())
+}
+
+pub async fn remove_quota(
+    namespace: &str,
+    database: &dyn db::Database,
+) -> Result<(), Box<dyn Error>> {
+    if !database.remove_quota(namespace).await? {
+        return Err(format!("Namespace `{}` has no quota", namespace).into());
+    }
+    println!("Removed quota of `{}`", namespace);
+    Ok(())
+}
+
+/// Grants `permission` on `path` and everything below it. The principal is
+/// the user named `username` or the group named `group`, whichever is given.
+pub async fn grant(
+    namespace: &str,
+    path: &str,
+    username: Option<&str>,
+    group: Option<&str>,
+    permission: acl::Permission,
+    config: &config::ServerConfig,
+    database: &dyn db::Database,
+) -> Result<(), Box<dyn Error>> {
+    let namespace = get_namespace(namespace, config, database).await?;
+    let principal = get_principal(username, group, database).await?;
+    database
+        .grant(namespace.id(), path, &principal, permission)
+        .await?;
+    println!(
+        "Granted {} on `{}` in `{}`",
+        permission,
+        path,
+        namespace.name()
+    );
+    Ok(())
+}
+
+pub async fn revoke(
+    namespace: &str,
+    path: &str,
+    username: Option<&str>,
+    group: Option<&str>,
+    config: &config::ServerConfig,
+    database: &dyn db::Database,
+) -> Result<(), Box<dyn Error>> {
+    let namespace = get_namespace(namespace, config, database).await?;
+    let principal = get_principal(username, group, database).await?;
+    if !database.revoke(namespace.id(), path, &principal).await? {
+        return Err(format!("No entry for `{}` in `{}`", path, namespace.name()).into());
+    }
+    println!("Revoked access to `{}` in `{}`", path, namespace.name());
+    Ok(())
+}
+
+/// Prints the namespace's changes after version `since`, oldest first.
+pub async fn list_changes(
+    namespace: &str,
+    since: i64,
+    config: &config::ServerConfig,
+    database: &dyn db::Database,
+) -> Result<(), Box<dyn Error>> {
+    let namespace = get_namespace(namespace, config, database).await?;
+    let server_version = database.get_server_version(namespace.id()).await?;
+    let changes = database
+        .get_changes(namespace.id(), since, server_version)
+        .await?;
+    for (version, change_event) in changes {
+        println!("{}\t{:?}", version, change_event);
+    }
+    Ok(())
+}
+
+/// Checks the given namespace, or every namespace if none is given. Fails if
+/// any discrepancy was found.
+pub async fn fsck(
+    namespace: Option<&str>,
+    verify_checksums: bool,
+    repair: bool,
+    config: &config::ServerConfig,
+    database: &dyn db::Database,
+) -> Result<(), Box<dyn Error>> {
+    let namespaces = match namespace {
+        Some(name) => vec![get_namespace(name, config, database).await?],
+        None => db::namespaces::list_namespaces(config.storage_config(), database).await?,
+    };
+
+    let mut found = 0;
+    for namespace in namespaces {
+        let discrepancies =
+            fsck::check_namespace(database, &namespace, verify_checksums, repair).await?;
+        for discrepancy in &discrepancies {
+            println!("{}: {}", namespace.name(), discrepancy);
+        }
+        found += discrepancies.len();
+    }
+
+    match (found, repair) {
+        (0, _) => println!("No discrepancies found"),
+        (found, true) => println!("Repaired {} discrepancies", found),
+        (found, false) => return Err(format!("Found {} discrepancies", found).into()),
+    }
+    Ok(())
+}
+
+/// Compacts the change log of every namespace.
+pub async fn gc(
+    config: &config::ServerConfig,
+    database: &dyn db::Database,
+) -> Result<(), Box<dyn Error>> {
+    let removed = compaction::compact_all(config.storage_config(), database).await?;
+    println!("Removed {} changes", removed);
+    Ok(())
+}
+
+async fn get_namespace(
+    name: &str,
+    config: &config::ServerConfig,
+    database: &dyn db::Database,
+) -> Result<db::namespaces::Namespace, Box<dyn Error>> {
+    let namespace = db::namespaces::get_namespace(name, config.storage_config(), database).await?;
+    namespace.ok_or_else(|| format!("No namespace named `{}`", name).into())
+}
+
+async fn get_principal(
+    username: Option<&str>,
+    group: Option<&str>,
+    database: &dyn db::Database,
+) -> Result<acl::Principal, Box<dyn Error>> {
+    match (username, group) {
+        (Some(username), None) => {
+            let user = database.get_user(username).await?;
+            let user = user.ok_or_else(|| format!("No user named `{}`", username))?;
+            Ok(acl::Principal::User(user.id()))
+        }
+        (None, Some(group)) => Ok(acl::Principal::Group(group.to_string())),
+        _ => Err("Exactly one of a user and a group must be given".into()),
+    }
+}
+
+/// Reads a password from the first line of stdin, so it stays out of the
+/// shell history.
+fn read_password() -> io::Result<String> {
+    eprint!("Password: ");
+    io::stderr().flush()?;
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Password must not be empty",
+        ));
+    }
+    Ok(password.to_string())
+}
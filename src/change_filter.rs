@@ -0,0 +1,214 @@
+//! Restricts the change log sent to a client to the paths it may see. Moves
+//! that cross the boundary between visible and hidden paths become a delete
+//! or a create, so the client's tree stays consistent.
+
+use std::collections::LinkedList;
+use std::io;
+
+use hcs_lib::data;
+
+use crate::{snapshot, storage};
+
+pub fn filter_changes<F>(
+    changes: LinkedList<(i64, data::ChangeEvent)>,
+    storage: &dyn storage::StorageBackend,
+    is_visible: F,
+) -> LinkedList<(i64, data::ChangeEvent)>
+where
+    F: Fn(&str) -> bool,
+{
+    let mut filtered = LinkedList::new();
+    for (version, change_event) in changes {
+        for change_event in filter_change(change_event, storage, &is_visible) {
+            filtered.push_back((version, change_event));
+        }
+    }
+    filtered
+}
+
+fn filter_change<F>(
+    change_event: data::ChangeEvent,
+    storage: &dyn storage::StorageBackend,
+    is_visible: &F,
+) -> Vec<data::ChangeEvent>
+where
+    F: Fn(&str) -> bool,
+{
+    let keep_if = |visible: bool, change_event: data::ChangeEvent| {
+        if visible {
+            vec![change_event]
+        } else {
+            vec![]
+        }
+    };
+
+    match change_event {
+        data::ChangeEvent::File(file_event) => match file_event {
+            data::FileEvent::Create(ref file_create) => keep_if(
+                is_visible(file_create.path()),
+                data::ChangeEvent::File(file_event),
+            ),
+            data::FileEvent::Delete(ref file_delete) => keep_if(
+                is_visible(file_delete.path()),
+                data::ChangeEvent::File(file_event),
+            ),
+            data::FileEvent::Modify(ref file_modify) => keep_if(
+                is_visible(file_modify.path()),
+                data::ChangeEvent::File(file_event),
+            ),
+            data::FileEvent::Move(file_move) => {
+                match (
+                    is_visible(file_move.from_path()),
+                    is_visible(file_move.to_path()),
+                ) {
+                    (true, true) => vec![data::ChangeEvent::File(data::FileEvent::Move(file_move))],
+                    (true, false) => vec![data::ChangeEvent::File(data::FileEvent::Delete(
+                        data::FileDelete::new(file_move.from_path().to_string()),
+                    ))],
+                    (false, true) => vec![data::ChangeEvent::File(data::FileEvent::Create(
+                        data::FileCreate::new(file_move.to_path().to_string(), 0),
+                    ))],
+                    (false, false) => vec![],
+                }
+            }
+            data::FileEvent::UndoDelete(_) => vec![data::ChangeEvent::File(file_event)],
+        },
+        data::ChangeEvent::Directory(directory_event) => match directory_event {
+            data::DirectoryEvent::Create(ref directory_create) => keep_if(
+                is_visible(directory_create.path()),
+                data::ChangeEvent::Directory(directory_event),
+            ),
+            data::DirectoryEvent::Delete(ref directory_delete) => keep_if(
+                is_visible(directory_delete.path()),
+                data::ChangeEvent::Directory(directory_event),
+            ),
+            data::DirectoryEvent::Move(directory_move) => match (
+                is_visible(directory_move.from_path()),
+                is_visible(directory_move.to_path()),
+            ) {
+                (true, true) => vec![data::ChangeEvent::Directory(data::DirectoryEvent::Move(
+                    directory_move,
+                ))],
+                (true, false) => vec![data::ChangeEvent::Directory(data::DirectoryEvent::Delete(
+                    data::DirectoryDelete::new(directory_move.from_path().to_string()),
+                ))],
+                (false, true) => {
+                    match directory_contents(storage, directory_move.to_path(), is_visible) {
+                        Ok(change_events) => change_events,
+                        Err(err) => {
+                            log::error!(
+                                "Failed to list `{}` for a move into view: {}",
+                                directory_move.to_path(),
+                                err
+                            );
+                            vec![]
+                        }
+                    }
+                }
+                (false, false) => vec![],
+            },
+            data::DirectoryEvent::UndoDelete(_) => {
+                vec![data::ChangeEvent::Directory(directory_event)]
+            }
+        },
+        other => vec![other],
+    }
+}
+
+/// Creates for a directory that moved into view and everything currently
+/// stored below it.
+fn directory_contents<F>(
+    storage: &dyn storage::StorageBackend,
+    directory: &str,
+    is_visible: &F,
+) -> io::Result<Vec<data::ChangeEvent>>
+where
+    F: Fn(&str) -> bool,
+{
+    let mut change_events = vec![data::ChangeEvent::Directory(data::DirectoryEvent::Create(
+        data::DirectoryCreate::new(directory.to_string()),
+    ))];
+    change_events.extend(
+        snapshot::tree_changes(storage, directory)?
+            .into_iter()
+            .filter(|change_event| match change_event {
+                data::ChangeEvent::File(data::FileEvent::Create(file_create)) => {
+                    is_visible(file_create.path())
+                }
+                data::ChangeEvent::Directory(data::DirectoryEvent::Create(directory_create)) => {
+                    is_visible(directory_create.path())
+                }
+                _ => false,
+            }),
+    );
+    Ok(change_events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageBackend;
+    use crate::test_support;
+
+    fn filtered(
+        change_event: data::ChangeEvent,
+        storage: &dyn storage::StorageBackend,
+    ) -> Vec<String> {
+        let changes = LinkedList::from([(1, change_event)]);
+        filter_changes(changes, storage, |path| !path.starts_with("hidden"))
+            .iter()
+            .map(|(_, change_event)| test_support::describe(change_event))
+            .collect()
+    }
+
+    fn file_move(from_path: &str, to_path: &str) -> data::ChangeEvent {
+        data::ChangeEvent::File(data::FileEvent::Move(data::FileMove::new(
+            from_path.to_string(),
+            to_path.to_string(),
+        )))
+    }
+
+    fn directory_move(from_path: &str, to_path: &str) -> data::ChangeEvent {
+        data::ChangeEvent::Directory(data::DirectoryEvent::Move(data::DirectoryMove::new(
+            from_path.to_string(),
+            to_path.to_string(),
+        )))
+    }
+
+    #[test]
+    fn file_moves_across_the_boundary_become_deletes_and_creates() {
+        let storage = storage::MemoryStorage::new();
+        assert_eq!(filtered(file_move("a", "b"), &storage), ["move a b"]);
+        assert_eq!(filtered(file_move("a", "hidden/b"), &storage), ["delete a"]);
+        assert_eq!(filtered(file_move("hidden/a", "b"), &storage), ["create b"]);
+        assert!(filtered(file_move("hidden/a", "hidden/b"), &storage).is_empty());
+    }
+
+    #[test]
+    fn directories_moved_into_view_are_listed_from_storage() {
+        let storage = storage::MemoryStorage::new();
+        storage.create_dir("shown/sub").unwrap();
+        storage.create_dir("shown/hidden").unwrap();
+        test_support::write_file(&storage, "shown/sub/a.txt", "");
+
+        assert_eq!(
+            filtered(directory_move("shown", "elsewhere"), &storage),
+            ["move shown/ elsewhere/"]
+        );
+        assert_eq!(
+            filtered(directory_move("shown", "hidden"), &storage),
+            ["delete shown/"]
+        );
+        let mut created = filtered(directory_move("hidden", "shown"), &storage);
+        created.sort();
+        assert_eq!(
+            created,
+            [
+                "create shown/",
+                "create shown/hidden/",
+                "create shown/sub/",
+                "create shown/sub/a.txt"
+            ]
+        );
+    }
+}
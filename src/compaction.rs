@@ -0,0 +1,138 @@
+//! Squashes the part of a namespace's change log that every registered device
+//! has already consumed into a minimal baseline. Clients still behind the
+//! compaction horizon are sent a snapshot instead (see `snapshot`). Clients
+//! that never registered a device do not hold the horizon back, so entries
+//! they still have after a compacted delete are uploaded again.
+
+use std::collections::LinkedList;
+use std::sync::Arc;
+use std::time;
+
+use hcs_lib::data;
+
+use crate::{config, db};
+
+/// Compacts the namespace's change log up to the lowest version acknowledged
+/// by its devices. Returns the number of rows removed.
+pub async fn compact_namespace(
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let horizon = match database.min_acknowledged_version(namespace.id()).await? {
+        Some(horizon) => horizon,
+        None => {
+            log::debug!(
+                "No devices have synced namespace `{}`, skipping compaction",
+                namespace.name()
+            );
+            return Ok(0);
+        }
+    };
+    // Cursors are reported by clients, so they are never trusted to be behind
+    // the change log's end.
+    let horizon = horizon.min(database.get_server_version(namespace.id()).await?);
+    let compacted_version = database.get_compacted_version(namespace.id()).await?;
+    if horizon <= compacted_version {
+        return Ok(0);
+    }
+
+    let changes = database.get_changes(namespace.id(), 0, horizon).await?;
+    let before = changes.len();
+    let baseline = data::optimize_changes(changes.into_iter().collect::<LinkedList<_>>())
+        .into_iter()
+        .collect::<Vec<_>>();
+    let removed = before - baseline.len();
+
+    database
+        .replace_changes(namespace.id(), horizon, baseline)
+        .await?;
+    database
+        .remove_symlink_changes(namespace.id(), horizon)
+        .await?;
+    log::info!(
+        "Compacted namespace `{}` up to version {}, removed {} changes",
+        namespace.name(),
+        horizon,
+        removed
+    );
+    Ok(removed)
+}
+
+pub async fn compact_all(
+    storage_config: &config::StorageConfig,
+    database: &dyn db::Database,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut removed = 0;
+    let namespaces = db::namespaces::list_namespaces(storage_config, database).await?;
+    for namespace in namespaces {
+        removed += compact_namespace(database, &namespace).await?;
+    }
+    Ok(removed)
+}
+
+/// Compacts every namespace once per `interval`, forever.
+pub async fn run_periodically(
+    database: Arc<dyn db::Database>,
+    storage_config: config::StorageConfig,
+    interval: time::Duration,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Err(err) = compact_all(&storage_config, &*database).await {
+            log::error!("Change log compaction failed: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::test_support;
+
+    fn file_create(path: &str) -> data::ChangeEvent {
+        data::ChangeEvent::File(data::FileEvent::Create(data::FileCreate::new(
+            path.to_string(),
+            0,
+        )))
+    }
+
+    #[tokio::test]
+    async fn horizon_stops_at_the_server_version() {
+        let database = test_support::memory_database().await;
+        let (namespace, _) = test_support::memory_namespace(&database, "docs").await;
+        let device = database
+            .register_device("laptop", "host", "1.0", None)
+            .await
+            .unwrap();
+        for path in ["a", "b", "c"] {
+            database
+                .insert_change(namespace.id(), file_create(path), 0)
+                .await
+                .unwrap();
+        }
+        database
+            .acknowledge_version(device.id(), namespace.id(), 3)
+            .await
+            .unwrap();
+        // A cursor stored before cursors were limited to the server version
+        sqlx::query("UPDATE device_cursors SET server_version = 100")
+            .execute(database.pool())
+            .await
+            .unwrap();
+
+        compact_namespace(&database, &namespace).await.unwrap();
+
+        assert_eq!(
+            database
+                .get_compacted_version(namespace.id())
+                .await
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            database.get_server_version(namespace.id()).await.unwrap(),
+            3
+        );
+    }
+}
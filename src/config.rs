@@ -1,7 +1,11 @@
-use std::net;
+use std::{net, path, time};
 
 use hcs_lib::{config, server_database};
 
+mod load;
+
+pub use load::{load, ConfigError, ENV_PREFIX};
+
 #[derive(serde::Deserialize, Debug, Clone, PartialEq)]
 pub struct ServerConfig {
     #[serde(deserialize_with = "config::parse_log_filter")]
@@ -10,11 +14,271 @@ pub struct ServerConfig {
     db_config: server_database::DbConfig,
     tcp_config: TcpConfig,
     file_handler_config: server_database::ServerFileHandlerConfig,
+    #[serde(default)]
+    upload_config: UploadConfig,
+    #[serde(default)]
+    ignore_config: IgnoreConfig,
+    #[serde(default)]
+    compaction_config: CompactionConfig,
+    #[serde(default)]
+    consistency_config: ConsistencyConfig,
+    #[serde(default)]
+    move_config: MoveConfig,
+    #[serde(default)]
+    path_config: PathConfig,
+    #[serde(default)]
+    storage_config: StorageConfig,
 }
 
 #[derive(serde::Deserialize, Debug, Clone, PartialEq)]
 pub struct TcpConfig {
     addr: net::SocketAddr,
+
+    #[serde(default = "default_max_connections")]
+    max_connections: usize,
+    #[serde(default = "default_max_connections_per_ip")]
+    max_connections_per_ip: usize,
+
+    #[serde(default = "default_read_timeout_secs")]
+    read_timeout_secs: u64,
+    #[serde(default = "default_write_timeout_secs")]
+    write_timeout_secs: u64,
+    #[serde(default = "default_idle_timeout_secs")]
+    idle_timeout_secs: u64,
+    /// Longest a client may take over a single request, including its
+    /// uploads and downloads, however steadily it sends data.
+    #[serde(default = "default_transmission_timeout_secs")]
+    transmission_timeout_secs: u64,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct UploadConfig {
+    /// Largest file a client may upload, in bytes. Unlimited if not set.
+    #[serde(default)]
+    max_file_size: Option<u64>,
+    /// Free space, in bytes, that must remain on the storage volume after an
+    /// upload.
+    #[serde(default = "default_free_space_reserve")]
+    free_space_reserve: u64,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            max_file_size: None,
+            free_space_reserve: default_free_space_reserve(),
+        }
+    }
+}
+
+/// What to do with an incoming event for a path matched by `.hcsignore`.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum IgnoreAction {
+    /// Accept the event from the client but do not store it.
+    #[default]
+    Drop,
+    /// Send an error back to the client.
+    Reject,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct IgnoreConfig {
+    #[serde(default)]
+    action: IgnoreAction,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct CompactionConfig {
+    /// How often the change log is compacted. `0` disables compaction.
+    #[serde(default = "default_compaction_interval_secs")]
+    interval_secs: u64,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: default_compaction_interval_secs(),
+        }
+    }
+}
+
+/// What to do with a delete or move from a client whose source path does not
+/// exist in the storage directory.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingPathAction {
+    /// Record the change anyway and log an error.
+    #[default]
+    Record,
+    /// Send an error back to the client without recording the change.
+    Reject,
+    /// Skip deletes of missing paths and ask the client to upload the
+    /// destination of a file or symlink move instead. Moves of missing
+    /// directories are rejected, since their contents cannot be requested in
+    /// one upload.
+    Reconcile,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ConsistencyConfig {
+    #[serde(default)]
+    missing_paths: MissingPathAction,
+}
+
+/// What to do when the destination of a move from a client already exists.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CollisionPolicy {
+    /// Send an error back to the client.
+    #[default]
+    Reject,
+    /// Keep the existing destination in the versions directory, then replace
+    /// it.
+    Overwrite,
+    /// Move to the first free `name (n).ext` next to the destination instead.
+    Rename,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct MoveConfig {
+    #[serde(default)]
+    collision: CollisionPolicy,
+    /// Where destinations replaced under the overwrite policy are kept, one
+    /// subdirectory per namespace.
+    #[serde(default = "default_versions_directory")]
+    versions_directory: path::PathBuf,
+}
+
+impl Default for MoveConfig {
+    fn default() -> Self {
+        Self {
+            collision: CollisionPolicy::default(),
+            versions_directory: default_versions_directory(),
+        }
+    }
+}
+
+/// How incoming paths are canonicalized. Names that only differ in ways
+/// canonicalization ignores are reported as conflicts instead of being
+/// stored side by side.
+#[derive(serde::Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct PathConfig {
+    /// Normalize paths to Unicode NFC, as macOS clients may send NFD.
+    #[serde(default)]
+    normalize_unicode: bool,
+    /// Treat names that only differ in case as the same name.
+    #[serde(default)]
+    case_insensitive: bool,
+}
+
+/// Settings for storage backends other than a local directory. A namespace
+/// is kept in an S3-compatible object store when its storage directory is
+/// an `s3://bucket/prefix` URL.
+#[derive(serde::Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct StorageConfig {
+    #[serde(default)]
+    s3: S3Config,
+    /// Files are stored in plaintext if not set.
+    #[serde(default)]
+    encryption: Option<EncryptionConfig>,
+}
+
+/// Credentials are read from `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`.
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct S3Config {
+    /// Endpoint of a MinIO-style S3-compatible server. AWS if not set.
+    #[serde(default)]
+    endpoint: Option<String>,
+    #[serde(default = "default_s3_region")]
+    region: String,
+    /// Address buckets as `endpoint/bucket` rather than `bucket.endpoint`,
+    /// as most self-hosted servers expect.
+    #[serde(default)]
+    path_style: bool,
+    /// Files larger than this are uploaded in parts of this size, in bytes.
+    #[serde(default = "default_s3_part_size")]
+    part_size: usize,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Cipher {
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    #[default]
+    #[serde(rename = "xchacha20-poly1305")]
+    XChaCha20Poly1305,
+}
+
+/// Encryption of file contents at rest. Keys are 32 bytes, hex encoded,
+/// given inline or in a file. Exactly one of `key` and `key_file` must be set.
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct EncryptionConfig {
+    #[serde(default)]
+    cipher: Cipher,
+    #[serde(default)]
+    key: Option<String>,
+    #[serde(default)]
+    key_file: Option<path::PathBuf>,
+    /// Keys files may still be encrypted with, until `rotate-key` has
+    /// re-encrypted them with the current one.
+    #[serde(default)]
+    previous_key_files: Vec<path::PathBuf>,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            region: default_s3_region(),
+            path_style: false,
+            part_size: default_s3_part_size(),
+        }
+    }
+}
+
+fn default_max_connections() -> usize {
+    256
+}
+
+fn default_max_connections_per_ip() -> usize {
+    8
+}
+
+fn default_read_timeout_secs() -> u64 {
+    30
+}
+
+fn default_write_timeout_secs() -> u64 {
+    30
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    300
+}
+
+fn default_transmission_timeout_secs() -> u64 {
+    60 * 60
+}
+
+fn default_compaction_interval_secs() -> u64 {
+    60 * 60
+}
+
+fn default_versions_directory() -> path::PathBuf {
+    "_versions_directory".into()
+}
+
+fn default_free_space_reserve() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_s3_part_size() -> usize {
+    8 * 1024 * 1024
 }
 
 impl ServerConfig {
@@ -33,10 +297,208 @@ impl ServerConfig {
     pub fn file_handler_config(&self) -> &server_database::ServerFileHandlerConfig {
         &self.file_handler_config
     }
+
+    pub fn upload_config(&self) -> &UploadConfig {
+        &self.upload_config
+    }
+
+    pub fn ignore_config(&self) -> &IgnoreConfig {
+        &self.ignore_config
+    }
+
+    pub fn compaction_config(&self) -> &CompactionConfig {
+        &self.compaction_config
+    }
+
+    pub fn consistency_config(&self) -> &ConsistencyConfig {
+        &self.consistency_config
+    }
+
+    pub fn move_config(&self) -> &MoveConfig {
+        &self.move_config
+    }
+
+    pub fn path_config(&self) -> &PathConfig {
+        &self.path_config
+    }
+
+    pub fn storage_config(&self) -> &StorageConfig {
+        &self.storage_config
+    }
+
+    /// Takes over the fields of `new` that can change while the server is
+    /// running. Returns the fields that differ but only take effect after a
+    /// restart.
+    pub fn apply_reload(&mut self, new: ServerConfig) -> Vec<&'static str> {
+        let ServerConfig {
+            log_level,
+            db_config,
+            tcp_config,
+            file_handler_config,
+            upload_config,
+            ignore_config,
+            compaction_config,
+            consistency_config,
+            move_config,
+            path_config,
+            storage_config,
+        } = new;
+
+        let mut restart_required = Vec::new();
+        if db_config != self.db_config {
+            restart_required.push("db_config");
+        }
+        if tcp_config.addr != self.tcp_config.addr {
+            restart_required.push("tcp_config.addr");
+        }
+        if file_handler_config != self.file_handler_config {
+            restart_required.push("file_handler_config");
+        }
+        if compaction_config != self.compaction_config {
+            restart_required.push("compaction_config");
+        }
+        if storage_config != self.storage_config {
+            restart_required.push("storage_config");
+        }
+
+        self.log_level = log_level;
+        self.tcp_config = TcpConfig {
+            addr: self.tcp_config.addr,
+            ..tcp_config
+        };
+        self.upload_config = upload_config;
+        self.ignore_config = ignore_config;
+        self.consistency_config = consistency_config;
+        self.move_config = move_config;
+        self.path_config = path_config;
+        restart_required
+    }
 }
 
 impl TcpConfig {
     pub fn addr(&self) -> &net::SocketAddr {
         &self.addr
     }
+
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    pub fn max_connections_per_ip(&self) -> usize {
+        self.max_connections_per_ip
+    }
+
+    pub fn read_timeout(&self) -> time::Duration {
+        time::Duration::from_secs(self.read_timeout_secs)
+    }
+
+    pub fn write_timeout(&self) -> time::Duration {
+        time::Duration::from_secs(self.write_timeout_secs)
+    }
+
+    pub fn idle_timeout(&self) -> time::Duration {
+        time::Duration::from_secs(self.idle_timeout_secs)
+    }
+
+    pub fn transmission_timeout(&self) -> time::Duration {
+        time::Duration::from_secs(self.transmission_timeout_secs)
+    }
+}
+
+impl UploadConfig {
+    pub fn max_file_size(&self) -> Option<u64> {
+        self.max_file_size
+    }
+
+    pub fn free_space_reserve(&self) -> u64 {
+        self.free_space_reserve
+    }
+}
+
+impl IgnoreConfig {
+    pub fn action(&self) -> IgnoreAction {
+        self.action
+    }
+}
+
+impl CompactionConfig {
+    pub fn interval(&self) -> Option<time::Duration> {
+        match self.interval_secs {
+            0 => None,
+            secs => Some(time::Duration::from_secs(secs)),
+        }
+    }
+}
+
+impl ConsistencyConfig {
+    pub fn missing_paths(&self) -> MissingPathAction {
+        self.missing_paths
+    }
+}
+
+impl MoveConfig {
+    pub fn collision(&self) -> CollisionPolicy {
+        self.collision
+    }
+
+    pub fn versions_directory(&self) -> &path::Path {
+        &self.versions_directory
+    }
+}
+
+impl PathConfig {
+    pub fn normalize_unicode(&self) -> bool {
+        self.normalize_unicode
+    }
+
+    pub fn case_insensitive(&self) -> bool {
+        self.case_insensitive
+    }
+}
+
+impl StorageConfig {
+    pub fn s3(&self) -> &S3Config {
+        &self.s3
+    }
+
+    pub fn encryption(&self) -> Option<&EncryptionConfig> {
+        self.encryption.as_ref()
+    }
+}
+
+impl S3Config {
+    pub fn endpoint(&self) -> Option<&str> {
+        self.endpoint.as_deref()
+    }
+
+    pub fn region(&self) -> &str {
+        &self.region
+    }
+
+    pub fn path_style(&self) -> bool {
+        self.path_style
+    }
+
+    /// Never below the 5 MiB minimum S3 accepts for all but the last part.
+    pub fn part_size(&self) -> usize {
+        self.part_size.max(5 * 1024 * 1024)
+    }
+}
+
+impl EncryptionConfig {
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    pub fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+
+    pub fn key_file(&self) -> Option<&path::Path> {
+        self.key_file.as_deref()
+    }
+
+    pub fn previous_key_files(&self) -> &[path::PathBuf] {
+        &self.previous_key_files
+    }
 }
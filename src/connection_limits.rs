@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::{fmt, io, net, thread, time};
+
+use crate::config;
+
+#[derive(Debug)]
+pub enum LimitExceeded {
+    Total(usize),
+    PerIp(net::IpAddr, usize),
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::Total(max) => {
+                write!(
+                    f,
+                    "maximum number of concurrent connections ({}) reached",
+                    max
+                )
+            }
+            LimitExceeded::PerIp(ip, max) => write!(
+                f,
+                "maximum number of concurrent connections for `{}` ({}) reached",
+                ip, max
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ConnectionCounts {
+    total: usize,
+    per_ip: HashMap<net::IpAddr, usize>,
+}
+
+/// Tracks active connections and hands out a `ConnectionPermit` for each one
+/// that fits within the configured caps.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimiter {
+    max_connections: Arc<AtomicUsize>,
+    max_connections_per_ip: Arc<AtomicUsize>,
+    counts: Arc<Mutex<ConnectionCounts>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(tcp_config: &config::TcpConfig) -> Self {
+        Self {
+            max_connections: Arc::new(AtomicUsize::new(tcp_config.max_connections())),
+            max_connections_per_ip: Arc::new(AtomicUsize::new(tcp_config.max_connections_per_ip())),
+            counts: Arc::new(Mutex::new(ConnectionCounts::default())),
+        }
+    }
+
+    /// Applies new caps to connections accepted from now on. Connections
+    /// already over a lowered cap are left open.
+    pub fn set_limits(&self, tcp_config: &config::TcpConfig) {
+        self.max_connections
+            .store(tcp_config.max_connections(), Ordering::Relaxed);
+        self.max_connections_per_ip
+            .store(tcp_config.max_connections_per_ip(), Ordering::Relaxed);
+    }
+
+    pub fn try_acquire(&self, ip: net::IpAddr) -> Result<ConnectionPermit, LimitExceeded> {
+        let max_connections = self.max_connections.load(Ordering::Relaxed);
+        let max_connections_per_ip = self.max_connections_per_ip.load(Ordering::Relaxed);
+        let mut counts = self.counts.lock().unwrap();
+        if counts.total >= max_connections {
+            return Err(LimitExceeded::Total(max_connections));
+        }
+        let ip_count = counts.per_ip.entry(ip).or_insert(0);
+        if *ip_count >= max_connections_per_ip {
+            return Err(LimitExceeded::PerIp(ip, max_connections_per_ip));
+        }
+        *ip_count += 1;
+        counts.total += 1;
+
+        Ok(ConnectionPermit {
+            ip,
+            counts: self.counts.clone(),
+        })
+    }
+}
+
+/// Releases its slot in the `ConnectionLimiter` when dropped.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    ip: net::IpAddr,
+    counts: Arc<Mutex<ConnectionCounts>>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(ip_count) = counts.per_ip.get_mut(&self.ip) {
+            *ip_count -= 1;
+            if *ip_count == 0 {
+                counts.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Read and write timeouts on a socket surface as `WouldBlock` on unix and
+/// `TimedOut` on windows.
+pub fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Shuts a socket down once an armed deadline passes. Read and write timeouts
+/// only bound a single call, so a client trickling in a byte at a time could
+/// otherwise hold its connection forever. The deadline is kept by a thread of
+/// its own, which ends along with the watchdog.
+#[derive(Debug)]
+pub struct Watchdog {
+    deadlines: mpsc::Sender<Option<time::Instant>>,
+    expired: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    pub fn new(stream: net::TcpStream) -> Self {
+        let (deadlines, receiver) = mpsc::channel();
+        let expired = Arc::new(AtomicBool::new(false));
+        let thread_expired = expired.clone();
+        thread::spawn(move || {
+            let mut deadline: Option<time::Instant> = None;
+            loop {
+                let received = match deadline {
+                    Some(deadline) => receiver
+                        .recv_timeout(deadline.saturating_duration_since(time::Instant::now())),
+                    None => receiver
+                        .recv()
+                        .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok(next) => deadline = next,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        thread_expired.store(true, Ordering::Relaxed);
+                        let _ = stream.shutdown(net::Shutdown::Both);
+                        deadline = None;
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+        Self { deadlines, expired }
+    }
+
+    /// Shuts the socket down unless `disarm` is called within `timeout`.
+    pub fn arm(&self, timeout: time::Duration) {
+        let _ = self
+            .deadlines
+            .send(time::Instant::now().checked_add(timeout));
+    }
+
+    pub fn disarm(&self) {
+        let _ = self.deadlines.send(None);
+    }
+
+    /// Whether the socket was shut down because a deadline passed.
+    pub fn expired(&self) -> bool {
+        self.expired.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn socket_pair() -> (net::TcpStream, net::TcpStream) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (server, client)
+    }
+
+    #[test]
+    fn sockets_are_shut_down_past_the_deadline() {
+        let (server, mut client) = socket_pair();
+        let watchdog = Watchdog::new(server.try_clone().unwrap());
+
+        watchdog.arm(time::Duration::from_millis(50));
+        // The server end is closed while the client keeps it waiting
+        let mut buffer = [0; 1];
+        client
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(client.read(&mut buffer).unwrap(), 0);
+        assert!(watchdog.expired());
+    }
+
+    #[test]
+    fn disarmed_deadlines_do_not_expire() {
+        let (server, _client) = socket_pair();
+        let watchdog = Watchdog::new(server.try_clone().unwrap());
+
+        watchdog.arm(time::Duration::from_millis(50));
+        watchdog.disarm();
+        thread::sleep(time::Duration::from_millis(100));
+
+        assert!(!watchdog.expired());
+        server
+            .set_read_timeout(Some(time::Duration::from_millis(10)))
+            .unwrap();
+        let err = (&server).read(&mut [0; 1]).unwrap_err();
+        assert!(is_timeout(&err));
+    }
+}
@@ -0,0 +1,102 @@
+#[derive(Debug, Clone, PartialEq)]
+pub struct Device {
+    id: i32,
+    device_id: String,
+    hostname: String,
+    client_version: String,
+    /// Unix timestamp, in seconds.
+    last_seen: i64,
+    revoked: bool,
+    sync_include: Vec<String>,
+    sync_exclude: Vec<String>,
+    /// User the device was registered by, `None` for anonymous sessions.
+    user_id: Option<i32>,
+    /// Devices registered by a user wait for an operator to approve them.
+    approved: bool,
+}
+
+impl Device {
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    pub fn client_version(&self) -> &str {
+        &self.client_version
+    }
+
+    pub fn last_seen(&self) -> i64 {
+        self.last_seen
+    }
+
+    pub fn revoked(&self) -> bool {
+        self.revoked
+    }
+
+    pub fn sync_include(&self) -> &[String] {
+        &self.sync_include
+    }
+
+    pub fn sync_exclude(&self) -> &[String] {
+        &self.sync_exclude
+    }
+
+    pub fn user_id(&self) -> Option<i32> {
+        self.user_id
+    }
+
+    pub fn approved(&self) -> bool {
+        self.approved
+    }
+}
+
+/// Columns of a `devices` row, with `last_seen` as a Unix timestamp.
+pub type DeviceRow = (
+    i32,
+    String,
+    String,
+    String,
+    i64,
+    bool,
+    Vec<String>,
+    Vec<String>,
+    Option<i32>,
+    bool,
+);
+
+impl From<DeviceRow> for Device {
+    fn from(
+        (
+            id,
+            device_id,
+            hostname,
+            client_version,
+            last_seen,
+            revoked,
+            sync_include,
+            sync_exclude,
+            user_id,
+            approved,
+        ): DeviceRow,
+    ) -> Self {
+        Self {
+            id,
+            device_id,
+            hostname,
+            client_version,
+            last_seen,
+            revoked,
+            sync_include,
+            sync_exclude,
+            user_id,
+            approved,
+        }
+    }
+}
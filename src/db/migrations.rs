@@ -0,0 +1,139 @@
+//! Numbered schema migrations, embedded in the binary and applied in order,
+//! with one script per database. The versions applied so far are recorded in
+//! `schema_migrations`. The early Postgres migrations only create what does
+//! not exist yet, so databases set up before migrations were versioned are
+//! adopted as they are.
+
+use std::{error, fmt};
+
+use super::Database;
+
+pub(super) struct Migration {
+    pub(super) version: i64,
+    pub(super) name: &'static str,
+    pub(super) postgres: &'static str,
+    pub(super) sqlite: &'static str,
+}
+
+/// Every migration is written once per database, so their versions stay in
+/// step. Never edit an applied migration, add a new one instead.
+pub(super) const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "namespaces",
+        postgres: include_str!("migrations/postgres/0001_namespaces.sql"),
+        sqlite: include_str!("migrations/sqlite/0001_namespaces.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "users_and_acl",
+        postgres: include_str!("migrations/postgres/0002_users_and_acl.sql"),
+        sqlite: include_str!("migrations/sqlite/0002_users_and_acl.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "devices",
+        postgres: include_str!("migrations/postgres/0003_devices.sql"),
+        sqlite: include_str!("migrations/sqlite/0003_devices.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "compaction",
+        postgres: include_str!("migrations/postgres/0004_compaction.sql"),
+        sqlite: include_str!("migrations/sqlite/0004_compaction.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "checksums",
+        postgres: include_str!("migrations/postgres/0005_checksums.sql"),
+        sqlite: include_str!("migrations/sqlite/0005_checksums.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "symlinks",
+        postgres: include_str!("migrations/postgres/0006_symlinks.sql"),
+        sqlite: include_str!("migrations/sqlite/0006_symlinks.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "end_to_end_namespaces",
+        postgres: include_str!("migrations/postgres/0007_end_to_end_namespaces.sql"),
+        sqlite: include_str!("migrations/sqlite/0007_end_to_end_namespaces.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "namespace_usage",
+        postgres: include_str!("migrations/postgres/0008_namespace_usage.sql"),
+        sqlite: include_str!("migrations/sqlite/0008_namespace_usage.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "device_approval",
+        postgres: include_str!("migrations/postgres/0009_device_approval.sql"),
+        sqlite: include_str!("migrations/sqlite/0009_device_approval.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "file_metadata",
+        postgres: include_str!("migrations/postgres/0010_file_metadata.sql"),
+        sqlite: include_str!("migrations/sqlite/0010_file_metadata.sql"),
+    },
+];
+
+pub(super) const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS schema_migrations (
+    version BIGINT PRIMARY KEY,
+    name TEXT NOT NULL,
+    applied_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+)";
+
+pub(super) fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaError {
+    /// The database was migrated by a newer server.
+    TooNew { database: i64, supported: i64 },
+    /// Migrations are pending, run `hcs_server migrate`.
+    Outdated { database: i64, latest: i64 },
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaError::TooNew {
+                database,
+                supported,
+            } => write!(
+                f,
+                "database schema version {} is newer than the {} this server supports, upgrade the server",
+                database, supported
+            ),
+            SchemaError::Outdated { database, latest } => write!(
+                f,
+                "database schema version {} is behind version {}, run `hcs_server migrate`",
+                database, latest
+            ),
+        }
+    }
+}
+
+impl error::Error for SchemaError {}
+
+/// Fails unless the database is at exactly the schema version this server
+/// was built for.
+pub async fn check(db: &dyn Database) -> Result<(), Box<dyn error::Error>> {
+    let database = db.schema_version().await?;
+    let latest = db.latest_schema_version();
+    if database > latest {
+        return Err(SchemaError::TooNew {
+            database,
+            supported: latest,
+        }
+        .into());
+    }
+    if database < latest {
+        return Err(SchemaError::Outdated { database, latest }.into());
+    }
+    Ok(())
+}
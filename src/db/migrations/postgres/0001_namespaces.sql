@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS namespaces (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(255) NOT NULL UNIQUE,
+    storage_directory TEXT NOT NULL,
+    server_version BIGINT NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS namespace_changes (
+    namespace_id INTEGER NOT NULL REFERENCES namespaces (id) ON DELETE CASCADE,
+    version BIGINT NOT NULL,
+    change_event BYTEA NOT NULL,
+    PRIMARY KEY (namespace_id, version)
+);
+
+CREATE TABLE IF NOT EXISTS storage_quotas (
+    namespace VARCHAR(255) PRIMARY KEY,
+    quota_bytes BIGINT NOT NULL
+);
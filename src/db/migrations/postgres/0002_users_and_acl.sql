@@ -0,0 +1,21 @@
+CREATE TABLE IF NOT EXISTS users (
+    id SERIAL PRIMARY KEY,
+    username VARCHAR(255) NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS user_groups (
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    group_name VARCHAR(255) NOT NULL,
+    PRIMARY KEY (user_id, group_name)
+);
+
+CREATE TABLE IF NOT EXISTS acl_entries (
+    id SERIAL PRIMARY KEY,
+    namespace_id INTEGER NOT NULL REFERENCES namespaces (id) ON DELETE CASCADE,
+    path TEXT NOT NULL,
+    user_id INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    group_name VARCHAR(255),
+    permission VARCHAR(16) NOT NULL,
+    CHECK ((user_id IS NULL) <> (group_name IS NULL))
+);
@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS devices (
+    id SERIAL PRIMARY KEY,
+    device_id VARCHAR(255) NOT NULL UNIQUE,
+    user_id INTEGER REFERENCES users (id) ON DELETE SET NULL,
+    hostname TEXT NOT NULL,
+    client_version TEXT NOT NULL,
+    last_seen TIMESTAMPTZ NOT NULL DEFAULT now(),
+    revoked BOOLEAN NOT NULL DEFAULT FALSE,
+    sync_include TEXT[] NOT NULL DEFAULT '{}',
+    sync_exclude TEXT[] NOT NULL DEFAULT '{}'
+);
+
+CREATE TABLE IF NOT EXISTS device_cursors (
+    device_id INTEGER NOT NULL REFERENCES devices (id) ON DELETE CASCADE,
+    namespace_id INTEGER NOT NULL REFERENCES namespaces (id) ON DELETE CASCADE,
+    server_version BIGINT NOT NULL,
+    PRIMARY KEY (device_id, namespace_id)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE namespaces
+ADD COLUMN IF NOT EXISTS compacted_version BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS namespace_symlink_changes (
+    namespace_id INTEGER NOT NULL REFERENCES namespaces (id) ON DELETE CASCADE,
+    version BIGINT NOT NULL,
+    symlink_event BYTEA NOT NULL,
+    PRIMARY KEY (namespace_id, version)
+);
@@ -0,0 +1,3 @@
+-- bincode encoded `metadata::FileMetadata` a file was uploaded with, kept
+-- here so it survives on storage without a local filesystem.
+ALTER TABLE file_checksums ADD COLUMN IF NOT EXISTS metadata BYTEA;
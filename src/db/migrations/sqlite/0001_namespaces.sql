@@ -0,0 +1,18 @@
+CREATE TABLE namespaces (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE,
+    storage_directory TEXT NOT NULL,
+    server_version INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE namespace_changes (
+    namespace_id INTEGER NOT NULL REFERENCES namespaces (id) ON DELETE CASCADE,
+    version INTEGER NOT NULL,
+    change_event BLOB NOT NULL,
+    PRIMARY KEY (namespace_id, version)
+);
+
+CREATE TABLE storage_quotas (
+    namespace TEXT PRIMARY KEY,
+    quota_bytes INTEGER NOT NULL
+);
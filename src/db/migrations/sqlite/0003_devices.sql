@@ -0,0 +1,20 @@
+-- `last_seen` is a Unix timestamp, in seconds. `sync_include` and
+-- `sync_exclude` are bincode encoded lists of patterns.
+CREATE TABLE devices (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    device_id TEXT NOT NULL UNIQUE,
+    user_id INTEGER REFERENCES users (id) ON DELETE SET NULL,
+    hostname TEXT NOT NULL,
+    client_version TEXT NOT NULL,
+    last_seen INTEGER NOT NULL DEFAULT (CAST(strftime('%s', 'now') AS INTEGER)),
+    revoked BOOLEAN NOT NULL DEFAULT FALSE,
+    sync_include BLOB NOT NULL DEFAULT X'0000000000000000',
+    sync_exclude BLOB NOT NULL DEFAULT X'0000000000000000'
+);
+
+CREATE TABLE device_cursors (
+    device_id INTEGER NOT NULL REFERENCES devices (id) ON DELETE CASCADE,
+    namespace_id INTEGER NOT NULL REFERENCES namespaces (id) ON DELETE CASCADE,
+    server_version INTEGER NOT NULL,
+    PRIMARY KEY (device_id, namespace_id)
+);
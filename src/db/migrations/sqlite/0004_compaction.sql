@@ -0,0 +1,2 @@
+ALTER TABLE namespaces
+ADD COLUMN compacted_version INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,6 @@
+CREATE TABLE file_checksums (
+    namespace_id INTEGER NOT NULL REFERENCES namespaces (id) ON DELETE CASCADE,
+    path TEXT NOT NULL,
+    sha256 TEXT NOT NULL,
+    PRIMARY KEY (namespace_id, path)
+);
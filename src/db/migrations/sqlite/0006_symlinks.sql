@@ -0,0 +1,6 @@
+CREATE TABLE namespace_symlink_changes (
+    namespace_id INTEGER NOT NULL REFERENCES namespaces (id) ON DELETE CASCADE,
+    version INTEGER NOT NULL,
+    symlink_event BLOB NOT NULL,
+    PRIMARY KEY (namespace_id, version)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE namespaces
+ADD COLUMN end_to_end BOOLEAN NOT NULL DEFAULT FALSE;
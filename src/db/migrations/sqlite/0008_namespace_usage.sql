@@ -0,0 +1,3 @@
+-- NULL until the namespace has been measured.
+ALTER TABLE namespaces
+ADD COLUMN used_bytes INTEGER;
@@ -0,0 +1,2 @@
+-- Devices registered before this migration stay usable.
+ALTER TABLE devices ADD COLUMN approved BOOLEAN NOT NULL DEFAULT TRUE;
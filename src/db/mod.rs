@@ -0,0 +1,307 @@
+//! Tables owned by the server itself, alongside the legacy change log managed
+//! by `hcs_lib::server_database`. They are kept in Postgres, or in an embedded
+//! SQLite file for single-user deployments, depending on the scheme of
+//! `db_config.database_url`.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use hcs_lib::{data, server_database};
+
+use crate::{acl, metadata, symlinks};
+
+pub mod devices;
+pub mod migrations;
+pub mod namespaces;
+mod postgres;
+mod sqlite;
+pub mod users;
+
+pub use postgres::PostgresDatabase;
+pub use sqlite::SqliteDatabase;
+
+/// Every query the server makes, implemented once per database.
+#[async_trait::async_trait]
+pub trait Database: fmt::Debug + Send + Sync {
+    /// Applies pending migrations and returns how many were applied.
+    async fn migrate(&self) -> Result<usize, Box<dyn Error>>;
+
+    /// Version of the latest migration applied, `0` if none.
+    async fn schema_version(&self) -> Result<i64, Box<dyn Error>>;
+
+    /// Version of the latest migration this server knows of.
+    fn latest_schema_version(&self) -> i64;
+
+    async fn get_namespace(
+        &self,
+        name: &str,
+    ) -> Result<Option<namespaces::NamespaceRow>, Box<dyn Error>>;
+
+    async fn list_namespaces(&self) -> Result<Vec<namespaces::NamespaceRow>, Box<dyn Error>>;
+
+    async fn insert_namespace(
+        &self,
+        name: &str,
+        storage_directory: &str,
+        end_to_end: bool,
+    ) -> Result<namespaces::NamespaceRow, Box<dyn Error>>;
+
+    /// Inserts a namespace along with changes at known versions, in one
+    /// transaction so that a namespace never exists with only part of them.
+    async fn import_namespace(
+        &self,
+        name: &str,
+        storage_directory: &str,
+        end_to_end: bool,
+        changes: Vec<(i64, data::ChangeEvent)>,
+    ) -> Result<namespaces::NamespaceRow, Box<dyn Error>>;
+
+    /// The change log kept by `hcs_lib::server_database` from before
+    /// namespaces existed, oldest first.
+    async fn get_legacy_changes(&self) -> Result<Vec<(i64, data::ChangeEvent)>, Box<dyn Error>>;
+
+    async fn get_server_version(&self, namespace_id: i32) -> Result<i64, Box<dyn Error>>;
+
+    /// Changes with a version in `(from_version, to_version]`, oldest first.
+    async fn get_changes(
+        &self,
+        namespace_id: i32,
+        from_version: i64,
+        to_version: i64,
+    ) -> Result<Vec<(i64, data::ChangeEvent)>, Box<dyn Error>>;
+
+    /// Appends `change_event` to the namespace's change log and returns the
+    /// new server version. `usage_delta` is added to the namespace's usage in
+    /// the same transaction.
+    async fn insert_change(
+        &self,
+        namespace_id: i32,
+        change_event: data::ChangeEvent,
+        usage_delta: i64,
+    ) -> Result<i64, Box<dyn Error>>;
+
+    /// Versions up to this one have been squashed by compaction, so a client
+    /// that has not reached it cannot be brought up to date from the change
+    /// log.
+    async fn get_compacted_version(&self, namespace_id: i32) -> Result<i64, Box<dyn Error>>;
+
+    /// Replaces every change up to `horizon` with `baseline` and moves the
+    /// namespace's compaction horizon forward.
+    async fn replace_changes(
+        &self,
+        namespace_id: i32,
+        horizon: i64,
+        baseline: Vec<(i64, data::ChangeEvent)>,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Symlink changes with a version in `(from_version, to_version]`, oldest
+    /// first. They are kept apart from the change log since they are not
+    /// `hcs_lib` change events, but share its server version counter.
+    async fn get_symlink_changes(
+        &self,
+        namespace_id: i32,
+        from_version: i64,
+        to_version: i64,
+    ) -> Result<Vec<(i64, symlinks::SymlinkEvent)>, Box<dyn Error>>;
+
+    /// Appends `symlink_event` to the namespace's change log and returns the
+    /// new server version.
+    async fn insert_symlink_change(
+        &self,
+        namespace_id: i32,
+        symlink_event: &symlinks::SymlinkEvent,
+    ) -> Result<i64, Box<dyn Error>>;
+
+    /// Drops symlink changes up to the compaction horizon. Clients behind it
+    /// are sent a snapshot, which lists symlinks from storage.
+    async fn remove_symlink_changes(
+        &self,
+        namespace_id: i32,
+        horizon: i64,
+    ) -> Result<u64, Box<dyn Error>>;
+
+    /// Records the SHA-256 of a file as it was uploaded, used by `fsck` to
+    /// detect files that changed in storage behind the server's back. Any
+    /// metadata of the previous upload is dropped.
+    async fn record_checksum(
+        &self,
+        namespace_id: i32,
+        path: &str,
+        sha256: &str,
+    ) -> Result<(), Box<dyn Error>>;
+
+    async fn get_checksums(
+        &self,
+        namespace_id: i32,
+    ) -> Result<HashMap<String, String>, Box<dyn Error>>;
+
+    /// Stores the metadata a file was uploaded with next to its checksum, so
+    /// it moves and goes away along with it.
+    async fn set_file_metadata(
+        &self,
+        namespace_id: i32,
+        path: &str,
+        metadata: &metadata::FileMetadata,
+    ) -> Result<(), Box<dyn Error>>;
+
+    async fn get_file_metadata(
+        &self,
+        namespace_id: i32,
+        path: &str,
+    ) -> Result<Option<metadata::FileMetadata>, Box<dyn Error>>;
+
+    /// Moves the checksum of `from_path`, or of every file below it if it is a
+    /// directory, to `to_path`.
+    async fn move_checksums(
+        &self,
+        namespace_id: i32,
+        from_path: &str,
+        to_path: &str,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Removes the checksum of `path`, or of every file below it if it is a
+    /// directory.
+    async fn remove_checksums(&self, namespace_id: i32, path: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Bytes stored in the namespace, `None` until it has been measured.
+    async fn get_usage(&self, namespace_id: i32) -> Result<Option<u64>, Box<dyn Error>>;
+
+    /// Records a measured usage. With `only_if_unknown`, a usage recorded in
+    /// the meantime is kept.
+    async fn set_usage(
+        &self,
+        namespace_id: i32,
+        used_bytes: u64,
+        only_if_unknown: bool,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Adds `delta` to the namespace's usage unless that takes it above
+    /// `limit`. Returns the new usage, or `None` if the limit was hit.
+    async fn reserve_usage(
+        &self,
+        namespace_id: i32,
+        delta: i64,
+        limit: Option<u64>,
+    ) -> Result<Option<u64>, Box<dyn Error>>;
+
+    async fn get_quota(&self, namespace: &str) -> Result<Option<u64>, Box<dyn Error>>;
+
+    async fn set_quota(&self, namespace: &str, quota_bytes: u64) -> Result<(), Box<dyn Error>>;
+
+    /// Returns whether the namespace had a quota.
+    async fn remove_quota(&self, namespace: &str) -> Result<bool, Box<dyn Error>>;
+
+    async fn insert_user(
+        &self,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<users::User, Box<dyn Error>>;
+
+    async fn get_user(&self, username: &str) -> Result<Option<users::User>, Box<dyn Error>>;
+
+    async fn get_password_hash(
+        &self,
+        username: &str,
+    ) -> Result<Option<(users::User, String)>, Box<dyn Error>>;
+
+    async fn set_password_hash(
+        &self,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<bool, Box<dyn Error>>;
+
+    async fn remove_user(&self, username: &str) -> Result<bool, Box<dyn Error>>;
+
+    async fn add_to_group(&self, user_id: i32, group_name: &str) -> Result<(), Box<dyn Error>>;
+
+    async fn remove_from_group(&self, user_id: i32, group_name: &str)
+        -> Result<(), Box<dyn Error>>;
+
+    async fn grant(
+        &self,
+        namespace_id: i32,
+        path: &str,
+        principal: &acl::Principal,
+        permission: acl::Permission,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Returns whether an entry was removed.
+    async fn revoke(
+        &self,
+        namespace_id: i32,
+        path: &str,
+        principal: &acl::Principal,
+    ) -> Result<bool, Box<dyn Error>>;
+
+    async fn namespace_has_entries(&self, namespace_id: i32) -> Result<bool, Box<dyn Error>>;
+
+    /// Entries in the namespace granted to `user` directly or through one of
+    /// their groups.
+    async fn entries_for_user(
+        &self,
+        namespace_id: i32,
+        user: &users::User,
+    ) -> Result<Vec<(String, acl::Permission)>, Box<dyn Error>>;
+
+    /// Creates the device or updates its details, and marks it as seen now.
+    /// A new device is bound to `user_id` and only approved if that is
+    /// `None`. An anonymous device taken over by a user is bound to them and
+    /// needs approval again.
+    async fn register_device(
+        &self,
+        device_id: &str,
+        hostname: &str,
+        client_version: &str,
+        user_id: Option<i32>,
+    ) -> Result<devices::Device, Box<dyn Error>>;
+
+    async fn get_device(&self, device_id: &str) -> Result<Option<devices::Device>, Box<dyn Error>>;
+
+    /// Most recently seen first.
+    async fn list_devices(&self) -> Result<Vec<devices::Device>, Box<dyn Error>>;
+
+    async fn revoke_device(&self, device_id: &str) -> Result<bool, Box<dyn Error>>;
+
+    async fn approve_device(&self, device_id: &str) -> Result<bool, Box<dyn Error>>;
+
+    async fn set_sync_filter(
+        &self,
+        id: i32,
+        include: &[String],
+        exclude: &[String],
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Records that the device has consumed the namespace's change log up to
+    /// `server_version`. Cursors never move backwards, nor past the
+    /// namespace's server version.
+    async fn acknowledge_version(
+        &self,
+        id: i32,
+        namespace_id: i32,
+        server_version: i64,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Acknowledged version of each device that has synced the namespace.
+    async fn get_cursors(&self, namespace_id: i32) -> Result<Vec<(String, i64)>, Box<dyn Error>>;
+
+    /// Lowest version acknowledged by any device that is not revoked, i.e.
+    /// every change up to it has been consumed by all devices.
+    async fn min_acknowledged_version(
+        &self,
+        namespace_id: i32,
+    ) -> Result<Option<i64>, Box<dyn Error>>;
+}
+
+/// Opens a SQLite database for `sqlite:` URLs and Postgres otherwise.
+pub async fn connect(
+    db_config: &server_database::DbConfig,
+) -> Result<Arc<dyn Database>, Box<dyn Error>> {
+    if db_config.database_url().starts_with("sqlite:") {
+        Ok(Arc::new(SqliteDatabase::connect(db_config).await?))
+    } else {
+        let db_pool = server_database::connect_db(db_config).await?;
+        Ok(Arc::new(PostgresDatabase::new(db_pool)))
+    }
+}
@@ -0,0 +1,161 @@
+use std::path;
+use std::sync::Arc;
+
+use hcs_lib::server_database;
+
+use super::Database;
+use crate::{config, storage};
+
+/// Namespace backed by `file_handler_config.storage_directory`. Clients that
+/// do not select a namespace use this one.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+#[derive(Debug, Clone)]
+pub struct Namespace {
+    id: i32,
+    name: String,
+    storage_directory: path::PathBuf,
+    storage: Arc<dyn storage::StorageBackend>,
+    end_to_end: bool,
+}
+
+impl Namespace {
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn storage_directory(&self) -> &path::Path {
+        &self.storage_directory
+    }
+
+    pub fn storage(&self) -> &dyn storage::StorageBackend {
+        self.storage.as_ref()
+    }
+
+    pub fn shared_storage(&self) -> Arc<dyn storage::StorageBackend> {
+        self.storage.clone()
+    }
+
+    /// The same namespace, remembering what its storage holds for as long as
+    /// the returned value lives. Meant to last for one sync.
+    pub fn cached(&self) -> Self {
+        Self {
+            storage: Arc::new(storage::CachedStorage::new(self.storage.clone())),
+            ..self.clone()
+        }
+    }
+
+    /// Clients encrypt names and contents before uploading, so the server
+    /// only ever sees opaque paths and blobs.
+    pub fn is_end_to_end(&self) -> bool {
+        self.end_to_end
+    }
+}
+
+/// `id`, `name`, `storage_directory` and `end_to_end` of a `namespaces` row.
+pub type NamespaceRow = (i32, String, String, bool);
+
+impl Namespace {
+    fn from_row(
+        (id, name, storage_directory, end_to_end): NamespaceRow,
+        storage_config: &config::StorageConfig,
+    ) -> std::io::Result<Self> {
+        let storage_directory = path::PathBuf::from(storage_directory);
+        Ok(Self {
+            id,
+            name,
+            storage: storage::open(&storage_directory, storage_config)?,
+            storage_directory,
+            end_to_end,
+        })
+    }
+
+    /// A namespace on `storage` instead of the one its row names.
+    #[cfg(test)]
+    pub(crate) fn with_storage(
+        (id, name, storage_directory, end_to_end): NamespaceRow,
+        storage: Arc<dyn storage::StorageBackend>,
+    ) -> Self {
+        Self {
+            id,
+            name,
+            storage_directory: path::PathBuf::from(storage_directory),
+            storage,
+            end_to_end,
+        }
+    }
+}
+
+pub async fn get_namespace(
+    name: &str,
+    storage_config: &config::StorageConfig,
+    database: &dyn Database,
+) -> Result<Option<Namespace>, Box<dyn std::error::Error>> {
+    let namespace = database.get_namespace(name).await?;
+    Ok(namespace
+        .map(|row| Namespace::from_row(row, storage_config))
+        .transpose()?)
+}
+
+pub async fn list_namespaces(
+    storage_config: &config::StorageConfig,
+    database: &dyn Database,
+) -> Result<Vec<Namespace>, Box<dyn std::error::Error>> {
+    let namespaces = database.list_namespaces().await?;
+    Ok(namespaces
+        .into_iter()
+        .map(|row| Namespace::from_row(row, storage_config))
+        .collect::<Result<_, _>>()?)
+}
+
+pub async fn create_namespace(
+    name: &str,
+    storage_directory: &path::Path,
+    end_to_end: bool,
+    storage_config: &config::StorageConfig,
+    database: &dyn Database,
+) -> Result<Namespace, Box<dyn std::error::Error>> {
+    storage::open(storage_directory, storage_config)?.create_dir("")?;
+    let namespace = database
+        .insert_namespace(name, &storage_directory.to_string_lossy(), end_to_end)
+        .await?;
+    Ok(Namespace::from_row(namespace, storage_config)?)
+}
+
+/// Returns the default namespace, creating it on first start. The change log
+/// kept by `hcs_lib::server_database` from before namespaces existed is copied
+/// into it so existing clients keep their versions. Both happen in one
+/// transaction, so an interrupted import is retried on the next start.
+pub async fn ensure_default_namespace(
+    file_handler_config: &server_database::ServerFileHandlerConfig,
+    storage_config: &config::StorageConfig,
+    database: &dyn Database,
+) -> Result<Namespace, Box<dyn std::error::Error>> {
+    let namespace = get_namespace(DEFAULT_NAMESPACE, storage_config, database).await?;
+    if let Some(namespace) = namespace {
+        return Ok(namespace);
+    }
+
+    log::info!("Creating default namespace");
+    let storage_directory = file_handler_config.storage_directory();
+    storage::open(storage_directory, storage_config)?.create_dir("")?;
+
+    let legacy_changes = database.get_legacy_changes().await?;
+    log::info!(
+        "Importing {} changes into default namespace",
+        legacy_changes.len()
+    );
+    let namespace = database
+        .import_namespace(
+            DEFAULT_NAMESPACE,
+            &storage_directory.to_string_lossy(),
+            false,
+            legacy_changes,
+        )
+        .await?;
+    Ok(Namespace::from_row(namespace, storage_config)?)
+}
@@ -0,0 +1,820 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use hcs_lib::{data, server_database};
+use sqlx::Executor;
+
+use super::{devices, migrations, namespaces, users, Database};
+use crate::{acl, metadata, symlinks};
+
+/// Held for the duration of a migration so that servers started at the same
+/// time do not migrate concurrently.
+const MIGRATION_LOCK_ID: i64 = 0x4843_535f_4d49_4752;
+
+const DEVICE_COLUMNS: &str = "id, device_id, hostname, client_version,
+    EXTRACT(EPOCH FROM last_seen)::BIGINT, revoked, sync_include, sync_exclude, user_id,
+    approved";
+
+#[derive(Debug, Clone)]
+pub struct PostgresDatabase {
+    db_pool: sqlx::PgPool,
+}
+
+impl PostgresDatabase {
+    pub fn new(db_pool: sqlx::PgPool) -> Self {
+        Self { db_pool }
+    }
+}
+
+#[async_trait::async_trait]
+impl Database for PostgresDatabase {
+    async fn migrate(&self) -> Result<usize, Box<dyn Error>> {
+        server_database::initialize_db(&self.db_pool).await?;
+
+        let mut transaction = self.db_pool.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(MIGRATION_LOCK_ID)
+            .execute(&mut transaction)
+            .await?;
+        sqlx::query(migrations::CREATE_TABLE)
+            .execute(&mut transaction)
+            .await?;
+
+        let (database,): (Option<i64>,) =
+            sqlx::query_as("SELECT MAX(version) FROM schema_migrations")
+                .fetch_one(&mut transaction)
+                .await?;
+        let database = database.unwrap_or(0);
+        let latest = self.latest_schema_version();
+        if database > latest {
+            return Err(migrations::SchemaError::TooNew {
+                database,
+                supported: latest,
+            }
+            .into());
+        }
+
+        let pending = migrations::MIGRATIONS
+            .iter()
+            .filter(|migration| migration.version > database)
+            .collect::<Vec<_>>();
+        for migration in &pending {
+            log::info!(
+                "Applying migration {} ({})",
+                migration.version,
+                migration.name
+            );
+            transaction.execute(migration.postgres).await?;
+            sqlx::query("INSERT INTO schema_migrations (version, name) VALUES ($1, $2)")
+                .bind(migration.version)
+                .bind(migration.name)
+                .execute(&mut transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(pending.len())
+    }
+
+    async fn schema_version(&self) -> Result<i64, Box<dyn Error>> {
+        sqlx::query(migrations::CREATE_TABLE)
+            .execute(&self.db_pool)
+            .await?;
+        let (version,): (Option<i64>,) =
+            sqlx::query_as("SELECT MAX(version) FROM schema_migrations")
+                .fetch_one(&self.db_pool)
+                .await?;
+        Ok(version.unwrap_or(0))
+    }
+
+    fn latest_schema_version(&self) -> i64 {
+        migrations::latest_version()
+    }
+
+    async fn get_namespace(
+        &self,
+        name: &str,
+    ) -> Result<Option<namespaces::NamespaceRow>, Box<dyn Error>> {
+        Ok(sqlx::query_as(
+            "SELECT id, name, storage_directory, end_to_end FROM namespaces WHERE name = $1",
+        )
+        .bind(name)
+        .fetch_optional(&self.db_pool)
+        .await?)
+    }
+
+    async fn list_namespaces(&self) -> Result<Vec<namespaces::NamespaceRow>, Box<dyn Error>> {
+        Ok(sqlx::query_as(
+            "SELECT id, name, storage_directory, end_to_end FROM namespaces ORDER BY name",
+        )
+        .fetch_all(&self.db_pool)
+        .await?)
+    }
+
+    async fn insert_namespace(
+        &self,
+        name: &str,
+        storage_directory: &str,
+        end_to_end: bool,
+    ) -> Result<namespaces::NamespaceRow, Box<dyn Error>> {
+        Ok(sqlx::query_as(
+            "INSERT INTO namespaces (name, storage_directory, end_to_end) VALUES ($1, $2, $3)
+            RETURNING id, name, storage_directory, end_to_end",
+        )
+        .bind(name)
+        .bind(storage_directory)
+        .bind(end_to_end)
+        .fetch_one(&self.db_pool)
+        .await?)
+    }
+
+    async fn import_namespace(
+        &self,
+        name: &str,
+        storage_directory: &str,
+        end_to_end: bool,
+        changes: Vec<(i64, data::ChangeEvent)>,
+    ) -> Result<namespaces::NamespaceRow, Box<dyn Error>> {
+        let mut transaction = self.db_pool.begin().await?;
+        let namespace: namespaces::NamespaceRow = sqlx::query_as(
+            "INSERT INTO namespaces (name, storage_directory, end_to_end) VALUES ($1, $2, $3)
+            RETURNING id, name, storage_directory, end_to_end",
+        )
+        .bind(name)
+        .bind(storage_directory)
+        .bind(end_to_end)
+        .fetch_one(&mut transaction)
+        .await?;
+        for (version, change_event) in changes {
+            let bytes = bincode::serialize(&change_event)?;
+            sqlx::query(
+                "INSERT INTO namespace_changes (namespace_id, version, change_event)
+                VALUES ($1, $2, $3)",
+            )
+            .bind(namespace.0)
+            .bind(version)
+            .bind(bytes)
+            .execute(&mut transaction)
+            .await?;
+            sqlx::query(
+                "UPDATE namespaces SET server_version = GREATEST(server_version, $2) WHERE id = $1",
+            )
+            .bind(namespace.0)
+            .bind(version)
+            .execute(&mut transaction)
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok(namespace)
+    }
+
+    async fn get_legacy_changes(&self) -> Result<Vec<(i64, data::ChangeEvent)>, Box<dyn Error>> {
+        let server_version = server_database::get_server_version(&self.db_pool).await?;
+        let changes = server_database::get_changes(0, server_version, &self.db_pool).await?;
+        Ok(changes.into_iter().map(Into::into).collect())
+    }
+
+    async fn get_server_version(&self, namespace_id: i32) -> Result<i64, Box<dyn Error>> {
+        let (server_version,): (i64,) =
+            sqlx::query_as("SELECT server_version FROM namespaces WHERE id = $1")
+                .bind(namespace_id)
+                .fetch_one(&self.db_pool)
+                .await?;
+        Ok(server_version)
+    }
+
+    async fn get_changes(
+        &self,
+        namespace_id: i32,
+        from_version: i64,
+        to_version: i64,
+    ) -> Result<Vec<(i64, data::ChangeEvent)>, Box<dyn Error>> {
+        let rows: Vec<(i64, Vec<u8>)> = sqlx::query_as(
+            "SELECT version, change_event FROM namespace_changes
+            WHERE namespace_id = $1 AND version > $2 AND version <= $3
+            ORDER BY version",
+        )
+        .bind(namespace_id)
+        .bind(from_version)
+        .bind(to_version)
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        let mut changes = Vec::with_capacity(rows.len());
+        for (version, bytes) in rows {
+            changes.push((version, bincode::deserialize(&bytes)?));
+        }
+        Ok(changes)
+    }
+
+    async fn insert_change(
+        &self,
+        namespace_id: i32,
+        change_event: data::ChangeEvent,
+        usage_delta: i64,
+    ) -> Result<i64, Box<dyn Error>> {
+        let bytes = bincode::serialize(&change_event)?;
+
+        let mut transaction = self.db_pool.begin().await?;
+        let (version,): (i64,) = sqlx::query_as(
+            "UPDATE namespaces SET server_version = server_version + 1,
+                used_bytes = CASE WHEN used_bytes + $2 < 0 THEN 0 ELSE used_bytes + $2 END
+            WHERE id = $1
+            RETURNING server_version",
+        )
+        .bind(namespace_id)
+        .bind(usage_delta)
+        .fetch_one(&mut transaction)
+        .await?;
+        sqlx::query(
+            "INSERT INTO namespace_changes (namespace_id, version, change_event)
+            VALUES ($1, $2, $3)",
+        )
+        .bind(namespace_id)
+        .bind(version)
+        .bind(bytes)
+        .execute(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+
+        Ok(version)
+    }
+
+    async fn get_compacted_version(&self, namespace_id: i32) -> Result<i64, Box<dyn Error>> {
+        let (compacted_version,): (i64,) =
+            sqlx::query_as("SELECT compacted_version FROM namespaces WHERE id = $1")
+                .bind(namespace_id)
+                .fetch_one(&self.db_pool)
+                .await?;
+        Ok(compacted_version)
+    }
+
+    async fn replace_changes(
+        &self,
+        namespace_id: i32,
+        horizon: i64,
+        baseline: Vec<(i64, data::ChangeEvent)>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut transaction = self.db_pool.begin().await?;
+        sqlx::query("DELETE FROM namespace_changes WHERE namespace_id = $1 AND version <= $2")
+            .bind(namespace_id)
+            .bind(horizon)
+            .execute(&mut transaction)
+            .await?;
+        for (version, change_event) in baseline {
+            let bytes = bincode::serialize(&change_event)?;
+            sqlx::query(
+                "INSERT INTO namespace_changes (namespace_id, version, change_event)
+                VALUES ($1, $2, $3)",
+            )
+            .bind(namespace_id)
+            .bind(version)
+            .bind(bytes)
+            .execute(&mut transaction)
+            .await?;
+        }
+        sqlx::query(
+            "UPDATE namespaces SET compacted_version = GREATEST(compacted_version, $2)
+            WHERE id = $1",
+        )
+        .bind(namespace_id)
+        .bind(horizon)
+        .execute(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn get_symlink_changes(
+        &self,
+        namespace_id: i32,
+        from_version: i64,
+        to_version: i64,
+    ) -> Result<Vec<(i64, symlinks::SymlinkEvent)>, Box<dyn Error>> {
+        let rows: Vec<(i64, Vec<u8>)> = sqlx::query_as(
+            "SELECT version, symlink_event FROM namespace_symlink_changes
+            WHERE namespace_id = $1 AND version > $2 AND version <= $3
+            ORDER BY version",
+        )
+        .bind(namespace_id)
+        .bind(from_version)
+        .bind(to_version)
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        let mut changes = Vec::with_capacity(rows.len());
+        for (version, bytes) in rows {
+            changes.push((version, bincode::deserialize(&bytes)?));
+        }
+        Ok(changes)
+    }
+
+    async fn insert_symlink_change(
+        &self,
+        namespace_id: i32,
+        symlink_event: &symlinks::SymlinkEvent,
+    ) -> Result<i64, Box<dyn Error>> {
+        let bytes = bincode::serialize(symlink_event)?;
+
+        let mut transaction = self.db_pool.begin().await?;
+        let (version,): (i64,) = sqlx::query_as(
+            "UPDATE namespaces SET server_version = server_version + 1 WHERE id = $1
+            RETURNING server_version",
+        )
+        .bind(namespace_id)
+        .fetch_one(&mut transaction)
+        .await?;
+        sqlx::query(
+            "INSERT INTO namespace_symlink_changes (namespace_id, version, symlink_event)
+            VALUES ($1, $2, $3)",
+        )
+        .bind(namespace_id)
+        .bind(version)
+        .bind(bytes)
+        .execute(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+
+        Ok(version)
+    }
+
+    async fn remove_symlink_changes(
+        &self,
+        namespace_id: i32,
+        horizon: i64,
+    ) -> Result<u64, Box<dyn Error>> {
+        let result = sqlx::query(
+            "DELETE FROM namespace_symlink_changes WHERE namespace_id = $1 AND version <= $2",
+        )
+        .bind(namespace_id)
+        .bind(horizon)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn record_checksum(
+        &self,
+        namespace_id: i32,
+        path: &str,
+        sha256: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO file_checksums (namespace_id, path, sha256) VALUES ($1, $2, $3)
+            ON CONFLICT (namespace_id, path) DO UPDATE SET
+                sha256 = EXCLUDED.sha256,
+                metadata = NULL",
+        )
+        .bind(namespace_id)
+        .bind(path)
+        .bind(sha256)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_checksums(
+        &self,
+        namespace_id: i32,
+    ) -> Result<HashMap<String, String>, Box<dyn Error>> {
+        let rows: Vec<(String, String)> =
+            sqlx::query_as("SELECT path, sha256 FROM file_checksums WHERE namespace_id = $1")
+                .bind(namespace_id)
+                .fetch_all(&self.db_pool)
+                .await?;
+        Ok(rows.into_iter().collect())
+    }
+
+    async fn set_file_metadata(
+        &self,
+        namespace_id: i32,
+        path: &str,
+        metadata: &metadata::FileMetadata,
+    ) -> Result<(), Box<dyn Error>> {
+        let bytes = bincode::serialize(metadata)?;
+        sqlx::query(
+            "UPDATE file_checksums SET metadata = $3 WHERE namespace_id = $1 AND path = $2",
+        )
+        .bind(namespace_id)
+        .bind(path)
+        .bind(bytes)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_file_metadata(
+        &self,
+        namespace_id: i32,
+        path: &str,
+    ) -> Result<Option<metadata::FileMetadata>, Box<dyn Error>> {
+        let row: Option<(Option<Vec<u8>>,)> = sqlx::query_as(
+            "SELECT metadata FROM file_checksums WHERE namespace_id = $1 AND path = $2",
+        )
+        .bind(namespace_id)
+        .bind(path)
+        .fetch_optional(&self.db_pool)
+        .await?;
+        match row {
+            Some((Some(bytes),)) => Ok(Some(bincode::deserialize(&bytes)?)),
+            _ => Ok(None),
+        }
+    }
+
+    async fn move_checksums(
+        &self,
+        namespace_id: i32,
+        from_path: &str,
+        to_path: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "UPDATE file_checksums SET path = $3 || substr(path, length($2) + 1)
+            WHERE namespace_id = $1 AND (path = $2 OR left(path, length($2) + 1) = $2 || '/')",
+        )
+        .bind(namespace_id)
+        .bind(from_path)
+        .bind(to_path)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_checksums(&self, namespace_id: i32, path: &str) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "DELETE FROM file_checksums
+            WHERE namespace_id = $1 AND (path = $2 OR left(path, length($2) + 1) = $2 || '/')",
+        )
+        .bind(namespace_id)
+        .bind(path)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_usage(&self, namespace_id: i32) -> Result<Option<u64>, Box<dyn Error>> {
+        let (used_bytes,): (Option<i64>,) =
+            sqlx::query_as("SELECT used_bytes FROM namespaces WHERE id = $1")
+                .bind(namespace_id)
+                .fetch_one(&self.db_pool)
+                .await?;
+        Ok(used_bytes.map(|used_bytes| used_bytes as u64))
+    }
+
+    async fn set_usage(
+        &self,
+        namespace_id: i32,
+        used_bytes: u64,
+        only_if_unknown: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "UPDATE namespaces SET used_bytes = $2
+            WHERE id = $1 AND (NOT $3 OR used_bytes IS NULL)",
+        )
+        .bind(namespace_id)
+        .bind(used_bytes as i64)
+        .bind(only_if_unknown)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn reserve_usage(
+        &self,
+        namespace_id: i32,
+        delta: i64,
+        limit: Option<u64>,
+    ) -> Result<Option<u64>, Box<dyn Error>> {
+        let used_bytes: Option<(i64,)> = sqlx::query_as(
+            "UPDATE namespaces
+            SET used_bytes = CASE WHEN used_bytes + $2 < 0 THEN 0 ELSE used_bytes + $2 END
+            WHERE id = $1 AND used_bytes IS NOT NULL
+                AND ($3 IS NULL OR used_bytes + $2 <= $3)
+            RETURNING used_bytes",
+        )
+        .bind(namespace_id)
+        .bind(delta)
+        .bind(limit.map(|limit| limit as i64))
+        .fetch_optional(&self.db_pool)
+        .await?;
+        Ok(used_bytes.map(|(used_bytes,)| used_bytes as u64))
+    }
+
+    async fn get_quota(&self, namespace: &str) -> Result<Option<u64>, Box<dyn Error>> {
+        let quota: Option<(i64,)> =
+            sqlx::query_as("SELECT quota_bytes FROM storage_quotas WHERE namespace = $1")
+                .bind(namespace)
+                .fetch_optional(&self.db_pool)
+                .await?;
+        Ok(quota.map(|(quota_bytes,)| quota_bytes as u64))
+    }
+
+    async fn set_quota(&self, namespace: &str, quota_bytes: u64) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO storage_quotas (namespace, quota_bytes) VALUES ($1, $2)
+            ON CONFLICT (namespace) DO UPDATE SET quota_bytes = EXCLUDED.quota_bytes",
+        )
+        .bind(namespace)
+        .bind(quota_bytes as i64)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_quota(&self, namespace: &str) -> Result<bool, Box<dyn Error>> {
+        let result = sqlx::query("DELETE FROM storage_quotas WHERE namespace = $1")
+            .bind(namespace)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn insert_user(
+        &self,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<users::User, Box<dyn Error>> {
+        let (id,): (i32,) = sqlx::query_as(
+            "INSERT INTO users (username, password_hash) VALUES ($1, $2) RETURNING id",
+        )
+        .bind(username)
+        .bind(password_hash)
+        .fetch_one(&self.db_pool)
+        .await?;
+        Ok(users::User::new(id, username.to_string()))
+    }
+
+    async fn get_user(&self, username: &str) -> Result<Option<users::User>, Box<dyn Error>> {
+        let user: Option<(i32, String)> =
+            sqlx::query_as("SELECT id, username FROM users WHERE username = $1")
+                .bind(username)
+                .fetch_optional(&self.db_pool)
+                .await?;
+        Ok(user.map(|(id, username)| users::User::new(id, username)))
+    }
+
+    async fn get_password_hash(
+        &self,
+        username: &str,
+    ) -> Result<Option<(users::User, String)>, Box<dyn Error>> {
+        let user: Option<(i32, String, String)> =
+            sqlx::query_as("SELECT id, username, password_hash FROM users WHERE username = $1")
+                .bind(username)
+                .fetch_optional(&self.db_pool)
+                .await?;
+        Ok(user
+            .map(|(id, username, password_hash)| (users::User::new(id, username), password_hash)))
+    }
+
+    async fn set_password_hash(
+        &self,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        let result = sqlx::query("UPDATE users SET password_hash = $2 WHERE username = $1")
+            .bind(username)
+            .bind(password_hash)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn remove_user(&self, username: &str) -> Result<bool, Box<dyn Error>> {
+        let result = sqlx::query("DELETE FROM users WHERE username = $1")
+            .bind(username)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn add_to_group(&self, user_id: i32, group_name: &str) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO user_groups (user_id, group_name) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )
+        .bind(user_id)
+        .bind(group_name)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_from_group(
+        &self,
+        user_id: i32,
+        group_name: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query("DELETE FROM user_groups WHERE user_id = $1 AND group_name = $2")
+            .bind(user_id)
+            .bind(group_name)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn grant(
+        &self,
+        namespace_id: i32,
+        path: &str,
+        principal: &acl::Principal,
+        permission: acl::Permission,
+    ) -> Result<(), Box<dyn Error>> {
+        let path = acl::normalize_path(path).ok_or_else(|| format!("Invalid path: `{}`", path))?;
+        let (user_id, group_name) = principal.columns();
+        sqlx::query(
+            "INSERT INTO acl_entries (namespace_id, path, user_id, group_name, permission)
+            VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(namespace_id)
+        .bind(path)
+        .bind(user_id)
+        .bind(group_name)
+        .bind(permission.as_str())
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn revoke(
+        &self,
+        namespace_id: i32,
+        path: &str,
+        principal: &acl::Principal,
+    ) -> Result<bool, Box<dyn Error>> {
+        let path = acl::normalize_path(path).ok_or_else(|| format!("Invalid path: `{}`", path))?;
+        let (user_id, group_name) = principal.columns();
+        let result = sqlx::query(
+            "DELETE FROM acl_entries WHERE namespace_id = $1 AND path = $2
+            AND user_id IS NOT DISTINCT FROM $3 AND group_name IS NOT DISTINCT FROM $4",
+        )
+        .bind(namespace_id)
+        .bind(path)
+        .bind(user_id)
+        .bind(group_name)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn namespace_has_entries(&self, namespace_id: i32) -> Result<bool, Box<dyn Error>> {
+        let (has_entries,): (bool,) =
+            sqlx::query_as("SELECT EXISTS (SELECT 1 FROM acl_entries WHERE namespace_id = $1)")
+                .bind(namespace_id)
+                .fetch_one(&self.db_pool)
+                .await?;
+        Ok(has_entries)
+    }
+
+    async fn entries_for_user(
+        &self,
+        namespace_id: i32,
+        user: &users::User,
+    ) -> Result<Vec<(String, acl::Permission)>, Box<dyn Error>> {
+        let rows: Vec<(String, String)> = sqlx::query_as(
+            "SELECT path, permission FROM acl_entries
+            WHERE namespace_id = $1 AND (
+                user_id = $2
+                OR group_name IN (SELECT group_name FROM user_groups WHERE user_id = $2)
+            )",
+        )
+        .bind(namespace_id)
+        .bind(user.id())
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        let mut entries = Vec::with_capacity(rows.len());
+        for (path, permission) in rows {
+            entries.push((path, permission.parse()?));
+        }
+        Ok(entries)
+    }
+
+    async fn register_device(
+        &self,
+        device_id: &str,
+        hostname: &str,
+        client_version: &str,
+        user_id: Option<i32>,
+    ) -> Result<devices::Device, Box<dyn Error>> {
+        let device: devices::DeviceRow = sqlx::query_as(&format!(
+            "INSERT INTO devices (device_id, hostname, client_version, user_id, approved)
+            VALUES ($1, $2, $3, $4, $4 IS NULL)
+            ON CONFLICT (device_id) DO UPDATE SET
+                hostname = EXCLUDED.hostname,
+                client_version = EXCLUDED.client_version,
+                user_id = COALESCE(devices.user_id, EXCLUDED.user_id),
+                approved = devices.approved
+                    AND (devices.user_id IS NOT NULL OR EXCLUDED.user_id IS NULL),
+                last_seen = now()
+            RETURNING {}",
+            DEVICE_COLUMNS
+        ))
+        .bind(device_id)
+        .bind(hostname)
+        .bind(client_version)
+        .bind(user_id)
+        .fetch_one(&self.db_pool)
+        .await?;
+        Ok(device.into())
+    }
+
+    async fn get_device(&self, device_id: &str) -> Result<Option<devices::Device>, Box<dyn Error>> {
+        let device: Option<devices::DeviceRow> = sqlx::query_as(&format!(
+            "SELECT {} FROM devices WHERE device_id = $1",
+            DEVICE_COLUMNS
+        ))
+        .bind(device_id)
+        .fetch_optional(&self.db_pool)
+        .await?;
+        Ok(device.map(devices::Device::from))
+    }
+
+    async fn list_devices(&self) -> Result<Vec<devices::Device>, Box<dyn Error>> {
+        let devices: Vec<devices::DeviceRow> = sqlx::query_as(&format!(
+            "SELECT {} FROM devices ORDER BY last_seen DESC",
+            DEVICE_COLUMNS
+        ))
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(devices.into_iter().map(devices::Device::from).collect())
+    }
+
+    async fn approve_device(&self, device_id: &str) -> Result<bool, Box<dyn Error>> {
+        let result = sqlx::query("UPDATE devices SET approved = TRUE WHERE device_id = $1")
+            .bind(device_id)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn revoke_device(&self, device_id: &str) -> Result<bool, Box<dyn Error>> {
+        let result = sqlx::query("UPDATE devices SET revoked = TRUE WHERE device_id = $1")
+            .bind(device_id)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn set_sync_filter(
+        &self,
+        id: i32,
+        include: &[String],
+        exclude: &[String],
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query("UPDATE devices SET sync_include = $2, sync_exclude = $3 WHERE id = $1")
+            .bind(id)
+            .bind(include)
+            .bind(exclude)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn acknowledge_version(
+        &self,
+        id: i32,
+        namespace_id: i32,
+        server_version: i64,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO device_cursors (device_id, namespace_id, server_version)
+            SELECT $1, $2, LEAST($3, server_version) FROM namespaces WHERE id = $2
+            ON CONFLICT (device_id, namespace_id) DO UPDATE SET
+                server_version = GREATEST(device_cursors.server_version, EXCLUDED.server_version)",
+        )
+        .bind(id)
+        .bind(namespace_id)
+        .bind(server_version)
+        .execute(&self.db_pool)
+        .await?;
+        sqlx::query("UPDATE devices SET last_seen = now() WHERE id = $1")
+            .bind(id)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_cursors(&self, namespace_id: i32) -> Result<Vec<(String, i64)>, Box<dyn Error>> {
+        Ok(sqlx::query_as(
+            "SELECT devices.device_id, device_cursors.server_version
+            FROM device_cursors JOIN devices ON devices.id = device_cursors.device_id
+            WHERE device_cursors.namespace_id = $1
+            ORDER BY devices.device_id",
+        )
+        .bind(namespace_id)
+        .fetch_all(&self.db_pool)
+        .await?)
+    }
+
+    async fn min_acknowledged_version(
+        &self,
+        namespace_id: i32,
+    ) -> Result<Option<i64>, Box<dyn Error>> {
+        let (version,): (Option<i64>,) = sqlx::query_as(
+            "SELECT MIN(device_cursors.server_version)
+            FROM device_cursors JOIN devices ON devices.id = device_cursors.device_id
+            WHERE device_cursors.namespace_id = $1 AND NOT devices.revoked",
+        )
+        .bind(namespace_id)
+        .fetch_one(&self.db_pool)
+        .await?;
+        Ok(version)
+    }
+}
@@ -0,0 +1,1433 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::str::FromStr;
+
+use hcs_lib::{data, server_database};
+use sqlx::Executor;
+
+use super::{devices, migrations, namespaces, users, Database};
+use crate::{acl, metadata, symlinks};
+
+const DEVICE_COLUMNS: &str = "id, device_id, hostname, client_version,
+    last_seen, revoked, sync_include, sync_exclude, user_id, approved";
+
+/// Like `devices::DeviceRow`, with the pattern lists bincode encoded.
+type DeviceRow = (
+    i32,
+    String,
+    String,
+    String,
+    i64,
+    bool,
+    Vec<u8>,
+    Vec<u8>,
+    Option<i32>,
+    bool,
+);
+
+const NOW: &str = "CAST(strftime('%s', 'now') AS INTEGER)";
+
+/// The first row a `RETURNING` statement gave. Outside a transaction SQLite
+/// only commits the statement once all of its rows are read, which
+/// `fetch_one` and `fetch_optional` do not do, so these use `fetch_all`.
+fn first_row<T>(rows: Vec<T>) -> Option<T> {
+    rows.into_iter().next()
+}
+
+/// Single file database, for deployments with one user that do not want to
+/// run Postgres. `sqlite::memory:` keeps everything in memory, on a single
+/// connection.
+#[derive(Debug, Clone)]
+pub struct SqliteDatabase {
+    db_pool: sqlx::SqlitePool,
+}
+
+impl SqliteDatabase {
+    pub fn new(db_pool: sqlx::SqlitePool) -> Self {
+        Self { db_pool }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn pool(&self) -> &sqlx::SqlitePool {
+        &self.db_pool
+    }
+
+    pub async fn connect(db_config: &server_database::DbConfig) -> Result<Self, sqlx::Error> {
+        Self::open(db_config.database_url(), db_config.max_connections()).await
+    }
+
+    /// Connects to `database_url` with at most `max_connections` connections.
+    pub(crate) async fn open(
+        database_url: &str,
+        max_connections: u32,
+    ) -> Result<Self, sqlx::Error> {
+        let options = sqlx::sqlite::SqliteConnectOptions::from_str(database_url)?
+            .create_if_missing(true)
+            .foreign_keys(true)
+            .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal);
+        // Every connection to an in-memory database opens a database of its
+        // own.
+        let max_connections = match database_url.contains(":memory:") {
+            true => 1,
+            false => max_connections,
+        };
+        let db_pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(max_connections)
+            .connect_with(options)
+            .await?;
+        Ok(Self { db_pool })
+    }
+}
+
+#[async_trait::async_trait]
+impl Database for SqliteDatabase {
+    async fn migrate(&self) -> Result<usize, Box<dyn Error>> {
+        let mut transaction = self.db_pool.begin().await?;
+        sqlx::query(migrations::CREATE_TABLE)
+            .execute(&mut transaction)
+            .await?;
+
+        let (database,): (Option<i64>,) =
+            sqlx::query_as("SELECT MAX(version) FROM schema_migrations")
+                .fetch_one(&mut transaction)
+                .await?;
+        let database = database.unwrap_or(0);
+        let latest = self.latest_schema_version();
+        if database > latest {
+            return Err(migrations::SchemaError::TooNew {
+                database,
+                supported: latest,
+            }
+            .into());
+        }
+
+        let pending = migrations::MIGRATIONS
+            .iter()
+            .filter(|migration| migration.version > database)
+            .collect::<Vec<_>>();
+        for migration in &pending {
+            log::info!(
+                "Applying migration {} ({})",
+                migration.version,
+                migration.name
+            );
+            transaction.execute(migration.sqlite).await?;
+            sqlx::query("INSERT INTO schema_migrations (version, name) VALUES ($1, $2)")
+                .bind(migration.version)
+                .bind(migration.name)
+                .execute(&mut transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(pending.len())
+    }
+
+    async fn schema_version(&self) -> Result<i64, Box<dyn Error>> {
+        sqlx::query(migrations::CREATE_TABLE)
+            .execute(&self.db_pool)
+            .await?;
+        let (version,): (Option<i64>,) =
+            sqlx::query_as("SELECT MAX(version) FROM schema_migrations")
+                .fetch_one(&self.db_pool)
+                .await?;
+        Ok(version.unwrap_or(0))
+    }
+
+    fn latest_schema_version(&self) -> i64 {
+        migrations::latest_version()
+    }
+
+    async fn get_namespace(
+        &self,
+        name: &str,
+    ) -> Result<Option<namespaces::NamespaceRow>, Box<dyn Error>> {
+        Ok(sqlx::query_as(
+            "SELECT id, name, storage_directory, end_to_end FROM namespaces WHERE name = $1",
+        )
+        .bind(name)
+        .fetch_optional(&self.db_pool)
+        .await?)
+    }
+
+    async fn list_namespaces(&self) -> Result<Vec<namespaces::NamespaceRow>, Box<dyn Error>> {
+        Ok(sqlx::query_as(
+            "SELECT id, name, storage_directory, end_to_end FROM namespaces ORDER BY name",
+        )
+        .fetch_all(&self.db_pool)
+        .await?)
+    }
+
+    async fn insert_namespace(
+        &self,
+        name: &str,
+        storage_directory: &str,
+        end_to_end: bool,
+    ) -> Result<namespaces::NamespaceRow, Box<dyn Error>> {
+        Ok(sqlx::query_as(
+            "INSERT INTO namespaces (name, storage_directory, end_to_end) VALUES ($1, $2, $3)
+            RETURNING id, name, storage_directory, end_to_end",
+        )
+        .bind(name)
+        .bind(storage_directory)
+        .bind(end_to_end)
+        .fetch_all(&self.db_pool)
+        .await
+        .map(first_row)?
+        .ok_or(sqlx::Error::RowNotFound)?)
+    }
+
+    async fn import_namespace(
+        &self,
+        name: &str,
+        storage_directory: &str,
+        end_to_end: bool,
+        changes: Vec<(i64, data::ChangeEvent)>,
+    ) -> Result<namespaces::NamespaceRow, Box<dyn Error>> {
+        let mut transaction = self.db_pool.begin().await?;
+        let namespace: namespaces::NamespaceRow = sqlx::query_as(
+            "INSERT INTO namespaces (name, storage_directory, end_to_end) VALUES ($1, $2, $3)
+            RETURNING id, name, storage_directory, end_to_end",
+        )
+        .bind(name)
+        .bind(storage_directory)
+        .bind(end_to_end)
+        .fetch_one(&mut transaction)
+        .await?;
+        for (version, change_event) in changes {
+            let bytes = bincode::serialize(&change_event)?;
+            sqlx::query(
+                "INSERT INTO namespace_changes (namespace_id, version, change_event)
+                VALUES ($1, $2, $3)",
+            )
+            .bind(namespace.0)
+            .bind(version)
+            .bind(bytes)
+            .execute(&mut transaction)
+            .await?;
+            sqlx::query(
+                "UPDATE namespaces SET server_version = MAX(server_version, $2) WHERE id = $1",
+            )
+            .bind(namespace.0)
+            .bind(version)
+            .execute(&mut transaction)
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok(namespace)
+    }
+
+    /// SQLite databases never held a legacy change log.
+    async fn get_legacy_changes(&self) -> Result<Vec<(i64, data::ChangeEvent)>, Box<dyn Error>> {
+        Ok(Vec::new())
+    }
+
+    async fn get_server_version(&self, namespace_id: i32) -> Result<i64, Box<dyn Error>> {
+        let (server_version,): (i64,) =
+            sqlx::query_as("SELECT server_version FROM namespaces WHERE id = $1")
+                .bind(namespace_id)
+                .fetch_one(&self.db_pool)
+                .await?;
+        Ok(server_version)
+    }
+
+    async fn get_changes(
+        &self,
+        namespace_id: i32,
+        from_version: i64,
+        to_version: i64,
+    ) -> Result<Vec<(i64, data::ChangeEvent)>, Box<dyn Error>> {
+        let rows: Vec<(i64, Vec<u8>)> = sqlx::query_as(
+            "SELECT version, change_event FROM namespace_changes
+            WHERE namespace_id = $1 AND version > $2 AND version <= $3
+            ORDER BY version",
+        )
+        .bind(namespace_id)
+        .bind(from_version)
+        .bind(to_version)
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        let mut changes = Vec::with_capacity(rows.len());
+        for (version, bytes) in rows {
+            changes.push((version, bincode::deserialize(&bytes)?));
+        }
+        Ok(changes)
+    }
+
+    async fn insert_change(
+        &self,
+        namespace_id: i32,
+        change_event: data::ChangeEvent,
+        usage_delta: i64,
+    ) -> Result<i64, Box<dyn Error>> {
+        let bytes = bincode::serialize(&change_event)?;
+
+        let mut transaction = self.db_pool.begin().await?;
+        let (version,): (i64,) = sqlx::query_as(
+            "UPDATE namespaces SET server_version = server_version + 1,
+                used_bytes = CASE WHEN used_bytes + $2 < 0 THEN 0 ELSE used_bytes + $2 END
+            WHERE id = $1
+            RETURNING server_version",
+        )
+        .bind(namespace_id)
+        .bind(usage_delta)
+        .fetch_one(&mut transaction)
+        .await?;
+        sqlx::query(
+            "INSERT INTO namespace_changes (namespace_id, version, change_event)
+            VALUES ($1, $2, $3)",
+        )
+        .bind(namespace_id)
+        .bind(version)
+        .bind(bytes)
+        .execute(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+
+        Ok(version)
+    }
+
+    async fn get_compacted_version(&self, namespace_id: i32) -> Result<i64, Box<dyn Error>> {
+        let (compacted_version,): (i64,) =
+            sqlx::query_as("SELECT compacted_version FROM namespaces WHERE id = $1")
+                .bind(namespace_id)
+                .fetch_one(&self.db_pool)
+                .await?;
+        Ok(compacted_version)
+    }
+
+    async fn replace_changes(
+        &self,
+        namespace_id: i32,
+        horizon: i64,
+        baseline: Vec<(i64, data::ChangeEvent)>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut transaction = self.db_pool.begin().await?;
+        sqlx::query("DELETE FROM namespace_changes WHERE namespace_id = $1 AND version <= $2")
+            .bind(namespace_id)
+            .bind(horizon)
+            .execute(&mut transaction)
+            .await?;
+        for (version, change_event) in baseline {
+            let bytes = bincode::serialize(&change_event)?;
+            sqlx::query(
+                "INSERT INTO namespace_changes (namespace_id, version, change_event)
+                VALUES ($1, $2, $3)",
+            )
+            .bind(namespace_id)
+            .bind(version)
+            .bind(bytes)
+            .execute(&mut transaction)
+            .await?;
+        }
+        sqlx::query(
+            "UPDATE namespaces SET compacted_version = MAX(compacted_version, $2)
+            WHERE id = $1",
+        )
+        .bind(namespace_id)
+        .bind(horizon)
+        .execute(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn get_symlink_changes(
+        &self,
+        namespace_id: i32,
+        from_version: i64,
+        to_version: i64,
+    ) -> Result<Vec<(i64, symlinks::SymlinkEvent)>, Box<dyn Error>> {
+        let rows: Vec<(i64, Vec<u8>)> = sqlx::query_as(
+            "SELECT version, symlink_event FROM namespace_symlink_changes
+            WHERE namespace_id = $1 AND version > $2 AND version <= $3
+            ORDER BY version",
+        )
+        .bind(namespace_id)
+        .bind(from_version)
+        .bind(to_version)
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        let mut changes = Vec::with_capacity(rows.len());
+        for (version, bytes) in rows {
+            changes.push((version, bincode::deserialize(&bytes)?));
+        }
+        Ok(changes)
+    }
+
+    async fn insert_symlink_change(
+        &self,
+        namespace_id: i32,
+        symlink_event: &symlinks::SymlinkEvent,
+    ) -> Result<i64, Box<dyn Error>> {
+        let bytes = bincode::serialize(symlink_event)?;
+
+        let mut transaction = self.db_pool.begin().await?;
+        let (version,): (i64,) = sqlx::query_as(
+            "UPDATE namespaces SET server_version = server_version + 1 WHERE id = $1
+            RETURNING server_version",
+        )
+        .bind(namespace_id)
+        .fetch_one(&mut transaction)
+        .await?;
+        sqlx::query(
+            "INSERT INTO namespace_symlink_changes (namespace_id, version, symlink_event)
+            VALUES ($1, $2, $3)",
+        )
+        .bind(namespace_id)
+        .bind(version)
+        .bind(bytes)
+        .execute(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+
+        Ok(version)
+    }
+
+    async fn remove_symlink_changes(
+        &self,
+        namespace_id: i32,
+        horizon: i64,
+    ) -> Result<u64, Box<dyn Error>> {
+        let result = sqlx::query(
+            "DELETE FROM namespace_symlink_changes WHERE namespace_id = $1 AND version <= $2",
+        )
+        .bind(namespace_id)
+        .bind(horizon)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn record_checksum(
+        &self,
+        namespace_id: i32,
+        path: &str,
+        sha256: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO file_checksums (namespace_id, path, sha256) VALUES ($1, $2, $3)
+            ON CONFLICT (namespace_id, path) DO UPDATE SET
+                sha256 = EXCLUDED.sha256,
+                metadata = NULL",
+        )
+        .bind(namespace_id)
+        .bind(path)
+        .bind(sha256)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_checksums(
+        &self,
+        namespace_id: i32,
+    ) -> Result<HashMap<String, String>, Box<dyn Error>> {
+        let rows: Vec<(String, String)> =
+            sqlx::query_as("SELECT path, sha256 FROM file_checksums WHERE namespace_id = $1")
+                .bind(namespace_id)
+                .fetch_all(&self.db_pool)
+                .await?;
+        Ok(rows.into_iter().collect())
+    }
+
+    async fn set_file_metadata(
+        &self,
+        namespace_id: i32,
+        path: &str,
+        metadata: &metadata::FileMetadata,
+    ) -> Result<(), Box<dyn Error>> {
+        let bytes = bincode::serialize(metadata)?;
+        sqlx::query(
+            "UPDATE file_checksums SET metadata = $3 WHERE namespace_id = $1 AND path = $2",
+        )
+        .bind(namespace_id)
+        .bind(path)
+        .bind(bytes)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_file_metadata(
+        &self,
+        namespace_id: i32,
+        path: &str,
+    ) -> Result<Option<metadata::FileMetadata>, Box<dyn Error>> {
+        let row: Option<(Option<Vec<u8>>,)> = sqlx::query_as(
+            "SELECT metadata FROM file_checksums WHERE namespace_id = $1 AND path = $2",
+        )
+        .bind(namespace_id)
+        .bind(path)
+        .fetch_optional(&self.db_pool)
+        .await?;
+        match row {
+            Some((Some(bytes),)) => Ok(Some(bincode::deserialize(&bytes)?)),
+            _ => Ok(None),
+        }
+    }
+
+    async fn move_checksums(
+        &self,
+        namespace_id: i32,
+        from_path: &str,
+        to_path: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "UPDATE file_checksums SET path = $3 || substr(path, length($2) + 1)
+            WHERE namespace_id = $1 AND (path = $2 OR substr(path, 1, length($2) + 1) = $2 || '/')",
+        )
+        .bind(namespace_id)
+        .bind(from_path)
+        .bind(to_path)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_checksums(&self, namespace_id: i32, path: &str) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "DELETE FROM file_checksums
+            WHERE namespace_id = $1 AND (path = $2 OR substr(path, 1, length($2) + 1) = $2 || '/')",
+        )
+        .bind(namespace_id)
+        .bind(path)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_usage(&self, namespace_id: i32) -> Result<Option<u64>, Box<dyn Error>> {
+        let (used_bytes,): (Option<i64>,) =
+            sqlx::query_as("SELECT used_bytes FROM namespaces WHERE id = $1")
+                .bind(namespace_id)
+                .fetch_one(&self.db_pool)
+                .await?;
+        Ok(used_bytes.map(|used_bytes| used_bytes as u64))
+    }
+
+    async fn set_usage(
+        &self,
+        namespace_id: i32,
+        used_bytes: u64,
+        only_if_unknown: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "UPDATE namespaces SET used_bytes = $2
+            WHERE id = $1 AND (NOT $3 OR used_bytes IS NULL)",
+        )
+        .bind(namespace_id)
+        .bind(used_bytes as i64)
+        .bind(only_if_unknown)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn reserve_usage(
+        &self,
+        namespace_id: i32,
+        delta: i64,
+        limit: Option<u64>,
+    ) -> Result<Option<u64>, Box<dyn Error>> {
+        let used_bytes: Vec<(i64,)> = sqlx::query_as(
+            "UPDATE namespaces
+            SET used_bytes = CASE WHEN used_bytes + $2 < 0 THEN 0 ELSE used_bytes + $2 END
+            WHERE id = $1 AND used_bytes IS NOT NULL
+                AND ($3 IS NULL OR used_bytes + $2 <= $3)
+            RETURNING used_bytes",
+        )
+        .bind(namespace_id)
+        .bind(delta)
+        .bind(limit.map(|limit| limit as i64))
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(first_row(used_bytes).map(|(used_bytes,)| used_bytes as u64))
+    }
+
+    async fn get_quota(&self, namespace: &str) -> Result<Option<u64>, Box<dyn Error>> {
+        let quota: Option<(i64,)> =
+            sqlx::query_as("SELECT quota_bytes FROM storage_quotas WHERE namespace = $1")
+                .bind(namespace)
+                .fetch_optional(&self.db_pool)
+                .await?;
+        Ok(quota.map(|(quota_bytes,)| quota_bytes as u64))
+    }
+
+    async fn set_quota(&self, namespace: &str, quota_bytes: u64) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO storage_quotas (namespace, quota_bytes) VALUES ($1, $2)
+            ON CONFLICT (namespace) DO UPDATE SET quota_bytes = EXCLUDED.quota_bytes",
+        )
+        .bind(namespace)
+        .bind(quota_bytes as i64)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_quota(&self, namespace: &str) -> Result<bool, Box<dyn Error>> {
+        let result = sqlx::query("DELETE FROM storage_quotas WHERE namespace = $1")
+            .bind(namespace)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn insert_user(
+        &self,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<users::User, Box<dyn Error>> {
+        let (id,): (i32,) = sqlx::query_as(
+            "INSERT INTO users (username, password_hash) VALUES ($1, $2) RETURNING id",
+        )
+        .bind(username)
+        .bind(password_hash)
+        .fetch_all(&self.db_pool)
+        .await
+        .map(first_row)?
+        .ok_or(sqlx::Error::RowNotFound)?;
+        Ok(users::User::new(id, username.to_string()))
+    }
+
+    async fn get_user(&self, username: &str) -> Result<Option<users::User>, Box<dyn Error>> {
+        let user: Option<(i32, String)> =
+            sqlx::query_as("SELECT id, username FROM users WHERE username = $1")
+                .bind(username)
+                .fetch_optional(&self.db_pool)
+                .await?;
+        Ok(user.map(|(id, username)| users::User::new(id, username)))
+    }
+
+    async fn get_password_hash(
+        &self,
+        username: &str,
+    ) -> Result<Option<(users::User, String)>, Box<dyn Error>> {
+        let user: Option<(i32, String, String)> =
+            sqlx::query_as("SELECT id, username, password_hash FROM users WHERE username = $1")
+                .bind(username)
+                .fetch_optional(&self.db_pool)
+                .await?;
+        Ok(user
+            .map(|(id, username, password_hash)| (users::User::new(id, username), password_hash)))
+    }
+
+    async fn set_password_hash(
+        &self,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        let result = sqlx::query("UPDATE users SET password_hash = $2 WHERE username = $1")
+            .bind(username)
+            .bind(password_hash)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn remove_user(&self, username: &str) -> Result<bool, Box<dyn Error>> {
+        let result = sqlx::query("DELETE FROM users WHERE username = $1")
+            .bind(username)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn add_to_group(&self, user_id: i32, group_name: &str) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO user_groups (user_id, group_name) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )
+        .bind(user_id)
+        .bind(group_name)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_from_group(
+        &self,
+        user_id: i32,
+        group_name: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query("DELETE FROM user_groups WHERE user_id = $1 AND group_name = $2")
+            .bind(user_id)
+            .bind(group_name)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn grant(
+        &self,
+        namespace_id: i32,
+        path: &str,
+        principal: &acl::Principal,
+        permission: acl::Permission,
+    ) -> Result<(), Box<dyn Error>> {
+        let path = acl::normalize_path(path).ok_or_else(|| format!("Invalid path: `{}`", path))?;
+        let (user_id, group_name) = principal.columns();
+        sqlx::query(
+            "INSERT INTO acl_entries (namespace_id, path, user_id, group_name, permission)
+            VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(namespace_id)
+        .bind(path)
+        .bind(user_id)
+        .bind(group_name)
+        .bind(permission.as_str())
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn revoke(
+        &self,
+        namespace_id: i32,
+        path: &str,
+        principal: &acl::Principal,
+    ) -> Result<bool, Box<dyn Error>> {
+        let path = acl::normalize_path(path).ok_or_else(|| format!("Invalid path: `{}`", path))?;
+        let (user_id, group_name) = principal.columns();
+        let result = sqlx::query(
+            "DELETE FROM acl_entries WHERE namespace_id = $1 AND path = $2
+            AND user_id IS $3 AND group_name IS $4",
+        )
+        .bind(namespace_id)
+        .bind(path)
+        .bind(user_id)
+        .bind(group_name)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn namespace_has_entries(&self, namespace_id: i32) -> Result<bool, Box<dyn Error>> {
+        let (has_entries,): (bool,) =
+            sqlx::query_as("SELECT EXISTS (SELECT 1 FROM acl_entries WHERE namespace_id = $1)")
+                .bind(namespace_id)
+                .fetch_one(&self.db_pool)
+                .await?;
+        Ok(has_entries)
+    }
+
+    async fn entries_for_user(
+        &self,
+        namespace_id: i32,
+        user: &users::User,
+    ) -> Result<Vec<(String, acl::Permission)>, Box<dyn Error>> {
+        let rows: Vec<(String, String)> = sqlx::query_as(
+            "SELECT path, permission FROM acl_entries
+            WHERE namespace_id = $1 AND (
+                user_id = $2
+                OR group_name IN (SELECT group_name FROM user_groups WHERE user_id = $2)
+            )",
+        )
+        .bind(namespace_id)
+        .bind(user.id())
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        let mut entries = Vec::with_capacity(rows.len());
+        for (path, permission) in rows {
+            entries.push((path, permission.parse()?));
+        }
+        Ok(entries)
+    }
+
+    async fn register_device(
+        &self,
+        device_id: &str,
+        hostname: &str,
+        client_version: &str,
+        user_id: Option<i32>,
+    ) -> Result<devices::Device, Box<dyn Error>> {
+        let device: DeviceRow = sqlx::query_as(&format!(
+            "INSERT INTO devices (device_id, hostname, client_version, user_id, approved)
+            VALUES ($1, $2, $3, $4, $4 IS NULL)
+            ON CONFLICT (device_id) DO UPDATE SET
+                hostname = EXCLUDED.hostname,
+                client_version = EXCLUDED.client_version,
+                user_id = COALESCE(devices.user_id, EXCLUDED.user_id),
+                approved = devices.approved
+                    AND (devices.user_id IS NOT NULL OR EXCLUDED.user_id IS NULL),
+                last_seen = {}
+            RETURNING {}",
+            NOW, DEVICE_COLUMNS
+        ))
+        .bind(device_id)
+        .bind(hostname)
+        .bind(client_version)
+        .bind(user_id)
+        .fetch_all(&self.db_pool)
+        .await
+        .map(first_row)?
+        .ok_or(sqlx::Error::RowNotFound)?;
+        device_from_row(device)
+    }
+
+    async fn get_device(&self, device_id: &str) -> Result<Option<devices::Device>, Box<dyn Error>> {
+        let device: Option<DeviceRow> = sqlx::query_as(&format!(
+            "SELECT {} FROM devices WHERE device_id = $1",
+            DEVICE_COLUMNS
+        ))
+        .bind(device_id)
+        .fetch_optional(&self.db_pool)
+        .await?;
+        device.map(device_from_row).transpose()
+    }
+
+    async fn list_devices(&self) -> Result<Vec<devices::Device>, Box<dyn Error>> {
+        let devices: Vec<DeviceRow> = sqlx::query_as(&format!(
+            "SELECT {} FROM devices ORDER BY last_seen DESC",
+            DEVICE_COLUMNS
+        ))
+        .fetch_all(&self.db_pool)
+        .await?;
+        devices.into_iter().map(device_from_row).collect()
+    }
+
+    async fn approve_device(&self, device_id: &str) -> Result<bool, Box<dyn Error>> {
+        let result = sqlx::query("UPDATE devices SET approved = TRUE WHERE device_id = $1")
+            .bind(device_id)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn revoke_device(&self, device_id: &str) -> Result<bool, Box<dyn Error>> {
+        let result = sqlx::query("UPDATE devices SET revoked = TRUE WHERE device_id = $1")
+            .bind(device_id)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn set_sync_filter(
+        &self,
+        id: i32,
+        include: &[String],
+        exclude: &[String],
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query("UPDATE devices SET sync_include = $2, sync_exclude = $3 WHERE id = $1")
+            .bind(id)
+            .bind(bincode::serialize(include)?)
+            .bind(bincode::serialize(exclude)?)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn acknowledge_version(
+        &self,
+        id: i32,
+        namespace_id: i32,
+        server_version: i64,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO device_cursors (device_id, namespace_id, server_version)
+            SELECT $1, $2, MIN($3, server_version) FROM namespaces WHERE id = $2
+            ON CONFLICT (device_id, namespace_id) DO UPDATE SET
+                server_version = MAX(device_cursors.server_version, EXCLUDED.server_version)",
+        )
+        .bind(id)
+        .bind(namespace_id)
+        .bind(server_version)
+        .execute(&self.db_pool)
+        .await?;
+        sqlx::query(&format!(
+            "UPDATE devices SET last_seen = {} WHERE id = $1",
+            NOW
+        ))
+        .bind(id)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_cursors(&self, namespace_id: i32) -> Result<Vec<(String, i64)>, Box<dyn Error>> {
+        Ok(sqlx::query_as(
+            "SELECT devices.device_id, device_cursors.server_version
+            FROM device_cursors JOIN devices ON devices.id = device_cursors.device_id
+            WHERE device_cursors.namespace_id = $1
+            ORDER BY devices.device_id",
+        )
+        .bind(namespace_id)
+        .fetch_all(&self.db_pool)
+        .await?)
+    }
+
+    async fn min_acknowledged_version(
+        &self,
+        namespace_id: i32,
+    ) -> Result<Option<i64>, Box<dyn Error>> {
+        let (version,): (Option<i64>,) = sqlx::query_as(
+            "SELECT MIN(device_cursors.server_version)
+            FROM device_cursors JOIN devices ON devices.id = device_cursors.device_id
+            WHERE device_cursors.namespace_id = $1 AND NOT devices.revoked",
+        )
+        .bind(namespace_id)
+        .fetch_one(&self.db_pool)
+        .await?;
+        Ok(version)
+    }
+}
+
+fn device_from_row(
+    (
+        id,
+        device_id,
+        hostname,
+        client_version,
+        last_seen,
+        revoked,
+        sync_include,
+        sync_exclude,
+        user_id,
+        approved,
+    ): DeviceRow,
+) -> Result<devices::Device, Box<dyn Error>> {
+    Ok(devices::Device::from((
+        id,
+        device_id,
+        hostname,
+        client_version,
+        last_seen,
+        revoked,
+        bincode::deserialize(&sync_include)?,
+        bincode::deserialize(&sync_exclude)?,
+        user_id,
+        approved,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path;
+
+    use super::*;
+    use crate::test_support;
+
+    fn file_create(path: &str) -> data::ChangeEvent {
+        data::ChangeEvent::File(data::FileEvent::Create(data::FileCreate::new(
+            path.to_string(),
+            0,
+        )))
+    }
+
+    fn paths(changes: &[(i64, data::ChangeEvent)]) -> Vec<(i64, String)> {
+        changes
+            .iter()
+            .map(|(version, change_event)| (*version, test_support::describe(change_event)))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn migrations_are_applied_once() {
+        let database = test_support::memory_database().await;
+        assert_eq!(
+            database.schema_version().await.unwrap(),
+            database.latest_schema_version()
+        );
+        assert_eq!(database.migrate().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn returned_rows_are_committed() {
+        let directory = std::env::temp_dir().join(format!("hcs-returning-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let database_url = format!("sqlite://{}", directory.join("hcs.db").display());
+        let writer = SqliteDatabase::open(&database_url, 1).await.unwrap();
+        writer.migrate().await.unwrap();
+        let reader = SqliteDatabase::open(&database_url, 1).await.unwrap();
+
+        // Another connection only sees what was committed
+        let namespace = writer
+            .insert_namespace("docs", "/docs", false)
+            .await
+            .unwrap();
+        assert!(reader.get_namespace("docs").await.unwrap().is_some());
+        writer.insert_user("alice", "hash").await.unwrap();
+        assert!(reader.get_user("alice").await.unwrap().is_some());
+        writer
+            .register_device("laptop", "host", "1.0", None)
+            .await
+            .unwrap();
+        assert!(reader.get_device("laptop").await.unwrap().is_some());
+        writer.set_usage(namespace.0, 0, false).await.unwrap();
+        writer.reserve_usage(namespace.0, 10, None).await.unwrap();
+        assert_eq!(reader.get_usage(namespace.0).await.unwrap(), Some(10));
+
+        drop((writer, reader));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn changes_are_versioned_per_namespace() {
+        let database = test_support::memory_database().await;
+        let (docs, _) = test_support::memory_namespace(&database, "docs").await;
+        let (music, _) = test_support::memory_namespace(&database, "music").await;
+
+        for path in ["a", "b", "c"] {
+            database
+                .insert_change(docs.id(), file_create(path), 0)
+                .await
+                .unwrap();
+        }
+        let version = database
+            .insert_change(music.id(), file_create("song"), 0)
+            .await
+            .unwrap();
+
+        assert_eq!(version, 1);
+        assert_eq!(database.get_server_version(docs.id()).await.unwrap(), 3);
+        assert_eq!(
+            paths(&database.get_changes(docs.id(), 1, 2).await.unwrap()),
+            [(2, "create b".to_string())]
+        );
+        assert_eq!(
+            paths(&database.get_changes(docs.id(), 1, i64::MAX).await.unwrap()),
+            [(2, "create b".to_string()), (3, "create c".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn changes_update_usage() {
+        let database = test_support::memory_database().await;
+        let (namespace, _) = test_support::memory_namespace(&database, "docs").await;
+
+        // Unknown usage stays unknown
+        database
+            .insert_change(namespace.id(), file_create("a"), 5)
+            .await
+            .unwrap();
+        assert_eq!(database.get_usage(namespace.id()).await.unwrap(), None);
+
+        database.set_usage(namespace.id(), 5, false).await.unwrap();
+        database.set_usage(namespace.id(), 100, true).await.unwrap();
+        database
+            .insert_change(namespace.id(), file_create("b"), 10)
+            .await
+            .unwrap();
+        assert_eq!(database.get_usage(namespace.id()).await.unwrap(), Some(15));
+
+        let reserved = database.reserve_usage(namespace.id(), 5, Some(20)).await;
+        assert_eq!(reserved.unwrap(), Some(20));
+        let reserved = database.reserve_usage(namespace.id(), 1, Some(20)).await;
+        assert_eq!(reserved.unwrap(), None);
+        let reserved = database.reserve_usage(namespace.id(), -50, None).await;
+        assert_eq!(reserved.unwrap(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn quotas() {
+        let database = test_support::memory_database().await;
+
+        assert_eq!(database.get_quota("docs").await.unwrap(), None);
+        database.set_quota("docs", 10).await.unwrap();
+        database.set_quota("docs", 20).await.unwrap();
+        assert_eq!(database.get_quota("docs").await.unwrap(), Some(20));
+        assert!(database.remove_quota("docs").await.unwrap());
+        assert!(!database.remove_quota("docs").await.unwrap());
+        assert_eq!(database.get_quota("docs").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn min_acknowledged_version_skips_revoked_devices() {
+        let database = test_support::memory_database().await;
+        let (namespace, _) = test_support::memory_namespace(&database, "docs").await;
+        let (other, _) = test_support::memory_namespace(&database, "other").await;
+        let laptop = database
+            .register_device("laptop", "host", "1.0", None)
+            .await
+            .unwrap();
+        let phone = database
+            .register_device("phone", "host", "1.0", None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            database
+                .min_acknowledged_version(namespace.id())
+                .await
+                .unwrap(),
+            None
+        );
+
+        for path in ["a", "b", "c", "d", "e"] {
+            database
+                .insert_change(namespace.id(), file_create(path), 0)
+                .await
+                .unwrap();
+        }
+        database
+            .insert_change(other.id(), file_create("a"), 0)
+            .await
+            .unwrap();
+        database
+            .acknowledge_version(laptop.id(), namespace.id(), 5)
+            .await
+            .unwrap();
+        // Cursors never move backwards
+        database
+            .acknowledge_version(laptop.id(), namespace.id(), 3)
+            .await
+            .unwrap();
+        database
+            .acknowledge_version(phone.id(), namespace.id(), 2)
+            .await
+            .unwrap();
+        database
+            .acknowledge_version(phone.id(), other.id(), 1)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            database.get_cursors(namespace.id()).await.unwrap(),
+            [("laptop".to_string(), 5), ("phone".to_string(), 2)]
+        );
+        assert_eq!(
+            database
+                .min_acknowledged_version(namespace.id())
+                .await
+                .unwrap(),
+            Some(2)
+        );
+
+        assert!(database.revoke_device("phone").await.unwrap());
+        assert_eq!(
+            database
+                .min_acknowledged_version(namespace.id())
+                .await
+                .unwrap(),
+            Some(5)
+        );
+    }
+
+    #[tokio::test]
+    async fn cursors_stop_at_the_server_version() {
+        let database = test_support::memory_database().await;
+        let (namespace, _) = test_support::memory_namespace(&database, "docs").await;
+        let laptop = database
+            .register_device("laptop", "host", "1.0", None)
+            .await
+            .unwrap();
+        for path in ["a", "b"] {
+            database
+                .insert_change(namespace.id(), file_create(path), 0)
+                .await
+                .unwrap();
+        }
+
+        database
+            .acknowledge_version(laptop.id(), namespace.id(), 100)
+            .await
+            .unwrap();
+        assert_eq!(
+            database.get_cursors(namespace.id()).await.unwrap(),
+            [("laptop".to_string(), 2)]
+        );
+
+        database
+            .acknowledge_version(laptop.id(), namespace.id(), i64::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .min_acknowledged_version(namespace.id())
+                .await
+                .unwrap(),
+            Some(2)
+        );
+    }
+
+    #[tokio::test]
+    async fn devices_of_users_need_approval() {
+        let database = test_support::memory_database().await;
+        let user = database.insert_user("alice", "hash").await.unwrap();
+
+        let anonymous = database
+            .register_device("laptop", "host", "1.0", None)
+            .await
+            .unwrap();
+        assert!(anonymous.approved());
+        assert_eq!(anonymous.user_id(), None);
+
+        let updated = database
+            .register_device("laptop", "renamed", "1.1", None)
+            .await
+            .unwrap();
+        assert_eq!(updated.id(), anonymous.id());
+        assert_eq!(updated.hostname(), "renamed");
+        assert_eq!(updated.client_version(), "1.1");
+        assert!(updated.approved());
+
+        // Taken over by a user, it is theirs and needs approval again
+        let taken_over = database
+            .register_device("laptop", "renamed", "1.1", Some(user.id()))
+            .await
+            .unwrap();
+        assert_eq!(taken_over.user_id(), Some(user.id()));
+        assert!(!taken_over.approved());
+
+        assert!(database.approve_device("laptop").await.unwrap());
+        let device = database.get_device("laptop").await.unwrap().unwrap();
+        assert!(device.approved());
+        assert!(!device.revoked());
+
+        // Registering anonymously again does not unbind it
+        let device = database
+            .register_device("laptop", "renamed", "1.1", None)
+            .await
+            .unwrap();
+        assert_eq!(device.user_id(), Some(user.id()));
+        assert!(device.approved());
+
+        assert!(database.revoke_device("laptop").await.unwrap());
+        assert!(database
+            .get_device("laptop")
+            .await
+            .unwrap()
+            .unwrap()
+            .revoked());
+        assert!(!database.approve_device("missing").await.unwrap());
+        assert!(database.get_device("missing").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn sync_filters_are_stored() {
+        let database = test_support::memory_database().await;
+        let device = database
+            .register_device("laptop", "host", "1.0", None)
+            .await
+            .unwrap();
+
+        let include = ["docs/**".to_string()];
+        let exclude = ["*.tmp".to_string(), "cache/".to_string()];
+        database
+            .set_sync_filter(device.id(), &include, &exclude)
+            .await
+            .unwrap();
+
+        let device = database.get_device("laptop").await.unwrap().unwrap();
+        assert_eq!(device.sync_include(), include);
+        assert_eq!(device.sync_exclude(), exclude);
+    }
+
+    #[tokio::test]
+    async fn acl_entries_apply_to_users_and_their_groups() {
+        let database = test_support::memory_database().await;
+        let (namespace, _) = test_support::memory_namespace(&database, "docs").await;
+        let alice = database.insert_user("alice", "hash").await.unwrap();
+        let bob = database.insert_user("bob", "hash").await.unwrap();
+        database.add_to_group(alice.id(), "staff").await.unwrap();
+
+        assert!(!database
+            .namespace_has_entries(namespace.id())
+            .await
+            .unwrap());
+        database
+            .grant(
+                namespace.id(),
+                "/shared/./reports/",
+                &acl::Principal::Group("staff".to_string()),
+                acl::Permission::Read,
+            )
+            .await
+            .unwrap();
+        database
+            .grant(
+                namespace.id(),
+                "alice",
+                &acl::Principal::User(alice.id()),
+                acl::Permission::ReadWrite,
+            )
+            .await
+            .unwrap();
+        assert!(database
+            .namespace_has_entries(namespace.id())
+            .await
+            .unwrap());
+
+        let mut entries = database
+            .entries_for_user(namespace.id(), &alice)
+            .await
+            .unwrap();
+        entries.sort();
+        assert_eq!(
+            entries,
+            [
+                ("alice".to_string(), acl::Permission::ReadWrite),
+                ("shared/reports".to_string(), acl::Permission::Read),
+            ]
+        );
+        assert!(database
+            .entries_for_user(namespace.id(), &bob)
+            .await
+            .unwrap()
+            .is_empty());
+
+        database
+            .remove_from_group(alice.id(), "staff")
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .entries_for_user(namespace.id(), &alice)
+                .await
+                .unwrap(),
+            [("alice".to_string(), acl::Permission::ReadWrite)]
+        );
+
+        let principal = acl::Principal::User(alice.id());
+        assert!(database
+            .revoke(namespace.id(), "alice/", &principal)
+            .await
+            .unwrap());
+        assert!(!database
+            .revoke(namespace.id(), "alice", &principal)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn acl_paths_outside_the_namespace_are_rejected() {
+        let database = test_support::memory_database().await;
+        let (namespace, _) = test_support::memory_namespace(&database, "docs").await;
+        let principal = acl::Principal::Group("staff".to_string());
+
+        assert!(database
+            .grant(namespace.id(), "a/../..", &principal, acl::Permission::Read)
+            .await
+            .is_err());
+        assert!(database
+            .revoke(namespace.id(), "../a", &principal)
+            .await
+            .is_err());
+        assert!(!database
+            .namespace_has_entries(namespace.id())
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn checksums_follow_moves_and_deletes() {
+        let database = test_support::memory_database().await;
+        let (namespace, _) = test_support::memory_namespace(&database, "docs").await;
+        let id = namespace.id();
+        for path in ["dir/a", "dir/sub/b", "dirt", "c"] {
+            database.record_checksum(id, path, path).await.unwrap();
+        }
+
+        database.move_checksums(id, "dir", "moved").await.unwrap();
+        database.move_checksums(id, "c", "moved/c").await.unwrap();
+        let mut checksums: Vec<_> = database
+            .get_checksums(id)
+            .await
+            .unwrap()
+            .into_iter()
+            .collect();
+        checksums.sort();
+        assert_eq!(
+            checksums,
+            [
+                ("dirt".to_string(), "dirt".to_string()),
+                ("moved/a".to_string(), "dir/a".to_string()),
+                ("moved/c".to_string(), "c".to_string()),
+                ("moved/sub/b".to_string(), "dir/sub/b".to_string()),
+            ]
+        );
+
+        database.remove_checksums(id, "moved/sub").await.unwrap();
+        database.remove_checksums(id, "dirt").await.unwrap();
+        let mut paths: Vec<_> = database
+            .get_checksums(id)
+            .await
+            .unwrap()
+            .into_keys()
+            .collect();
+        paths.sort();
+        assert_eq!(paths, ["moved/a", "moved/c"]);
+    }
+
+    #[tokio::test]
+    async fn metadata_is_kept_with_its_checksum() {
+        let database = test_support::memory_database().await;
+        let (namespace, _) = test_support::memory_namespace(&database, "docs").await;
+        let id = namespace.id();
+        let metadata = metadata::FileMetadata::read(
+            &path::Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml"),
+        )
+        .unwrap();
+
+        // Without a checksum there is nothing to attach it to
+        database
+            .set_file_metadata(id, "a", &metadata)
+            .await
+            .unwrap();
+        assert_eq!(database.get_file_metadata(id, "a").await.unwrap(), None);
+
+        database.record_checksum(id, "a", "1").await.unwrap();
+        database
+            .set_file_metadata(id, "a", &metadata)
+            .await
+            .unwrap();
+        database.move_checksums(id, "a", "b").await.unwrap();
+        assert_eq!(
+            database.get_file_metadata(id, "b").await.unwrap(),
+            Some(metadata)
+        );
+
+        // A new upload drops the metadata of the previous one
+        database.record_checksum(id, "b", "2").await.unwrap();
+        assert_eq!(database.get_file_metadata(id, "b").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn imported_namespaces_keep_their_versions() {
+        let database = test_support::memory_database().await;
+        assert!(database.get_legacy_changes().await.unwrap().is_empty());
+
+        let namespace = database
+            .import_namespace(
+                "default",
+                "/default",
+                false,
+                vec![(3, file_create("a")), (7, file_create("b"))],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            database.get_namespace("default").await.unwrap(),
+            Some(namespace.clone())
+        );
+        assert_eq!(database.get_server_version(namespace.0).await.unwrap(), 7);
+        assert_eq!(
+            paths(
+                &database
+                    .get_changes(namespace.0, 0, i64::MAX)
+                    .await
+                    .unwrap()
+            ),
+            [(3, "create a".to_string()), (7, "create b".to_string())]
+        );
+        let version = database
+            .insert_change(namespace.0, file_create("c"), 0)
+            .await
+            .unwrap();
+        assert_eq!(version, 8);
+
+        // A failed import leaves nothing behind
+        assert!(database
+            .import_namespace(
+                "other",
+                "/other",
+                false,
+                vec![(1, file_create("a")), (1, file_create("b"))],
+            )
+            .await
+            .is_err());
+        assert_eq!(database.get_namespace("other").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,68 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+
+use super::Database;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    id: i32,
+    username: String,
+}
+
+impl User {
+    pub(super) fn new(id: i32, username: String) -> Self {
+        Self { id, username }
+    }
+
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+}
+
+fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut rand_core::OsRng);
+    let hash = argon2::Argon2::default().hash_password(password.as_bytes(), &salt)?;
+    Ok(hash.to_string())
+}
+
+pub async fn create_user(
+    username: &str,
+    password: &str,
+    database: &dyn Database,
+) -> Result<User, Box<dyn std::error::Error>> {
+    let password_hash = hash_password(password)?;
+    database.insert_user(username, &password_hash).await
+}
+
+pub async fn set_password(
+    username: &str,
+    password: &str,
+    database: &dyn Database,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let password_hash = hash_password(password)?;
+    database.set_password_hash(username, &password_hash).await
+}
+
+/// Returns the user if `password` matches the stored hash.
+pub async fn authenticate(
+    username: &str,
+    password: &str,
+    database: &dyn Database,
+) -> Result<Option<User>, Box<dyn std::error::Error>> {
+    let (user, password_hash) = match database.get_password_hash(username).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+
+    let parsed_hash = PasswordHash::new(&password_hash)?;
+    if argon2::Argon2::default()
+        .verify_password(password.as_bytes(), &parsed_hash)
+        .is_err()
+    {
+        return Ok(None);
+    }
+    Ok(Some(user))
+}
@@ -1,6 +1,120 @@
+use std::fmt;
+
 use hcs_lib::data;
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
-pub enum ServerTcpError {}
+pub enum ServerTcpError {
+    QuotaExceeded {
+        quota: u64,
+        used: u64,
+        requested: u64,
+    },
+    FileTooLarge {
+        max_file_size: u64,
+        requested: u64,
+    },
+    InsufficientSpace {
+        available: u64,
+        reserve: u64,
+        requested: u64,
+    },
+    UnknownNamespace(String),
+    AuthenticationFailed,
+    PermissionDenied(String),
+    InvalidSyncFilter(String),
+    Ignored(String),
+    DeviceRevoked(String),
+    /// The device is new, or was registered by someone else, and has to be
+    /// approved by an operator first.
+    DeviceNotApproved(String),
+    /// An authenticated client has to register an approved device before
+    /// syncing.
+    DeviceNotRegistered,
+    PathNotFound(String),
+    DestinationExists(String),
+    SymlinkInPath(String),
+    NotASymlink(String),
+    PathConflict {
+        path: String,
+        existing: String,
+    },
+    /// The path is absolute or has components other than plain names.
+    InvalidPath(String),
+    /// The client reported a version no namespace can be at.
+    InvalidVersion(i64),
+    /// The client sent a request or change the server does not handle. The
+    /// session ends.
+    Unsupported(String),
+    /// The server failed to handle a request and ends the session.
+    Internal,
+}
 
 impl data::Data for ServerTcpError {}
+
+impl fmt::Display for ServerTcpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerTcpError::QuotaExceeded {
+                quota,
+                used,
+                requested,
+            } => write!(
+                f,
+                "Quota exceeded: {} of {} bytes used, {} bytes requested",
+                used, quota, requested
+            ),
+            ServerTcpError::FileTooLarge {
+                max_file_size,
+                requested,
+            } => write!(
+                f,
+                "File too large: {} bytes requested, maximum is {} bytes",
+                requested, max_file_size
+            ),
+            ServerTcpError::InsufficientSpace {
+                available,
+                reserve,
+                requested,
+            } => write!(
+                f,
+                "Insufficient disk space: {} bytes requested, {} bytes available with {} bytes reserved",
+                requested, available, reserve
+            ),
+            ServerTcpError::UnknownNamespace(name) => write!(f, "Unknown namespace: `{}`", name),
+            ServerTcpError::AuthenticationFailed => write!(f, "Authentication failed"),
+            ServerTcpError::PermissionDenied(path) => write!(f, "Permission denied: `{}`", path),
+            ServerTcpError::InvalidSyncFilter(err) => write!(f, "Invalid sync filter: {}", err),
+            ServerTcpError::DeviceRevoked(device_id) => {
+                write!(f, "Device has been revoked: `{}`", device_id)
+            }
+            ServerTcpError::DeviceNotApproved(device_id) => {
+                write!(f, "Device is awaiting approval: `{}`", device_id)
+            }
+            ServerTcpError::DeviceNotRegistered => {
+                write!(f, "Register an approved device before syncing")
+            }
+            ServerTcpError::PathNotFound(path) => {
+                write!(f, "Path does not exist on the server: `{}`", path)
+            }
+            ServerTcpError::DestinationExists(path) => {
+                write!(f, "Destination already exists: `{}`", path)
+            }
+            ServerTcpError::SymlinkInPath(path) => {
+                write!(f, "Path resolves through a symlink: `{}`", path)
+            }
+            ServerTcpError::NotASymlink(path) => write!(f, "Not a symlink: `{}`", path),
+            ServerTcpError::PathConflict { path, existing } => write!(
+                f,
+                "Path conflicts with existing `{}`: `{}`",
+                existing, path
+            ),
+            ServerTcpError::InvalidPath(path) => write!(f, "Invalid path: `{}`", path),
+            ServerTcpError::InvalidVersion(version) => write!(f, "Invalid version: {}", version),
+            ServerTcpError::Unsupported(what) => write!(f, "Not supported by the server: {}", what),
+            ServerTcpError::Internal => write!(f, "Internal server error"),
+            ServerTcpError::Ignored(path) => write!(f, "Path is ignored by `.hcsignore`: `{}`", path),
+        }
+    }
+}
+
+impl std::error::Error for ServerTcpError {}
@@ -1,6 +1,63 @@
 use hcs_lib::data;
 
+use crate::{metadata, symlinks};
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
-pub enum ExtraData {}
+pub enum ExtraData {
+    /// Sent right after the greeting to sync a namespace other than the
+    /// default one.
+    SelectNamespace(String),
+    Authenticate {
+        username: String,
+        password: String,
+    },
+    /// Identifies the client machine. Sent right after the greeting; a
+    /// registered device keeps its sync filter between sessions.
+    RegisterDevice {
+        device_id: String,
+        hostname: String,
+    },
+    /// Glob patterns limiting which paths are sent to this client.
+    SetSyncFilter {
+        include: Vec<String>,
+        exclude: Vec<String>,
+    },
+    /// Asks for a snapshot of the current tree, for a client without any
+    /// local state. Answered like a pull from version 0.
+    BootstrapRequest,
+    /// Precedes a pull that describes the whole tree rather than the changes
+    /// since the client's version. It only holds creates and is applied on
+    /// top of what the client has: entries the snapshot does not contain are
+    /// kept and uploaded as creates afterwards, so nothing the client has not
+    /// uploaded yet is lost. Deletes that were compacted away are therefore
+    /// not seen by the client.
+    Snapshot {
+        server_version: i64,
+    },
+    /// Asks the client to upload the file or symlink at `path` as a create.
+    /// Sent when the client moved something the server does not have.
+    UploadRequest(String),
+    /// The destination of the client's move already existed, so the entry was
+    /// stored at `path` instead. The client should rename its copy to match.
+    MoveRenamed {
+        requested_path: String,
+        path: String,
+    },
+    /// Metadata of the file in the `FileCreate` or `FileModify` that follows.
+    /// Sent by either side; the receiver applies it once the file is written.
+    FileMetadata {
+        path: String,
+        metadata: metadata::FileMetadata,
+    },
+    /// A symlink change, in either direction. Counts towards
+    /// `number_of_changes` and is answered with a `ServerVersion` like any
+    /// other change.
+    Symlink(symlinks::SymlinkEvent),
+    QuotaRequest,
+    QuotaReport {
+        quota: Option<u64>,
+        used: u64,
+    },
+}
 
 impl data::Data for ExtraData {}
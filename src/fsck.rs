@@ -0,0 +1,381 @@
+//! Checks that a namespace's storage matches what its change log says it
+//! should contain, and optionally brings the change log back in line with
+//! what is actually stored.
+
+use std::collections::BTreeMap;
+use std::{fmt, io};
+
+use hcs_lib::data;
+use sha2::{Digest, Sha256};
+
+use crate::{db, storage};
+
+const CHECKSUM_BUFFER_SIZE: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+impl fmt::Display for EntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntryKind::File => write!(f, "file"),
+            EntryKind::Directory => write!(f, "directory"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    kind: EntryKind,
+    size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Discrepancy {
+    /// Recorded in the change log but absent from storage.
+    Missing { path: String, kind: EntryKind },
+    /// Present in storage but never recorded.
+    Unrecorded { path: String, kind: EntryKind },
+    KindMismatch {
+        path: String,
+        expected: EntryKind,
+        actual: EntryKind,
+    },
+    SizeMismatch {
+        path: String,
+        expected: u64,
+        actual: u64,
+    },
+    /// The file's contents no longer match the checksum taken on upload.
+    ChecksumMismatch { path: String },
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Discrepancy::Missing { path, kind } => {
+                write!(f, "{} `{}` is missing from storage", kind, path)
+            }
+            Discrepancy::Unrecorded { path, kind } => {
+                write!(f, "{} `{}` is not in the change log", kind, path)
+            }
+            Discrepancy::KindMismatch {
+                path,
+                expected,
+                actual,
+            } => write!(f, "`{}` should be a {} but is a {}", path, expected, actual),
+            Discrepancy::SizeMismatch {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "file `{}` should be {} bytes but is {} bytes",
+                path, expected, actual
+            ),
+            Discrepancy::ChecksumMismatch { path } => {
+                write!(f, "file `{}` does not match its upload checksum", path)
+            }
+        }
+    }
+}
+
+/// Compares the namespace's storage against its change log. With
+/// `verify_checksums` every file with a recorded checksum is read and hashed.
+/// With `repair` a corrective change is inserted for every discrepancy, taking
+/// storage as the source of truth.
+pub async fn check_namespace(
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    verify_checksums: bool,
+    repair: bool,
+) -> Result<Vec<Discrepancy>, Box<dyn std::error::Error>> {
+    let server_version = database.get_server_version(namespace.id()).await?;
+    let changes = database
+        .get_changes(namespace.id(), 0, server_version)
+        .await?;
+    let expected = replay(changes.into_iter().map(|(_, change_event)| change_event));
+
+    let mut actual = BTreeMap::new();
+    walk(namespace.storage(), "", &mut actual)?;
+
+    let mut discrepancies = compare(&expected, &actual);
+    if verify_checksums {
+        let checksums = database.get_checksums(namespace.id()).await?;
+        for (path, sha256) in checksums {
+            if actual.get(&path).map(|entry| entry.kind) != Some(EntryKind::File) {
+                continue;
+            }
+            if file_checksum(namespace.storage(), &path)? != sha256 {
+                discrepancies.push(Discrepancy::ChecksumMismatch { path });
+            }
+        }
+    }
+
+    for discrepancy in &discrepancies {
+        log::warn!("Namespace `{}`: {}", namespace.name(), discrepancy);
+    }
+    if repair {
+        for discrepancy in &discrepancies {
+            repair_discrepancy(database, namespace, discrepancy).await?;
+        }
+        let used = actual
+            .values()
+            .filter(|entry| entry.kind == EntryKind::File)
+            .map(|entry| entry.size)
+            .sum();
+        database.set_usage(namespace.id(), used, false).await?;
+    }
+    Ok(discrepancies)
+}
+
+/// The tree the change log describes, by relative path.
+fn replay(change_events: impl Iterator<Item = data::ChangeEvent>) -> BTreeMap<String, Entry> {
+    let mut tree = BTreeMap::new();
+    for change_event in change_events {
+        match change_event {
+            data::ChangeEvent::File(data::FileEvent::Create(file_create)) => {
+                tree.insert(
+                    file_create.path().to_string(),
+                    Entry {
+                        kind: EntryKind::File,
+                        size: file_create.size(),
+                    },
+                );
+            }
+            data::ChangeEvent::File(data::FileEvent::Modify(file_modify)) => {
+                tree.insert(
+                    file_modify.path().to_string(),
+                    Entry {
+                        kind: EntryKind::File,
+                        size: file_modify.size(),
+                    },
+                );
+            }
+            data::ChangeEvent::File(data::FileEvent::Delete(file_delete)) => {
+                tree.remove(file_delete.path());
+            }
+            data::ChangeEvent::File(data::FileEvent::Move(file_move)) => {
+                if let Some(entry) = tree.remove(file_move.from_path()) {
+                    tree.insert(file_move.to_path().to_string(), entry);
+                }
+            }
+            data::ChangeEvent::Directory(data::DirectoryEvent::Create(directory_create)) => {
+                tree.insert(
+                    directory_create.path().to_string(),
+                    Entry {
+                        kind: EntryKind::Directory,
+                        size: 0,
+                    },
+                );
+            }
+            data::ChangeEvent::Directory(data::DirectoryEvent::Delete(directory_delete)) => {
+                remove_subtree(&mut tree, directory_delete.path());
+            }
+            data::ChangeEvent::Directory(data::DirectoryEvent::Move(directory_move)) => {
+                for (path, entry) in remove_subtree(&mut tree, directory_move.from_path()) {
+                    let moved_path = format!(
+                        "{}{}",
+                        directory_move.to_path(),
+                        &path[directory_move.from_path().len()..]
+                    );
+                    tree.insert(moved_path, entry);
+                }
+            }
+            _ => {}
+        }
+    }
+    tree
+}
+
+fn remove_subtree(tree: &mut BTreeMap<String, Entry>, directory: &str) -> Vec<(String, Entry)> {
+    let prefix = format!("{}/", directory);
+    let paths = tree
+        .keys()
+        .filter(|path| *path == directory || path.starts_with(&prefix))
+        .cloned()
+        .collect::<Vec<_>>();
+    paths
+        .into_iter()
+        .filter_map(|path| tree.remove(&path).map(|entry| (path, entry)))
+        .collect()
+}
+
+/// Everything stored below `directory`. Symlinks are skipped, as in
+/// `snapshot::tree_changes`.
+fn walk(
+    storage: &dyn storage::StorageBackend,
+    directory: &str,
+    tree: &mut BTreeMap<String, Entry>,
+) -> io::Result<()> {
+    for (name, stat) in storage.list(directory)? {
+        let relative_path = storage::join(directory, &name);
+        if stat.is_dir() {
+            tree.insert(
+                relative_path.clone(),
+                Entry {
+                    kind: EntryKind::Directory,
+                    size: 0,
+                },
+            );
+            walk(storage, &relative_path, tree)?;
+        } else if stat.is_file() {
+            tree.insert(
+                relative_path,
+                Entry {
+                    kind: EntryKind::File,
+                    size: stat.size(),
+                },
+            );
+        }
+    }
+    Ok(())
+}
+
+fn compare(
+    expected: &BTreeMap<String, Entry>,
+    actual: &BTreeMap<String, Entry>,
+) -> Vec<Discrepancy> {
+    let mut discrepancies = Vec::new();
+    // Directories whose recorded contents are gone as a whole, so their
+    // descendants need no discrepancy of their own.
+    let mut gone_directories: Vec<&str> = Vec::new();
+    for (path, expected_entry) in expected {
+        if gone_directories
+            .iter()
+            .any(|directory| path.starts_with(&format!("{}/", directory)))
+        {
+            continue;
+        }
+        match actual.get(path) {
+            None => {
+                if expected_entry.kind == EntryKind::Directory {
+                    gone_directories.push(path);
+                }
+                discrepancies.push(Discrepancy::Missing {
+                    path: path.clone(),
+                    kind: expected_entry.kind,
+                })
+            }
+            Some(actual_entry) if actual_entry.kind != expected_entry.kind => {
+                if expected_entry.kind == EntryKind::Directory {
+                    gone_directories.push(path);
+                }
+                discrepancies.push(Discrepancy::KindMismatch {
+                    path: path.clone(),
+                    expected: expected_entry.kind,
+                    actual: actual_entry.kind,
+                })
+            }
+            Some(actual_entry) if actual_entry.size != expected_entry.size => {
+                discrepancies.push(Discrepancy::SizeMismatch {
+                    path: path.clone(),
+                    expected: expected_entry.size,
+                    actual: actual_entry.size,
+                })
+            }
+            Some(_) => {}
+        }
+    }
+    for (path, actual_entry) in actual {
+        if !expected.contains_key(path) {
+            discrepancies.push(Discrepancy::Unrecorded {
+                path: path.clone(),
+                kind: actual_entry.kind,
+            });
+        }
+    }
+    discrepancies
+}
+
+fn file_checksum(storage: &dyn storage::StorageBackend, path: &str) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut reader = io::BufReader::with_capacity(CHECKSUM_BUFFER_SIZE, storage.open_read(path)?);
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Stored size of `path`, `0` if it is gone.
+fn stored_size(storage: &dyn storage::StorageBackend, path: &str) -> io::Result<u64> {
+    Ok(storage.stat(path)?.map(|stat| stat.size()).unwrap_or(0))
+}
+
+fn create_event(path: &str, kind: EntryKind, size: u64) -> data::ChangeEvent {
+    match kind {
+        EntryKind::File => data::ChangeEvent::File(data::FileEvent::Create(data::FileCreate::new(
+            path.to_string(),
+            size,
+        ))),
+        EntryKind::Directory => data::ChangeEvent::Directory(data::DirectoryEvent::Create(
+            data::DirectoryCreate::new(path.to_string()),
+        )),
+    }
+}
+
+fn delete_event(path: &str, kind: EntryKind) -> data::ChangeEvent {
+    match kind {
+        EntryKind::File => data::ChangeEvent::File(data::FileEvent::Delete(data::FileDelete::new(
+            path.to_string(),
+        ))),
+        EntryKind::Directory => data::ChangeEvent::Directory(data::DirectoryEvent::Delete(
+            data::DirectoryDelete::new(path.to_string()),
+        )),
+    }
+}
+
+/// Inserts the changes that make the change log agree with the storage
+/// directory, so clients converge on what the server actually holds.
+async fn repair_discrepancy(
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    discrepancy: &Discrepancy,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let storage = namespace.storage();
+    let mut change_events = Vec::new();
+    match discrepancy {
+        Discrepancy::Missing { path, kind } => {
+            database.remove_checksums(namespace.id(), path).await?;
+            change_events.push(delete_event(path, *kind));
+        }
+        Discrepancy::Unrecorded { path, kind } => {
+            let size = stored_size(storage, path)?;
+            if *kind == EntryKind::File {
+                let sha256 = file_checksum(storage, path)?;
+                database
+                    .record_checksum(namespace.id(), path, &sha256)
+                    .await?;
+            }
+            change_events.push(create_event(path, *kind, size));
+        }
+        Discrepancy::KindMismatch {
+            path,
+            expected,
+            actual,
+        } => {
+            // The contents of a directory that replaced a file are reported
+            // as unrecorded and created after it.
+            let size = stored_size(storage, path)?;
+            database.remove_checksums(namespace.id(), path).await?;
+            change_events.push(delete_event(path, *expected));
+            change_events.push(create_event(path, *actual, size));
+        }
+        Discrepancy::SizeMismatch { path, .. } | Discrepancy::ChecksumMismatch { path } => {
+            let sha256 = file_checksum(storage, path)?;
+            database
+                .record_checksum(namespace.id(), path, &sha256)
+                .await?;
+            change_events.push(data::ChangeEvent::File(data::FileEvent::Modify(
+                data::FileModify::new(path.clone(), stored_size(storage, path)?),
+            )));
+        }
+    }
+    for change_event in change_events {
+        database
+            .insert_change(namespace.id(), change_event, 0)
+            .await?;
+    }
+    Ok(())
+}
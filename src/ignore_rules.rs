@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::path;
+use std::sync::{Arc, Mutex};
+
+use ignore::gitignore;
+
+use crate::{config, db, errors, storage};
+
+pub const IGNORE_FILE_NAME: &str = ".hcsignore";
+
+/// `.hcsignore` files (gitignore syntax) found at the root of a storage
+/// directory and in any directory below it. Rules in deeper directories take
+/// precedence, as with git. Ignore files are read through the namespace's
+/// storage backend, so remote and encrypted storage is honoured too.
+#[derive(Debug)]
+pub struct IgnoreRules {
+    storage: Arc<dyn storage::StorageBackend>,
+    storage_directory: path::PathBuf,
+    action: config::IgnoreAction,
+    enabled: bool,
+    /// Parsed ignore file of each directory visited so far. `None` if the
+    /// directory has no ignore file.
+    matchers: Mutex<HashMap<path::PathBuf, Option<gitignore::Gitignore>>>,
+}
+
+impl IgnoreRules {
+    pub fn new(
+        storage: Arc<dyn storage::StorageBackend>,
+        storage_directory: &path::Path,
+        action: config::IgnoreAction,
+    ) -> Self {
+        Self {
+            storage,
+            storage_directory: storage_directory.to_path_buf(),
+            action,
+            enabled: true,
+            matchers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Rules for `namespace`. Names in an end-to-end encrypted namespace are
+    /// opaque, so no ignore file applies to them.
+    pub fn for_namespace(
+        namespace: &db::namespaces::Namespace,
+        action: config::IgnoreAction,
+    ) -> Self {
+        let mut ignore_rules = Self::new(
+            namespace.shared_storage(),
+            namespace.storage_directory(),
+            action,
+        );
+        ignore_rules.enabled = !namespace.is_end_to_end();
+        ignore_rules
+    }
+
+    /// Parses the ignore file in `relative_directory`, if it has one.
+    fn load_matcher(&self, relative_directory: &path::Path) -> Option<gitignore::Gitignore> {
+        let ignore_path = storage::join(&relative_directory.to_string_lossy(), IGNORE_FILE_NAME);
+        let contents = match self.storage.stat(&ignore_path) {
+            Ok(Some(stat)) if stat.is_file() => {
+                self.storage
+                    .read_range(&ignore_path, 0, stat.size() as usize)
+            }
+            Ok(_) => return None,
+            Err(err) => Err(err),
+        };
+        let contents = match contents {
+            Ok(contents) => contents,
+            Err(err) => {
+                log::error!("Failed to read `{}`: {}", ignore_path, err);
+                return None;
+            }
+        };
+
+        let directory = self.storage_directory.join(relative_directory);
+        let mut builder = gitignore::GitignoreBuilder::new(&directory);
+        for line in String::from_utf8_lossy(&contents).lines() {
+            if let Err(err) = builder.add_line(Some(directory.join(IGNORE_FILE_NAME)), line) {
+                log::error!("Invalid rule in `{}`: {}", ignore_path, err);
+            }
+        }
+        match builder.build() {
+            Ok(matcher) => Some(matcher),
+            Err(err) => {
+                log::error!("Failed to load `{}`: {}", ignore_path, err);
+                None
+            }
+        }
+    }
+
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let relative_path = path::Path::new(path.trim_matches('/'));
+        let full_path = self.storage_directory.join(relative_path);
+
+        let mut directories: Vec<&path::Path> = relative_path.ancestors().skip(1).collect();
+        directories.reverse();
+
+        let mut matchers = self.matchers.lock().unwrap();
+        let mut ignored = false;
+        for directory in directories {
+            let matcher = matchers
+                .entry(self.storage_directory.join(directory))
+                .or_insert_with(|| self.load_matcher(directory));
+            if let Some(matcher) = matcher {
+                let matched = matcher.matched_path_or_any_parents(&full_path, is_dir);
+                if matched.is_ignore() {
+                    ignored = true;
+                } else if matched.is_whitelist() {
+                    ignored = false;
+                }
+            }
+        }
+        ignored
+    }
+
+    /// Decides what happens to an incoming event for `path`. `Ok(false)`
+    /// means the event should be dropped without telling the client.
+    pub fn check(&self, path: &str, is_dir: bool) -> Result<bool, errors::ServerTcpError> {
+        if !self.is_ignored(path, is_dir) {
+            return Ok(true);
+        }
+        match self.action {
+            config::IgnoreAction::Drop => Ok(false),
+            config::IgnoreAction::Reject => Err(errors::ServerTcpError::Ignored(path.to_string())),
+        }
+    }
+
+    /// Forgets the cached rules of the directory containing `path`, after its
+    /// ignore file was written.
+    pub fn invalidate(&self, path: &str) {
+        if let Some(parent) = path::Path::new(path.trim_matches('/')).parent() {
+            self.matchers
+                .lock()
+                .unwrap()
+                .remove(&self.storage_directory.join(parent));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageBackend;
+    use crate::test_support;
+
+    #[test]
+    fn rules_are_read_through_the_storage_backend() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        storage.create_dir("build/keep").unwrap();
+        test_support::write_file(storage.as_ref(), IGNORE_FILE_NAME, "*.tmp\nbuild/\n");
+        test_support::write_file(storage.as_ref(), "build/keep/.hcsignore", "!*.tmp\n");
+
+        let ignore_rules = IgnoreRules::new(
+            storage,
+            path::Path::new("/nonexistent"),
+            config::IgnoreAction::Reject,
+        );
+        assert!(ignore_rules.is_ignored("a.tmp", false));
+        assert!(ignore_rules.is_ignored("dir/a.tmp", false));
+        assert!(!ignore_rules.is_ignored("a.txt", false));
+        assert!(ignore_rules.is_ignored("build", true));
+        assert!(matches!(
+            ignore_rules.check("a.tmp", false),
+            Err(errors::ServerTcpError::Ignored(_))
+        ));
+        assert!(matches!(ignore_rules.check("a.txt", false), Ok(true)));
+    }
+
+    #[test]
+    fn invalidate_reloads_the_rules() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let ignore_rules = IgnoreRules::new(
+            storage.clone(),
+            path::Path::new("/nonexistent"),
+            config::IgnoreAction::Drop,
+        );
+        assert!(!ignore_rules.is_ignored("a.log", false));
+
+        test_support::write_file(storage.as_ref(), IGNORE_FILE_NAME, "*.log\n");
+        assert!(!ignore_rules.is_ignored("a.log", false));
+        ignore_rules.invalidate(IGNORE_FILE_NAME);
+        assert!(ignore_rules.is_ignored("a.log", false));
+        assert!(matches!(ignore_rules.check("a.log", false), Ok(false)));
+    }
+}
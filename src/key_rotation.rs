@@ -0,0 +1,57 @@
+//! Re-encrypts every stored file with the current key after
+//! `storage_config.encryption.key` changed, so the previous keys can be
+//! dropped from `previous_key_files`. Plaintext files stored before
+//! encryption was enabled are encrypted as well.
+
+use std::{error, io};
+
+use crate::storage::{self, StorageBackend};
+use crate::{config, db};
+
+/// Re-encrypts every namespace with the current key.
+pub async fn rotate_all(
+    config: &config::ServerConfig,
+    database: &dyn db::Database,
+) -> Result<(), Box<dyn error::Error>> {
+    let storage_config = config.storage_config();
+    let encryption_config = storage_config
+        .encryption()
+        .ok_or("`storage_config.encryption` is not set")?;
+    let namespaces = db::namespaces::list_namespaces(storage_config, database).await?;
+    for namespace in namespaces {
+        let rotated = rotate_namespace(&namespace, storage_config, encryption_config)?;
+        println!("{}: re-encrypted {} files", namespace.name(), rotated);
+    }
+    Ok(())
+}
+
+/// Returns the number of files rewritten.
+pub fn rotate_namespace(
+    namespace: &db::namespaces::Namespace,
+    storage_config: &config::StorageConfig,
+    encryption_config: &config::EncryptionConfig,
+) -> io::Result<usize> {
+    let backend = storage::open_backend(namespace.storage_directory(), storage_config)?;
+    let storage = storage::EncryptedStorage::new(backend, encryption_config)?;
+    let rotated = rotate_directory(&storage, "")?;
+    log::info!(
+        "Re-encrypted {} files in namespace `{}`",
+        rotated,
+        namespace.name()
+    );
+    Ok(rotated)
+}
+
+fn rotate_directory(storage: &storage::EncryptedStorage, directory: &str) -> io::Result<usize> {
+    let mut rotated = 0;
+    for (name, stat) in storage.list(directory)? {
+        let path = storage::join(directory, &name);
+        if stat.is_dir() {
+            rotated += rotate_directory(storage, &path)?;
+        } else if stat.is_file() && storage.reencrypt(&path)? {
+            log::debug!("Re-encrypted `{}`", path);
+            rotated += 1;
+        }
+    }
+    Ok(rotated)
+}
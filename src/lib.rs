@@ -1,6 +1,25 @@
+pub mod acl;
+pub mod admin;
+pub mod change_filter;
+pub mod compaction;
 pub mod config;
+pub mod connection_limits;
+pub mod db;
 pub mod errors;
 pub mod extra_data;
+pub mod fsck;
+pub mod ignore_rules;
+pub mod key_rotation;
+pub mod metadata;
+pub mod paths;
+pub mod quota;
+pub mod reload;
 pub mod serve;
+pub mod snapshot;
+pub mod storage;
+pub mod symlinks;
 pub mod sync_client_to_server;
+pub mod sync_filter;
 pub mod sync_server_to_client;
+#[cfg(test)]
+mod test_support;
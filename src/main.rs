@@ -1,18 +1,272 @@
-use hcs_lib::{logger, server_database};
-use hcs_server::{config, serve};
+use std::sync::Arc;
+
+use clap::{Args, Parser, Subcommand};
+use hcs_lib::logger;
+use hcs_server::{acl, admin, compaction, config, db, key_rotation, reload, serve};
+
+#[derive(Parser)]
+#[command(version, about = "Home cloud sync server")]
+struct Cli {
+    /// Fields can be overridden by `HCS_<SECTION>__<FIELD>` environment
+    /// variables.
+    #[arg(long, global = true, default_value = "Config.toml")]
+    config: std::path::PathBuf,
+
+    /// Defaults to `serve`.
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Accept client connections.
+    Serve,
+    /// Create the tables and the default namespace.
+    InitDb,
+    /// Apply pending schema migrations. `serve` does so on startup as well.
+    Migrate,
+    /// Manage users. Passwords are read from stdin.
+    #[command(subcommand)]
+    User(UserCommand),
+    /// List, approve or revoke registered devices.
+    #[command(subcommand)]
+    Device(DeviceCommand),
+    /// Create or list namespaces.
+    #[command(subcommand)]
+    Namespace(NamespaceCommand),
+    /// Limit the bytes a namespace may store.
+    #[command(subcommand)]
+    Quota(QuotaCommand),
+    /// Grant or revoke access to paths in a namespace. Namespaces without
+    /// entries are open to every user.
+    #[command(subcommand)]
+    Acl(AclCommand),
+    /// Inspect a namespace's change log.
+    #[command(subcommand)]
+    Changes(ChangesCommand),
+    /// Compare storage against the change log.
+    Fsck {
+        /// Only check this namespace.
+        #[arg(long)]
+        namespace: Option<String>,
+        /// Hash every file and compare it to its upload checksum.
+        #[arg(long)]
+        checksums: bool,
+        /// Record corrective changes, taking storage as the source of truth.
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Compact the change log of every namespace.
+    Gc,
+    /// Re-encrypt stored files with the current key.
+    RotateKey,
+}
+
+#[derive(Subcommand)]
+enum UserCommand {
+    Add { username: String },
+    Remove { username: String },
+    Passwd { username: String },
+}
+
+#[derive(Subcommand)]
+enum DeviceCommand {
+    List,
+    /// Allow a device registered by a user to sync.
+    Approve {
+        device_id: String,
+    },
+    Revoke {
+        device_id: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum NamespaceCommand {
+    Create {
+        name: String,
+        /// Local directory, or `s3://bucket/prefix`.
+        storage_directory: std::path::PathBuf,
+        /// Clients encrypt names and contents before uploading.
+        #[arg(long)]
+        end_to_end: bool,
+    },
+    List,
+}
+
+#[derive(Subcommand)]
+enum QuotaCommand {
+    Set { namespace: String, bytes: u64 },
+    Remove { namespace: String },
+}
+
+#[derive(Args)]
+#[group(required = true, multiple = false)]
+struct Principal {
+    #[arg(long)]
+    user: Option<String>,
+    #[arg(long)]
+    group: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum AclCommand {
+    /// Applies to the path and everything below it.
+    Grant {
+        namespace: String,
+        path: String,
+        /// `read` or `read_write`.
+        permission: acl::Permission,
+        #[command(flatten)]
+        principal: Principal,
+    },
+    Revoke {
+        namespace: String,
+        path: String,
+        #[command(flatten)]
+        principal: Principal,
+    },
+}
+
+#[derive(Subcommand)]
+enum ChangesCommand {
+    List {
+        #[arg(long, default_value = hcs_server::db::namespaces::DEFAULT_NAMESPACE)]
+        namespace: String,
+        /// Only list changes after this version.
+        #[arg(long, default_value_t = 0)]
+        since: i64,
+    },
+}
 
 #[tokio::main]
 async fn main() {
-    let config: config::ServerConfig =
-        hcs_lib::config::read_config("Config.toml").expect("Failed to read `Config.toml`");
+    let cli = Cli::parse();
+
+    let config = config::load(&cli.config).unwrap_or_else(|err| fail(err));
 
     logger::init_logger(config.log_level());
 
-    let db_pool = server_database::connect_db(&config.db_config())
+    let database = db::connect(config.db_config())
         .await
-        .expect("Failed to connect to database");
+        .unwrap_or_else(|err| fail(format!("Failed to connect to database: {}", err)));
+
+    let command = cli.command.unwrap_or(Command::Serve);
+    if !matches!(command, Command::Serve | Command::InitDb | Command::Migrate) {
+        if let Err(err) = db::migrations::check(&*database).await {
+            fail(err);
+        }
+    }
+
+    let result = match command {
+        Command::Serve => {
+            serve(reload::SharedConfig::new(cli.config, config), database).await;
+            Ok(())
+        }
+        Command::InitDb => admin::init_db(&config, &*database).await,
+        Command::Migrate => admin::migrate(&*database).await,
+        Command::User(UserCommand::Add { username }) => {
+            admin::add_user(&username, &*database).await
+        }
+        Command::User(UserCommand::Remove { username }) => {
+            admin::remove_user(&username, &*database).await
+        }
+        Command::User(UserCommand::Passwd { username }) => {
+            admin::set_password(&username, &*database).await
+        }
+        Command::Device(DeviceCommand::List) => admin::list_devices(&*database).await,
+        Command::Device(DeviceCommand::Approve { device_id }) => {
+            admin::approve_device(&device_id, &*database).await
+        }
+        Command::Device(DeviceCommand::Revoke { device_id }) => {
+            admin::revoke_device(&device_id, &*database).await
+        }
+        Command::Namespace(NamespaceCommand::Create {
+            name,
+            storage_directory,
+            end_to_end,
+        }) => {
+            admin::create_namespace(&name, &storage_directory, end_to_end, &config, &*database)
+                .await
+        }
+        Command::Namespace(NamespaceCommand::List) => {
+            admin::list_namespaces(&config, &*database).await
+        }
+        Command::Quota(QuotaCommand::Set { namespace, bytes }) => {
+            admin::set_quota(&namespace, bytes, &config, &*database).await
+        }
+        Command::Quota(QuotaCommand::Remove { namespace }) => {
+            admin::remove_quota(&namespace, &*database).await
+        }
+        Command::Acl(AclCommand::Grant {
+            namespace,
+            path,
+            permission,
+            principal,
+        }) => {
+            admin::grant(
+                &namespace,
+                &path,
+                principal.user.as_deref(),
+                principal.group.as_deref(),
+                permission,
+                &config,
+                &*database,
+            )
+            .await
+        }
+        Command::Acl(AclCommand::Revoke {
+            namespace,
+            path,
+            principal,
+        }) => {
+            admin::revoke(
+                &namespace,
+                &path,
+                principal.user.as_deref(),
+                principal.group.as_deref(),
+                &config,
+                &*database,
+            )
+            .await
+        }
+        Command::Changes(ChangesCommand::List { namespace, since }) => {
+            admin::list_changes(&namespace, since, &config, &*database).await
+        }
+        Command::Fsck {
+            namespace,
+            checksums,
+            repair,
+        } => admin::fsck(namespace.as_deref(), checksums, repair, &config, &*database).await,
+        Command::Gc => admin::gc(&config, &*database).await,
+        Command::RotateKey => key_rotation::rotate_all(&config, &*database).await,
+    };
+
+    if let Err(err) = result {
+        fail(err);
+    }
+}
+
+fn fail(err: impl std::fmt::Display) -> ! {
+    eprintln!("Error: {}", err);
+    std::process::exit(1);
+}
+
+async fn serve(config: reload::SharedConfig, database: Arc<dyn db::Database>) {
+    match database.migrate().await {
+        Ok(0) => {}
+        Ok(applied) => log::info!("Applied {} migrations", applied),
+        Err(err) => fail(err),
+    }
 
-    server_database::initialize_db(&db_pool).await.unwrap();
+    let current = config.current();
+    if let Some(interval) = current.compaction_config().interval() {
+        tokio::spawn(compaction::run_periodically(
+            database.clone(),
+            current.storage_config().clone(),
+            interval,
+        ));
+    }
 
-    serve::tcp_handler(db_pool, config).await;
+    serve::tcp_handler(database, config).await;
 }
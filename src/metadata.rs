@@ -0,0 +1,199 @@
+//! File metadata carried alongside uploads and downloads, since the
+//! `FileCreate`/`FileModify` events themselves only hold a path and a size.
+//! It is kept in the database, and its mtime and extended attributes are also
+//! applied to the file itself when the storage is on the local filesystem.
+//! The mode is only kept in the database: the server must always be able to
+//! read and write its own copy.
+
+use std::{fs, io, path, time};
+
+use crate::db;
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct FileMetadata {
+    /// Modification time in nanoseconds since the Unix epoch.
+    mtime_nanos: i128,
+    /// Unix permission bits. `None` from clients without Unix permissions.
+    mode: Option<u32>,
+    /// `user.*` extended attributes. Only exchanged with the `xattrs` feature.
+    xattrs: Vec<(String, Vec<u8>)>,
+}
+
+/// Setuid, setgid and sticky bits are not read from stored files.
+const MODE_MASK: u32 = 0o777;
+
+impl FileMetadata {
+    pub fn mtime(&self) -> time::SystemTime {
+        if self.mtime_nanos >= 0 {
+            time::UNIX_EPOCH + time::Duration::from_nanos(self.mtime_nanos as u64)
+        } else {
+            time::UNIX_EPOCH - time::Duration::from_nanos(self.mtime_nanos.unsigned_abs() as u64)
+        }
+    }
+
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+    }
+
+    pub fn xattrs(&self) -> &[(String, Vec<u8>)] {
+        &self.xattrs
+    }
+
+    /// Reads the metadata of the file at `file_path`.
+    pub fn read(file_path: &path::Path) -> io::Result<Self> {
+        let metadata = fs::metadata(file_path)?;
+        let mtime_nanos = match metadata.modified()?.duration_since(time::UNIX_EPOCH) {
+            Ok(since_epoch) => since_epoch.as_nanos() as i128,
+            Err(err) => -(err.duration().as_nanos() as i128),
+        };
+        Ok(Self {
+            mtime_nanos,
+            mode: mode_of(&metadata),
+            xattrs: read_xattrs(file_path)?,
+        })
+    }
+
+    /// Applies the metadata to the file at `file_path`, except for the mode.
+    pub fn apply(&self, file_path: &path::Path) -> io::Result<()> {
+        write_xattrs(file_path, &self.xattrs)?;
+        fs::File::options()
+            .write(true)
+            .open(file_path)?
+            .set_modified(self.mtime())
+    }
+}
+
+/// Records the metadata `path` was uploaded with.
+pub async fn store(
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    path: &str,
+    metadata: &FileMetadata,
+) -> Result<(), Box<dyn std::error::Error>> {
+    database
+        .set_file_metadata(namespace.id(), path, metadata)
+        .await?;
+    if let Some(file_path) = namespace.storage().local_path(path) {
+        metadata.apply(&file_path)?;
+    }
+    Ok(())
+}
+
+/// Metadata to send along with `path`. Files uploaded without metadata on
+/// local storage still have that of the file itself.
+pub async fn stored(
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    path: &str,
+) -> Result<Option<FileMetadata>, Box<dyn std::error::Error>> {
+    if let Some(metadata) = database.get_file_metadata(namespace.id(), path).await? {
+        return Ok(Some(metadata));
+    }
+    match namespace.storage().local_path(path) {
+        Some(file_path) => Ok(Some(FileMetadata::read(&file_path)?)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & MODE_MASK)
+}
+
+#[cfg(not(unix))]
+fn mode_of(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(all(unix, feature = "xattrs"))]
+const XATTR_PREFIX: &str = "user.";
+
+#[cfg(all(unix, feature = "xattrs"))]
+fn read_xattrs(file_path: &path::Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut xattrs = Vec::new();
+    for name in xattr::list(file_path)? {
+        let name = name.to_string_lossy().to_string();
+        if !name.starts_with(XATTR_PREFIX) {
+            continue;
+        }
+        if let Some(value) = xattr::get(file_path, &name)? {
+            xattrs.push((name, value));
+        }
+    }
+    Ok(xattrs)
+}
+
+#[cfg(all(unix, feature = "xattrs"))]
+fn write_xattrs(file_path: &path::Path, xattrs: &[(String, Vec<u8>)]) -> io::Result<()> {
+    for (name, value) in xattrs {
+        if name.starts_with(XATTR_PREFIX) {
+            xattr::set(file_path, name, value)?;
+        } else {
+            log::warn!("Not setting non-user extended attribute `{}`", name);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(all(unix, feature = "xattrs")))]
+fn read_xattrs(_file_path: &path::Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+    Ok(Vec::new())
+}
+
+#[cfg(not(all(unix, feature = "xattrs")))]
+fn write_xattrs(_file_path: &path::Path, _xattrs: &[(String, Vec<u8>)]) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::db::Database;
+    use crate::storage::StorageBackend;
+    use crate::{storage, test_support};
+
+    #[tokio::test]
+    async fn read_only_modes_are_not_applied_to_stored_files() {
+        let storage_directory =
+            std::env::temp_dir().join(format!("hcs-metadata-mode-{}", std::process::id()));
+        fs::create_dir_all(&storage_directory).unwrap();
+        let database = test_support::memory_database().await;
+        let row = database
+            .insert_namespace("test", &storage_directory.to_string_lossy(), false)
+            .await
+            .unwrap();
+        let storage = Arc::new(storage::LocalStorage::new(storage_directory.clone()));
+        let namespace = db::namespaces::Namespace::with_storage(row, storage.clone());
+        test_support::write_file(storage.as_ref(), "a.txt", "first");
+        database
+            .record_checksum(namespace.id(), "a.txt", "checksum")
+            .await
+            .unwrap();
+
+        let metadata = FileMetadata {
+            mtime_nanos: 0,
+            mode: Some(0o444),
+            xattrs: Vec::new(),
+        };
+        store(&database, &namespace, "a.txt", &metadata)
+            .await
+            .unwrap();
+
+        let file_path = storage_directory.join("a.txt");
+        let file_metadata = fs::metadata(&file_path).unwrap();
+        assert_eq!(file_metadata.modified().unwrap(), time::UNIX_EPOCH);
+        assert_ne!(mode_of(&file_metadata), Some(0o444));
+
+        // The next upload can still replace the file, and clients get the
+        // mode it was uploaded with
+        test_support::write_file(storage.as_ref(), "a.txt", "second");
+        assert_eq!(storage.read_range("a.txt", 0, 6).unwrap(), b"second");
+        let stored = stored(&database, &namespace, "a.txt").await.unwrap();
+        assert_eq!(stored.unwrap().mode(), Some(0o444));
+
+        fs::remove_dir_all(storage_directory).unwrap();
+    }
+}
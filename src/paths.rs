@@ -0,0 +1,409 @@
+//! Canonicalization of incoming paths, so clients on case-insensitive or
+//! NFD-normalizing filesystems do not create duplicates of the same name.
+
+use hcs_lib::data;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{config, db, errors, storage, symlinks};
+
+/// The canonicalization that applies to `namespace`. Names in an end-to-end
+/// encrypted namespace are opaque, so none does.
+pub fn namespace_path_config(
+    namespace: &db::namespaces::Namespace,
+    path_config: &config::PathConfig,
+) -> config::PathConfig {
+    if namespace.is_end_to_end() {
+        config::PathConfig::default()
+    } else {
+        path_config.clone()
+    }
+}
+
+pub fn canonical_path(path: &str, path_config: &config::PathConfig) -> String {
+    if path_config.normalize_unicode() {
+        path.nfc().collect()
+    } else {
+        path.to_string()
+    }
+}
+
+/// Fails unless `path` is relative and made of plain names only, so it cannot
+/// reach outside the namespace.
+pub fn check_path(path: &str) -> Result<(), errors::ServerTcpError> {
+    let is_plain = |component: &str| {
+        !component.is_empty() && component != "." && component != ".." && !component.contains('\0')
+    };
+    if path.split('/').all(is_plain) {
+        Ok(())
+    } else {
+        Err(errors::ServerTcpError::InvalidPath(path.to_string()))
+    }
+}
+
+/// The form two names share if canonicalization considers them the same.
+fn collision_key(name: &str, path_config: &config::PathConfig) -> String {
+    let name = name.nfc().collect::<String>();
+    if path_config.case_insensitive() {
+        name.to_lowercase()
+    } else {
+        name
+    }
+}
+
+pub fn canonicalize_change_event(
+    change_event: data::ChangeEvent,
+    path_config: &config::PathConfig,
+) -> data::ChangeEvent {
+    if !path_config.normalize_unicode() {
+        return change_event;
+    }
+    let canonical = |path: &str| canonical_path(path, path_config);
+
+    match change_event {
+        data::ChangeEvent::File(file_event) => {
+            data::ChangeEvent::File(match file_event {
+                data::FileEvent::Create(file_create) => data::FileEvent::Create(
+                    data::FileCreate::new(canonical(file_create.path()), file_create.size()),
+                ),
+                data::FileEvent::Delete(file_delete) => {
+                    data::FileEvent::Delete(data::FileDelete::new(canonical(file_delete.path())))
+                }
+                data::FileEvent::Modify(file_modify) => data::FileEvent::Modify(
+                    data::FileModify::new(canonical(file_modify.path()), file_modify.size()),
+                ),
+                data::FileEvent::Move(file_move) => data::FileEvent::Move(data::FileMove::new(
+                    canonical(file_move.from_path()),
+                    canonical(file_move.to_path()),
+                )),
+                other => other,
+            })
+        }
+        data::ChangeEvent::Directory(directory_event) => {
+            data::ChangeEvent::Directory(match directory_event {
+                data::DirectoryEvent::Create(directory_create) => data::DirectoryEvent::Create(
+                    data::DirectoryCreate::new(canonical(directory_create.path())),
+                ),
+                data::DirectoryEvent::Delete(directory_delete) => data::DirectoryEvent::Delete(
+                    data::DirectoryDelete::new(canonical(directory_delete.path())),
+                ),
+                data::DirectoryEvent::Move(directory_move) => {
+                    data::DirectoryEvent::Move(data::DirectoryMove::new(
+                        canonical(directory_move.from_path()),
+                        canonical(directory_move.to_path()),
+                    ))
+                }
+                other => other,
+            })
+        }
+        other => other,
+    }
+}
+
+pub fn canonicalize_symlink_event(
+    symlink_event: symlinks::SymlinkEvent,
+    path_config: &config::PathConfig,
+) -> symlinks::SymlinkEvent {
+    if !path_config.normalize_unicode() {
+        return symlink_event;
+    }
+    let canonical = |path: &str| canonical_path(path, path_config);
+
+    match symlink_event {
+        symlinks::SymlinkEvent::Create(symlink_create) => {
+            symlinks::SymlinkEvent::Create(symlinks::SymlinkCreate::new(
+                canonical(symlink_create.path()),
+                symlink_create.target().to_string(),
+            ))
+        }
+        symlinks::SymlinkEvent::Delete(symlink_delete) => symlinks::SymlinkEvent::Delete(
+            symlinks::SymlinkDelete::new(canonical(symlink_delete.path())),
+        ),
+        symlinks::SymlinkEvent::Move(symlink_move) => {
+            symlinks::SymlinkEvent::Move(symlinks::SymlinkMove::new(
+                canonical(symlink_move.from_path()),
+                canonical(symlink_move.to_path()),
+            ))
+        }
+    }
+}
+
+/// Fails if any component of `path` would land next to an existing entry
+/// that canonicalization considers the same name. `moving_from` is the source
+/// of a move, which may differ from `path` only in case.
+pub fn check_collisions(
+    storage: &dyn storage::StorageBackend,
+    path: &str,
+    moving_from: Option<&str>,
+    path_config: &config::PathConfig,
+) -> Result<(), errors::ServerTcpError> {
+    if !path_config.normalize_unicode() && !path_config.case_insensitive() {
+        return Ok(());
+    }
+
+    let mut parent = String::new();
+    for component in path.split('/').filter(|component| !component.is_empty()) {
+        let key = collision_key(component, path_config);
+        if let Ok(entries) = storage.list(&parent) {
+            for (name, _) in entries {
+                if name == component || collision_key(&name, path_config) != key {
+                    continue;
+                }
+                let existing = storage::join(&parent, &name);
+                if Some(existing.as_str()) != moving_from {
+                    return Err(errors::ServerTcpError::PathConflict {
+                        path: path.to_string(),
+                        existing,
+                    });
+                }
+            }
+        }
+        parent = storage::join(&parent, component);
+    }
+    Ok(())
+}
+
+fn change_event_paths(change_event: &data::ChangeEvent) -> Vec<&str> {
+    match change_event {
+        data::ChangeEvent::File(data::FileEvent::Create(file_create)) => vec![file_create.path()],
+        data::ChangeEvent::File(data::FileEvent::Delete(file_delete)) => vec![file_delete.path()],
+        data::ChangeEvent::File(data::FileEvent::Modify(file_modify)) => vec![file_modify.path()],
+        data::ChangeEvent::File(data::FileEvent::Move(file_move)) => {
+            vec![file_move.from_path(), file_move.to_path()]
+        }
+        data::ChangeEvent::Directory(data::DirectoryEvent::Create(directory_create)) => {
+            vec![directory_create.path()]
+        }
+        data::ChangeEvent::Directory(data::DirectoryEvent::Delete(directory_delete)) => {
+            vec![directory_delete.path()]
+        }
+        data::ChangeEvent::Directory(data::DirectoryEvent::Move(directory_move)) => {
+            vec![directory_move.from_path(), directory_move.to_path()]
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Checks every path of a change event, and every path it brings into
+/// existence for collisions.
+pub fn check_change_event(
+    storage: &dyn storage::StorageBackend,
+    change_event: &data::ChangeEvent,
+    path_config: &config::PathConfig,
+) -> Result<(), errors::ServerTcpError> {
+    for path in change_event_paths(change_event) {
+        check_path(path)?;
+    }
+
+    match change_event {
+        data::ChangeEvent::File(data::FileEvent::Create(file_create)) => {
+            check_collisions(storage, file_create.path(), None, path_config)
+        }
+        data::ChangeEvent::File(data::FileEvent::Modify(file_modify)) => {
+            check_collisions(storage, file_modify.path(), None, path_config)
+        }
+        data::ChangeEvent::File(data::FileEvent::Move(file_move)) => check_collisions(
+            storage,
+            file_move.to_path(),
+            Some(file_move.from_path()),
+            path_config,
+        ),
+        data::ChangeEvent::Directory(data::DirectoryEvent::Create(directory_create)) => {
+            check_collisions(storage, directory_create.path(), None, path_config)
+        }
+        data::ChangeEvent::Directory(data::DirectoryEvent::Move(directory_move)) => {
+            check_collisions(
+                storage,
+                directory_move.to_path(),
+                Some(directory_move.from_path()),
+                path_config,
+            )
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn check_symlink_event(
+    storage: &dyn storage::StorageBackend,
+    symlink_event: &symlinks::SymlinkEvent,
+    path_config: &config::PathConfig,
+) -> Result<(), errors::ServerTcpError> {
+    match symlink_event {
+        symlinks::SymlinkEvent::Create(symlink_create) => check_path(symlink_create.path())?,
+        symlinks::SymlinkEvent::Delete(symlink_delete) => check_path(symlink_delete.path())?,
+        symlinks::SymlinkEvent::Move(symlink_move) => {
+            check_path(symlink_move.from_path())?;
+            check_path(symlink_move.to_path())?;
+        }
+    }
+
+    match symlink_event {
+        symlinks::SymlinkEvent::Create(symlink_create) => {
+            check_collisions(storage, symlink_create.path(), None, path_config)
+        }
+        symlinks::SymlinkEvent::Move(symlink_move) => check_collisions(
+            storage,
+            symlink_move.to_path(),
+            Some(symlink_move.from_path()),
+            path_config,
+        ),
+        symlinks::SymlinkEvent::Delete(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::storage::StorageBackend;
+
+    fn path_config(normalize_unicode: bool, case_insensitive: bool) -> config::PathConfig {
+        toml::from_str(&format!(
+            "normalize_unicode = {}\ncase_insensitive = {}",
+            normalize_unicode, case_insensitive
+        ))
+        .unwrap()
+    }
+
+    fn storage_with(paths: &[&str]) -> storage::MemoryStorage {
+        let storage = storage::MemoryStorage::new();
+        for path in paths {
+            if let Some((parent, _)) = path.rsplit_once('/') {
+                storage.create_dir(parent).unwrap();
+            }
+            let mut writer = storage.open_write(path).unwrap();
+            writer.write_all(b"").unwrap();
+            writer.finish().unwrap();
+        }
+        storage
+    }
+
+    fn conflicting(result: Result<(), errors::ServerTcpError>) -> Option<String> {
+        match result {
+            Ok(()) => None,
+            Err(errors::ServerTcpError::PathConflict { existing, .. }) => Some(existing),
+            Err(err) => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn canonical_path_is_nfc_only_when_enabled() {
+        let nfd = "cafe\u{301}/menu";
+        assert_eq!(
+            canonical_path(nfd, &path_config(true, false)),
+            "caf\u{e9}/menu"
+        );
+        assert_eq!(canonical_path(nfd, &path_config(false, true)), nfd);
+    }
+
+    #[test]
+    fn moves_are_canonicalized_on_both_ends() {
+        let change_event = data::ChangeEvent::File(data::FileEvent::Move(data::FileMove::new(
+            "e\u{301}a".to_string(),
+            "e\u{301}b".to_string(),
+        )));
+        match canonicalize_change_event(change_event, &path_config(true, false)) {
+            data::ChangeEvent::File(data::FileEvent::Move(file_move)) => {
+                assert_eq!(file_move.from_path(), "\u{e9}a");
+                assert_eq!(file_move.to_path(), "\u{e9}b");
+            }
+            other => panic!("unexpected change: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn case_fold_collisions_are_reported() {
+        let storage = storage_with(&["Docs/Readme.md"]);
+        let config = path_config(false, true);
+        assert_eq!(
+            conflicting(check_collisions(&storage, "docs/new.md", None, &config)),
+            Some("Docs".to_string())
+        );
+        assert_eq!(
+            conflicting(check_collisions(&storage, "Docs/README.md", None, &config)),
+            Some("Docs/Readme.md".to_string())
+        );
+        assert_eq!(
+            conflicting(check_collisions(&storage, "Docs/Readme.md", None, &config)),
+            None
+        );
+        // Renaming a file to a different case of its own name is allowed
+        assert_eq!(
+            conflicting(check_collisions(
+                &storage,
+                "Docs/README.md",
+                Some("Docs/Readme.md"),
+                &config
+            )),
+            None
+        );
+    }
+
+    #[test]
+    fn differently_normalized_names_collide() {
+        let storage = storage_with(&["cafe\u{301}"]);
+        assert_eq!(
+            conflicting(check_collisions(
+                &storage,
+                "caf\u{e9}",
+                None,
+                &path_config(true, false)
+            )),
+            Some("cafe\u{301}".to_string())
+        );
+        assert_eq!(
+            conflicting(check_collisions(
+                &storage,
+                "CAF\u{c9}",
+                None,
+                &path_config(true, false)
+            )),
+            None
+        );
+    }
+
+    #[test]
+    fn nothing_collides_without_canonicalization() {
+        let storage = storage_with(&["Docs/Readme.md", "cafe\u{301}"]);
+        let config = path_config(false, false);
+        assert_eq!(
+            conflicting(check_collisions(&storage, "docs/readme.md", None, &config)),
+            None
+        );
+        assert_eq!(
+            conflicting(check_collisions(&storage, "caf\u{e9}", None, &config)),
+            None
+        );
+    }
+
+    #[test]
+    fn check_path_accepts_plain_names() {
+        assert!(check_path("file").is_ok());
+        assert!(check_path("dir/sub/file.txt").is_ok());
+        assert!(check_path("dir/..hidden").is_ok());
+    }
+
+    #[test]
+    fn check_path_rejects_escaping_paths() {
+        for path in [
+            "",
+            "/etc/passwd",
+            "..",
+            "../outside",
+            "dir/../../outside",
+            "dir/./file",
+            "dir//file",
+            "dir/",
+            "nul\0byte",
+        ] {
+            assert!(
+                matches!(
+                    check_path(path),
+                    Err(errors::ServerTcpError::InvalidPath(_))
+                ),
+                "{:?} should be rejected",
+                path
+            );
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use std::io;
+
+use crate::{db, errors, storage};
+
+/// Total size in bytes of all files below `directory` (`""` for the root).
+/// Symlinks are not followed.
+pub fn storage_usage(storage: &dyn storage::StorageBackend, directory: &str) -> io::Result<u64> {
+    let mut usage = 0;
+    for (name, stat) in storage.list(directory)? {
+        if stat.is_dir() {
+            usage += storage_usage(storage, &storage::join(directory, &name))?;
+        } else {
+            usage += stat.size();
+        }
+    }
+    Ok(usage)
+}
+
+/// Bytes stored in the namespace. Storage is only walked the first time,
+/// after that the usage is kept up to date along with the change log.
+pub async fn usage(
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    if let Some(used) = database.get_usage(namespace.id()).await? {
+        return Ok(used);
+    }
+    let measured = storage_usage(namespace.storage(), "")?;
+    database.set_usage(namespace.id(), measured, true).await?;
+    let used = database.get_usage(namespace.id()).await?;
+    Ok(used.unwrap_or(measured))
+}
+
+/// Reserves room for writing `size` bytes to `target_path` within the
+/// namespace's quota. Any existing file at `target_path` is replaced, so its
+/// size is not counted. Returns the change in usage, to be given back with
+/// `release` if the upload does not complete.
+pub async fn reserve(
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    target_path: &str,
+    size: u64,
+) -> Result<i64, Box<dyn std::error::Error>> {
+    let quota = database.get_quota(namespace.name()).await?;
+    let replaced = match namespace.storage().stat(target_path)? {
+        Some(stat) if stat.is_file() => stat.size(),
+        _ => 0,
+    };
+    let delta = size as i64 - replaced as i64;
+
+    usage(database, namespace).await?;
+    if database
+        .reserve_usage(namespace.id(), delta, quota)
+        .await?
+        .is_some()
+    {
+        return Ok(delta);
+    }
+    let used = usage(database, namespace).await?;
+    Err(errors::ServerTcpError::QuotaExceeded {
+        quota: quota.unwrap_or_default(),
+        used: used.saturating_sub(replaced),
+        requested: size,
+    }
+    .into())
+}
+
+/// Gives back a reservation made by `reserve`.
+pub async fn release(
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    reserved: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    database
+        .reserve_usage(namespace.id(), -reserved, None)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::test_support;
+
+    #[tokio::test]
+    async fn usage_is_measured_once_then_tracked() {
+        let database = test_support::memory_database().await;
+        let (namespace, storage) = test_support::memory_namespace(&database, "test").await;
+        test_support::write_file(storage.as_ref(), "a.txt", "12345");
+
+        assert_eq!(usage(&database, &namespace).await.unwrap(), 5);
+        test_support::write_file(storage.as_ref(), "b.txt", "123");
+        assert_eq!(usage(&database, &namespace).await.unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn reservations_count_against_the_quota() {
+        let database = test_support::memory_database().await;
+        let (namespace, storage) = test_support::memory_namespace(&database, "test").await;
+        test_support::write_file(storage.as_ref(), "a.txt", "1234");
+        database.set_quota("test", 10).await.unwrap();
+
+        // Replacing a file only needs room for the difference
+        assert_eq!(reserve(&database, &namespace, "a.txt", 9).await.unwrap(), 5);
+        // Each of these fits on its own, but not after the first
+        assert_eq!(reserve(&database, &namespace, "b.txt", 1).await.unwrap(), 1);
+        let err = reserve(&database, &namespace, "c.txt", 1)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::ServerTcpError>(),
+            Some(errors::ServerTcpError::QuotaExceeded {
+                quota: 10,
+                used: 10,
+                requested: 1,
+            })
+        ));
+
+        release(&database, &namespace, 1).await.unwrap();
+        assert_eq!(usage(&database, &namespace).await.unwrap(), 9);
+        assert_eq!(reserve(&database, &namespace, "c.txt", 1).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn replaced_files_are_not_counted_as_used() {
+        let database = test_support::memory_database().await;
+        let (namespace, storage) = test_support::memory_namespace(&database, "test").await;
+        test_support::write_file(storage.as_ref(), "a.txt", "12345678");
+        database.set_quota("test", 10).await.unwrap();
+
+        let err = reserve(&database, &namespace, "a.txt", 11)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::ServerTcpError>(),
+            Some(errors::ServerTcpError::QuotaExceeded {
+                used: 0,
+                requested: 11,
+                ..
+            })
+        ));
+    }
+}
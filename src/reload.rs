@@ -0,0 +1,69 @@
+//! Reloading the config file while the server is running, on SIGHUP. Fields
+//! that can safely change live are applied to connections accepted from then
+//! on; the others are logged and keep their value until a restart.
+
+use std::path;
+use std::sync::{Arc, RwLock};
+
+use crate::{config, connection_limits};
+
+/// The config currently in effect. Connections take a snapshot when they are
+/// accepted, so a reload never changes settings in the middle of a sync.
+#[derive(Debug, Clone)]
+pub struct SharedConfig {
+    path: path::PathBuf,
+    current: Arc<RwLock<config::ServerConfig>>,
+}
+
+impl SharedConfig {
+    pub fn new(path: path::PathBuf, config: config::ServerConfig) -> Self {
+        Self {
+            path,
+            current: Arc::new(RwLock::new(config)),
+        }
+    }
+
+    pub fn current(&self) -> config::ServerConfig {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Reads the config file again, with environment overrides, and applies
+    /// what can change live. On error the current config stays in effect.
+    pub fn reload(&self) -> Result<config::ServerConfig, config::ConfigError> {
+        let new = config::load(&self.path)?;
+        let mut current = self.current.write().unwrap();
+        for field in current.apply_reload(new) {
+            log::warn!(
+                "`{}` changed, the new value takes effect after a restart",
+                field
+            );
+        }
+        log::set_max_level(current.log_level());
+        Ok(current.clone())
+    }
+}
+
+/// Reloads `config` and updates `connection_limiter` whenever the process
+/// receives SIGHUP.
+#[cfg(unix)]
+pub async fn reload_on_sighup(
+    config: SharedConfig,
+    connection_limiter: connection_limits::ConnectionLimiter,
+) {
+    use tokio::signal::unix;
+
+    let mut hangups = match unix::signal(unix::SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            log::error!("Failed to listen for SIGHUP, config reload disabled: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        log::info!("Reloading `{}`", config.path.display());
+        match config.reload() {
+            Ok(reloaded) => connection_limiter.set_limits(reloaded.tcp_config()),
+            Err(e) => log::error!("Keeping the current config: {}", e),
+        }
+    }
+}
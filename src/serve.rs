@@ -1,31 +1,112 @@
 use std::collections::LinkedList;
+use std::io;
 use std::net as s_net;
+use std::sync::Arc;
 
 use hcs_lib::protocol::server::HCSProtocol;
-use hcs_lib::{data, protocol, server_database};
+use hcs_lib::{data, protocol};
 
-use crate::{config, errors, extra_data, sync_client_to_server, sync_server_to_client};
+use crate::{
+    acl, change_filter, config, connection_limits, db, errors, extra_data, ignore_rules, metadata,
+    paths, quota, reload, snapshot, symlinks, sync_client_to_server, sync_filter,
+    sync_server_to_client,
+};
 
 static SLEEP_TIME: u64 = 5;
 
-pub async fn tcp_handler(db_pool: sqlx::PgPool, config: config::ServerConfig) {
+/// A change as exchanged during a sync: an `hcs_lib` change event, or a
+/// symlink change carried as extra data.
+enum SyncChange {
+    Event(data::ChangeEvent),
+    Symlink(symlinks::SymlinkEvent),
+}
+
+pub async fn tcp_handler(database: Arc<dyn db::Database>, shared_config: reload::SharedConfig) {
+    let config = shared_config.current();
     let listener = s_net::TcpListener::bind(config.tcp_config().addr())
         .expect("Failed to bind to TCP address");
+    let connection_limiter = connection_limits::ConnectionLimiter::new(config.tcp_config());
+    #[cfg(unix)]
+    tokio::spawn(reload::reload_on_sighup(
+        shared_config.clone(),
+        connection_limiter.clone(),
+    ));
+    let default_namespace = db::namespaces::ensure_default_namespace(
+        config.file_handler_config(),
+        config.storage_config(),
+        &*database,
+    )
+    .await
+    .expect("Failed to set up default namespace");
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
-                let db_pool = db_pool.clone();
-                let file_handler_config = config.file_handler_config().clone();
-                tokio::spawn(async move {
-                    let mut tcp_hcs_handler =
-                        TcpHCSHandler::new(stream, db_pool.clone(), file_handler_config.clone());
+                let peer_addr = match stream.peer_addr() {
+                    Ok(peer_addr) => peer_addr,
+                    Err(e) => {
+                        log::error!("Failed to get peer address of client: {}", e);
+                        continue;
+                    }
+                };
+                let permit = match connection_limiter.try_acquire(peer_addr.ip()) {
+                    Ok(permit) => permit,
+                    Err(e) => {
+                        log::warn!("Rejecting connection from {}: {}", peer_addr, e);
+                        continue;
+                    }
+                };
 
-                    let transmission_result = tcp_hcs_handler.start_transmission().await;
+                let config = shared_config.current();
+                let tcp_config = config.tcp_config();
+                if let Err(e) = stream
+                    .set_read_timeout(Some(tcp_config.read_timeout()))
+                    .and_then(|_| stream.set_write_timeout(Some(tcp_config.write_timeout())))
+                {
+                    log::error!("Failed to set timeouts for {}: {}", peer_addr, e);
+                    continue;
+                }
 
-                    match transmission_result {
-                        Ok(_) => log::info!("Transmission successful"),
-                        Err(e) => log::error!("Transmission failed: {}", e),
-                    }
+                let database = database.clone();
+                let namespace = default_namespace.clone();
+                // Sessions do blocking socket reads, so each runs on the blocking
+                // pool and idle clients cannot starve the async workers.
+                let runtime = tokio::runtime::Handle::current();
+                tokio::task::spawn_blocking(move || {
+                    runtime.block_on(async move {
+                        let _permit = permit;
+                        let mut tcp_hcs_handler = match TcpHCSHandler::new(
+                            stream,
+                            peer_addr,
+                            database.clone(),
+                            namespace,
+                            config,
+                        ) {
+                            Ok(tcp_hcs_handler) => tcp_hcs_handler,
+                            Err(e) => {
+                                log::error!(
+                                    "Failed to set up connection from {}: {}",
+                                    peer_addr,
+                                    e
+                                );
+                                return;
+                            }
+                        };
+
+                        let transmission_result = tcp_hcs_handler.start_transmission().await;
+
+                        match transmission_result {
+                            Ok(_) => log::info!("Transmission successful"),
+                            Err(e) => match e.downcast_ref::<io::Error>() {
+                                Some(io_err) if connection_limits::is_timeout(io_err) => {
+                                    log::warn!(
+                                        "Closing connection from {}: read or write timed out",
+                                        peer_addr
+                                    )
+                                }
+                                _ => log::error!("Transmission failed: {}", e),
+                            },
+                        }
+                    })
                 });
             }
             Err(e) => log::error!("Error accepting client: {}", e),
@@ -53,30 +134,75 @@ pub fn transmission_type_to_bytes(
 
 struct TcpHCSHandler {
     tcp_connection: Box<protocol::TcpConnection>,
-    db_pool: sqlx::PgPool,
-    file_handler_config: server_database::ServerFileHandlerConfig,
+    /// Second handle on the socket, used to switch between the read and idle
+    /// timeouts while `tcp_connection` owns the stream.
+    tcp_stream: s_net::TcpStream,
+    /// Bounds the total time of each payload and request, across reads.
+    watchdog: connection_limits::Watchdog,
+    peer_addr: s_net::SocketAddr,
+    database: Arc<dyn db::Database>,
+    /// Namespace the client is syncing. Starts as the default namespace until
+    /// the client selects another one.
+    namespace: db::namespaces::Namespace,
+    /// Client version reported in the greeting.
+    client_version: String,
+    /// Set once the client authenticates.
+    user: Option<db::users::User>,
+    /// Set once the client registers its device.
+    device: Option<db::devices::Device>,
+    access: acl::AccessControl,
+    sync_filter: sync_filter::SyncFilter,
+    config: config::ServerConfig,
 }
 
 impl TcpHCSHandler {
     fn new(
         tcp_stream: s_net::TcpStream,
-        db_pool: sqlx::PgPool,
-        file_handler_config: server_database::ServerFileHandlerConfig,
-    ) -> Self {
+        peer_addr: s_net::SocketAddr,
+        database: Arc<dyn db::Database>,
+        namespace: db::namespaces::Namespace,
+        config: config::ServerConfig,
+    ) -> io::Result<Self> {
+        let stream_handle = tcp_stream.try_clone()?;
+        let watchdog = connection_limits::Watchdog::new(tcp_stream.try_clone()?);
         let tcp_connection = protocol::TcpConnection::new(tcp_stream);
-        Self {
+        Ok(Self {
             tcp_connection,
-            db_pool,
-            file_handler_config,
-        }
+            tcp_stream: stream_handle,
+            watchdog,
+            peer_addr,
+            database,
+            namespace,
+            client_version: String::new(),
+            user: None,
+            device: None,
+            access: acl::AccessControl::deny_all(),
+            sync_filter: sync_filter::SyncFilter::all(),
+            config,
+        })
     }
 
     async fn start_transmission(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        log::info!("Starting transmission");
+        log::info!("Starting transmission with {}", self.peer_addr);
         log::debug!("Waiting for greeting");
         // Receive greeting from client
-        let bytes = self.tcp_connection.read_next_chunk()?;
-        let transmission = bytes_to_transmission_type(&bytes)?;
+        // The greeting is small, so it gets a single read timeout in total
+        self.watchdog.arm(self.config.tcp_config().read_timeout());
+        let read = self.tcp_connection.read_next_chunk();
+        self.watchdog.disarm();
+        let bytes = match read {
+            Ok(bytes) => bytes,
+            Err(_) if self.watchdog.expired() => {
+                log::warn!(
+                    "Closing connection from {}: no greeting within {:?}",
+                    self.peer_addr,
+                    self.config.tcp_config().read_timeout()
+                );
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let transmission = bytes_to_transmission_type(bytes)?;
         let greeting: data::Greeting = transmission.try_into().map_err(|_| {
             "Failed to convert transmission to greeting. Expected greeting transmission."
         })?;
@@ -87,19 +213,307 @@ impl TcpHCSHandler {
         self.tcp_connection
             .write(&transmission_type_to_bytes(response)?)?;
 
+        self.reload_access().await?;
+
         log::debug!("Starting payload loop");
         loop {
-            let bytes = self.tcp_connection.read_next_chunk()?;
-            let transmission = bytes_to_transmission_type(&bytes)?;
-            let end_transmission = self.receive_payload(transmission).await;
+            let transmission = match self.read_next_payload() {
+                Ok(Some(transmission)) => transmission,
+                Ok(None) => {
+                    log::warn!(
+                        "Closing connection from {}: idle for more than {:?}",
+                        self.peer_addr,
+                        self.config.tcp_config().idle_timeout()
+                    );
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            self.watchdog
+                .arm(self.config.tcp_config().transmission_timeout());
+            let received = self.receive_payload(transmission).await;
+            self.watchdog.disarm();
+            match received {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(_) if self.watchdog.expired() => {
+                    log::warn!(
+                        "Closing connection from {}: request took more than {:?}",
+                        self.peer_addr,
+                        self.config.tcp_config().transmission_timeout()
+                    );
+                    break;
+                }
+                Err(e) => {
+                    // The client may be mid-transfer, so the stream cannot be
+                    // trusted to be at a payload boundary anymore.
+                    let error = match e.downcast_ref::<errors::ServerTcpError>() {
+                        Some(errors::ServerTcpError::Unsupported(what)) => {
+                            errors::ServerTcpError::Unsupported(what.clone())
+                        }
+                        _ => errors::ServerTcpError::Internal,
+                    };
+                    let transmission = data::Transmission::<
+                        errors::ServerTcpError,
+                        extra_data::ExtraData,
+                    >::Error(error);
+                    if let Err(write_err) = transmission_type_to_bytes(transmission)
+                        .and_then(|bytes| Ok(self.tcp_connection.write(&bytes)?))
+                    {
+                        log::warn!(
+                            "Failed to report error to {}: {}",
+                            self.peer_addr,
+                            write_err
+                        );
+                    }
+                    return Err(e);
+                }
+            }
+        }
 
-            if let Ok(_end_transmission) = end_transmission {
-                break;
-            } else {
-                dbg!(&end_transmission);
+        Ok(())
+    }
+
+    /// Waits up to the idle timeout for the next payload. Returns `None` if the
+    /// client did not send a whole payload in that time.
+    fn read_next_payload(
+        &mut self,
+    ) -> Result<
+        Option<data::Transmission<errors::ServerTcpError, extra_data::ExtraData>>,
+        Box<dyn std::error::Error>,
+    > {
+        let idle_timeout = self.config.tcp_config().idle_timeout();
+        self.tcp_stream.set_read_timeout(Some(idle_timeout))?;
+        self.watchdog.arm(idle_timeout);
+        let read = self.tcp_connection.read_next_chunk();
+        self.watchdog.disarm();
+        let bytes = match read {
+            Ok(bytes) => bytes,
+            Err(e) if connection_limits::is_timeout(&e) || self.watchdog.expired() => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let transmission = bytes_to_transmission_type(bytes)?;
+        self.tcp_stream
+            .set_read_timeout(Some(self.config.tcp_config().read_timeout()))?;
+        Ok(Some(transmission))
+    }
+
+    async fn select_namespace(&mut self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let namespace =
+            db::namespaces::get_namespace(name, self.config.storage_config(), &*self.database)
+                .await?;
+        let transmission = match namespace {
+            Some(namespace) => {
+                log::info!("{} selected namespace `{}`", self.peer_addr, name);
+                self.namespace = namespace;
+                self.reload_access().await?;
+                data::Transmission::<errors::ServerTcpError, extra_data::ExtraData>::Proceed
+            }
+            None => {
+                log::warn!("{} selected unknown namespace `{}`", self.peer_addr, name);
+                data::Transmission::Error(errors::ServerTcpError::UnknownNamespace(
+                    name.to_string(),
+                ))
             }
+        };
+        let bytes = transmission_type_to_bytes(transmission)?;
+        self.tcp_connection.write(&bytes)?;
+        Ok(())
+    }
+
+    async fn authenticate(
+        &mut self,
+        username: &str,
+        password: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let user = db::users::authenticate(username, password, &*self.database).await?;
+        let transmission = match user {
+            Some(user) => {
+                log::info!("{} authenticated as `{}`", self.peer_addr, username);
+                self.user = Some(user);
+                self.reload_access().await?;
+                data::Transmission::<errors::ServerTcpError, extra_data::ExtraData>::Proceed
+            }
+            None => {
+                log::warn!(
+                    "{} failed to authenticate as `{}`",
+                    self.peer_addr,
+                    username
+                );
+                data::Transmission::Error(errors::ServerTcpError::AuthenticationFailed)
+            }
+        };
+        let bytes = transmission_type_to_bytes(transmission)?;
+        self.tcp_connection.write(&bytes)?;
+        Ok(())
+    }
+
+    async fn set_sync_filter(
+        &mut self,
+        include: Vec<String>,
+        exclude: Vec<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let transmission = match sync_filter::SyncFilter::new(include, exclude) {
+            Ok(sync_filter) => {
+                log::info!(
+                    "{} set sync filter: include {:?}, exclude {:?}",
+                    self.peer_addr,
+                    sync_filter.include_patterns(),
+                    sync_filter.exclude_patterns()
+                );
+                if let Some(device) = &self.device {
+                    self.database
+                        .set_sync_filter(
+                            device.id(),
+                            sync_filter.include_patterns(),
+                            sync_filter.exclude_patterns(),
+                        )
+                        .await?;
+                }
+                self.sync_filter = sync_filter;
+                data::Transmission::<errors::ServerTcpError, extra_data::ExtraData>::Proceed
+            }
+            Err(err) => data::Transmission::Error(err),
+        };
+        let bytes = transmission_type_to_bytes(transmission)?;
+        self.tcp_connection.write(&bytes)?;
+        Ok(())
+    }
+
+    /// Registers the client's device and restores its saved sync filter.
+    /// Returns `false` if the device was revoked or is not approved for the
+    /// current user, and the connection should be closed.
+    async fn register_device(
+        &mut self,
+        device_id: &str,
+        hostname: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let device = self
+            .database
+            .register_device(
+                device_id,
+                hostname,
+                &self.client_version,
+                self.user.as_ref().map(|user| user.id()),
+            )
+            .await?;
+
+        let rejection = if device.revoked() {
+            log::warn!(
+                "{} tried to connect with revoked device `{}`",
+                self.peer_addr,
+                device_id
+            );
+            Some(errors::ServerTcpError::DeviceRevoked(device_id.to_string()))
+        } else if !device.approved() || device.user_id() != self.user.as_ref().map(|u| u.id()) {
+            log::warn!(
+                "{} tried to connect with unapproved device `{}`",
+                self.peer_addr,
+                device_id
+            );
+            Some(errors::ServerTcpError::DeviceNotApproved(
+                device_id.to_string(),
+            ))
+        } else {
+            None
+        };
+        if let Some(rejection) = rejection {
+            let transmission =
+                data::Transmission::<errors::ServerTcpError, extra_data::ExtraData>::Error(
+                    rejection,
+                );
+            let bytes = transmission_type_to_bytes(transmission)?;
+            self.tcp_connection.write(&bytes)?;
+            return Ok(false);
+        }
+
+        log::info!(
+            "{} registered device `{}` ({})",
+            self.peer_addr,
+            device_id,
+            hostname
+        );
+        match sync_filter::SyncFilter::new(
+            device.sync_include().to_vec(),
+            device.sync_exclude().to_vec(),
+        ) {
+            Ok(sync_filter) => self.sync_filter = sync_filter,
+            Err(err) => log::error!("Saved sync filter of `{}` is invalid: {}", device_id, err),
+        }
+        self.device = Some(device);
+
+        let transmission =
+            data::Transmission::<errors::ServerTcpError, extra_data::ExtraData>::Proceed;
+        let bytes = transmission_type_to_bytes(transmission)?;
+        self.tcp_connection.write(&bytes)?;
+        Ok(true)
+    }
+
+    /// Whether the session may sync. An authenticated client must have
+    /// registered an approved device of its user, so that revoking a device
+    /// cannot be bypassed by not registering one. Anonymous sessions are only
+    /// limited by the ACL.
+    fn check_device(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        let user = match &self.user {
+            Some(user) => user,
+            None => return Ok(true),
+        };
+        if self
+            .device
+            .as_ref()
+            .is_some_and(|device| device.user_id() == Some(user.id()))
+        {
+            return Ok(true);
+        }
+        log::warn!(
+            "{} tried to sync as `{}` without a registered device",
+            self.peer_addr,
+            user.username()
+        );
+        let transmission =
+            data::Transmission::<errors::ServerTcpError, extra_data::ExtraData>::Error(
+                errors::ServerTcpError::DeviceNotRegistered,
+            );
+        let bytes = transmission_type_to_bytes(transmission)?;
+        self.tcp_connection.write(&bytes)?;
+        Ok(false)
+    }
+
+    /// A client asking to sync from `client_version` has applied every change
+    /// up to it. Returns `false` if the version is negative, and the
+    /// connection should be closed.
+    async fn acknowledge_version(
+        &mut self,
+        client_version: i64,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        if client_version < 0 {
+            log::warn!(
+                "{} reported invalid version {}",
+                self.peer_addr,
+                client_version
+            );
+            let transmission =
+                data::Transmission::<errors::ServerTcpError, extra_data::ExtraData>::Error(
+                    errors::ServerTcpError::InvalidVersion(client_version),
+                );
+            let bytes = transmission_type_to_bytes(transmission)?;
+            self.tcp_connection.write(&bytes)?;
+            return Ok(false);
+        }
+        if let Some(device) = &self.device {
+            self.database
+                .acknowledge_version(device.id(), self.namespace.id(), client_version)
+                .await?;
         }
+        Ok(true)
+    }
 
+    /// Loads the ACL for the current user and namespace.
+    async fn reload_access(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.access =
+            acl::AccessControl::load(&*self.database, &self.namespace, self.user.as_ref()).await?;
         Ok(())
     }
 }
@@ -110,8 +524,10 @@ impl HCSProtocol<data::Transmission<errors::ServerTcpError, extra_data::ExtraDat
 {
     async fn greet(
         &mut self,
-        _payload: data::Greeting,
+        payload: data::Greeting,
     ) -> data::Transmission<errors::ServerTcpError, extra_data::ExtraData> {
+        self.client_version = payload.version().to_string();
+
         // TODO: If client version (payload.version()) and server_version are out of sync, send error to upgrade/downgrade client.
 
         let response = data::Transmission::Proceed;
@@ -122,34 +538,113 @@ impl HCSProtocol<data::Transmission<errors::ServerTcpError, extra_data::ExtraDat
         &mut self,
         payload: data::Transmission<errors::ServerTcpError, extra_data::ExtraData>,
     ) -> Result<bool, Box<dyn std::error::Error>> {
+        let needs_device = !matches!(
+            payload,
+            data::Transmission::EndConnection
+                | data::Transmission::Other(
+                    extra_data::ExtraData::SelectNamespace(_)
+                        | extra_data::ExtraData::Authenticate { .. }
+                        | extra_data::ExtraData::RegisterDevice { .. }
+                        | extra_data::ExtraData::SetSyncFilter { .. }
+                )
+        );
+        if needs_device && !self.check_device()? {
+            return Ok(true);
+        }
+
         match payload {
             data::Transmission::SyncClientToServer(sync_client_to_server) => {
                 log::debug!("Handling sync client to server");
+                if !self
+                    .acknowledge_version(sync_client_to_server.client_version())
+                    .await?
+                {
+                    return Ok(true);
+                }
                 handle_sync_client_to_server(
                     &mut self.tcp_connection,
-                    &self.db_pool,
-                    &self.file_handler_config,
+                    &*self.database,
+                    &self.namespace,
+                    &self.access,
+                    &self.config,
                     sync_client_to_server,
                 )
                 .await?;
             }
             data::Transmission::SyncServerToClient(sync_server_to_client) => {
                 log::debug!("Handling sync server to client");
+                if !self
+                    .acknowledge_version(sync_server_to_client.client_version())
+                    .await?
+                {
+                    return Ok(true);
+                }
                 handle_sync_server_to_client(
                     &mut self.tcp_connection,
-                    &self.db_pool,
-                    &self.file_handler_config,
+                    &*self.database,
+                    &self.namespace,
+                    &self.access,
+                    &self.sync_filter,
+                    self.config.ignore_config(),
                     sync_server_to_client,
                 )
                 .await?;
             }
             data::Transmission::ServerVersion(_) => {
-                handle_server_version(&mut self.tcp_connection, &self.db_pool).await?;
+                handle_server_version(&mut self.tcp_connection, &*self.database, &self.namespace)
+                    .await?;
             }
             data::Transmission::EndConnection => return Ok(true),
-            data::Transmission::Other(_) => unimplemented!("Other transmission type"),
+            data::Transmission::Other(extra_data::ExtraData::SelectNamespace(name)) => {
+                self.select_namespace(&name).await?;
+            }
+            data::Transmission::Other(extra_data::ExtraData::Authenticate {
+                username,
+                password,
+            }) => {
+                self.authenticate(&username, &password).await?;
+            }
+            data::Transmission::Other(extra_data::ExtraData::RegisterDevice {
+                device_id,
+                hostname,
+            }) => {
+                if !self.register_device(&device_id, &hostname).await? {
+                    return Ok(true);
+                }
+            }
+            data::Transmission::Other(extra_data::ExtraData::SetSyncFilter {
+                include,
+                exclude,
+            }) => {
+                self.set_sync_filter(include, exclude).await?;
+            }
+            data::Transmission::Other(extra_data::ExtraData::BootstrapRequest) => {
+                log::debug!("Handling bootstrap");
+                send_changes_since(
+                    &mut self.tcp_connection,
+                    &*self.database,
+                    &self.namespace,
+                    &self.access,
+                    &self.sync_filter,
+                    self.config.ignore_config(),
+                    0,
+                )
+                .await?;
+            }
+            data::Transmission::Other(extra_data) => {
+                handle_extra_data(
+                    &mut self.tcp_connection,
+                    &*self.database,
+                    &self.namespace,
+                    extra_data,
+                )
+                .await?;
+            }
             _ => {
-                panic!("Unexpected transmission type");
+                return Err(errors::ServerTcpError::Unsupported(
+                    "unexpected transmission".to_string(),
+                )
+                .into());
             }
         }
 
@@ -167,33 +662,41 @@ impl HCSProtocol<data::Transmission<errors::ServerTcpError, extra_data::ExtraDat
 
 async fn handle_server_to_client_change_event(
     tcp_connection: &mut Box<protocol::TcpConnection>,
-    file_handler_config: &server_database::ServerFileHandlerConfig,
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
     change_event: data::ChangeEvent,
 ) -> Result<(), Box<dyn std::error::Error>> {
     match change_event {
         data::ChangeEvent::File(file_event) => match file_event {
             data::FileEvent::Create(file_create) => {
+                let metadata = metadata::stored(database, namespace, file_create.path()).await?;
                 sync_server_to_client::handle_file_create(
                     tcp_connection,
-                    file_handler_config,
+                    namespace,
                     file_create,
+                    metadata,
                 )?;
             }
             data::FileEvent::Delete(file_delete) => {
                 sync_server_to_client::handle_file_delete(tcp_connection, file_delete)?;
             }
             data::FileEvent::Modify(file_modify) => {
+                let metadata = metadata::stored(database, namespace, file_modify.path()).await?;
                 sync_server_to_client::handle_file_modify(
                     tcp_connection,
-                    file_handler_config,
+                    namespace,
                     file_modify,
+                    metadata,
                 )?;
             }
             data::FileEvent::Move(file_move) => {
                 sync_server_to_client::handle_file_move(tcp_connection, file_move)?;
             }
             data::FileEvent::UndoDelete(_file_undo_delete) => {
-                unimplemented!("Undo delete file")
+                return Err(errors::ServerTcpError::Unsupported(
+                    "undoing a file delete".to_string(),
+                )
+                .into());
             }
         },
         data::ChangeEvent::Directory(directory_event) => match directory_event {
@@ -207,29 +710,112 @@ async fn handle_server_to_client_change_event(
                 sync_server_to_client::handle_directory_move(tcp_connection, directory_move)?;
             }
             data::DirectoryEvent::UndoDelete(_directory_undo_delete) => {
-                unimplemented!("Undo delete directory")
+                return Err(errors::ServerTcpError::Unsupported(
+                    "undoing a directory delete".to_string(),
+                )
+                .into());
             }
         },
-        _ => unimplemented!(),
+        _ => {
+            return Err(errors::ServerTcpError::Unsupported("change event".to_string()).into());
+        }
     }
     Ok(())
 }
 
 async fn handle_sync_server_to_client(
     tcp_connection: &mut Box<protocol::TcpConnection>,
-    db_pool: &sqlx::PgPool,
-    file_handler_config: &server_database::ServerFileHandlerConfig,
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    access: &acl::AccessControl,
+    sync_filter: &sync_filter::SyncFilter,
+    ignore_config: &config::IgnoreConfig,
     sync_server_to_client: data::SyncServerToClient,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let server_version = server_database::get_server_version(db_pool).await?;
-    let client_version = sync_server_to_client.client_version();
+    send_changes_since(
+        tcp_connection,
+        database,
+        namespace,
+        access,
+        sync_filter,
+        ignore_config,
+        sync_server_to_client.client_version(),
+    )
+    .await
+}
+
+/// Brings a client at `client_version` up to the current server version. New
+/// clients (version 0) and clients behind the compaction horizon are sent a
+/// snapshot of the tree instead of the change log.
+async fn send_changes_since(
+    tcp_connection: &mut Box<protocol::TcpConnection>,
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    access: &acl::AccessControl,
+    sync_filter: &sync_filter::SyncFilter,
+    ignore_config: &config::IgnoreConfig,
+    client_version: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let namespace = &namespace.cached();
+    let server_version = database.get_server_version(namespace.id()).await?;
+    let compacted_version = database.get_compacted_version(namespace.id()).await?;
 
-    let changes = server_database::get_changes(client_version, server_version, db_pool).await?;
-    let changes = changes
+    let (optimized_changes, symlink_changes) =
+        if snapshot::is_required(client_version, compacted_version) {
+            log::info!(
+                "Client version {} (compaction horizon {}). Sending snapshot at version {}.",
+                client_version,
+                compacted_version,
+                server_version
+            );
+            send_snapshot_header(tcp_connection, server_version)?;
+            // Entries keep the client's version so it only moves to the server
+            // version once the whole snapshot has been sent.
+            let symlink_changes = symlinks::tree_symlinks(namespace.storage(), "")?
+                .into_iter()
+                .map(|symlink_event| (client_version, symlink_event))
+                .collect::<Vec<_>>();
+            (
+                snapshot::snapshot_changes(namespace.storage(), client_version)?,
+                symlink_changes,
+            )
+        } else {
+            let changes = database
+                .get_changes(namespace.id(), client_version, server_version)
+                .await?;
+            let changes = changes.into_iter().collect::<LinkedList<_>>();
+            let symlink_changes = database
+                .get_symlink_changes(namespace.id(), client_version, server_version)
+                .await?;
+            (data::optimize_changes(changes), symlink_changes)
+        };
+    let ignore_rules = ignore_rules::IgnoreRules::for_namespace(namespace, ignore_config.action());
+    let is_visible = |path: &str| {
+        let is_dir = matches!(namespace.storage().stat(path), Ok(Some(stat)) if stat.is_dir());
+        access.can_read(path)
+            && sync_filter.matches(path, is_dir)
+            && !ignore_rules.is_ignored(path, is_dir)
+    };
+    let optimized_changes =
+        change_filter::filter_changes(optimized_changes, namespace.storage(), is_visible);
+    let mut symlink_changes = symlink_changes
         .into_iter()
-        .map(|change| change.into())
-        .collect::<LinkedList<_>>();
-    let optimized_changes = data::optimize_changes(changes);
+        .filter_map(|(version, symlink_event)| {
+            symlinks::filter_symlink_event(symlink_event, namespace.storage(), is_visible)
+                .map(|symlink_event| (version, SyncChange::Symlink(symlink_event)))
+        })
+        .peekable();
+    // Symlink changes go in before the first change event with a later
+    // version, so a snapshot creates their parent directories first.
+    let mut sync_changes = Vec::with_capacity(optimized_changes.len());
+    for (version, change_event) in optimized_changes {
+        while let Some(symlink_change) = symlink_changes.next_if(|(v, _)| *v < version) {
+            sync_changes.push(symlink_change);
+        }
+        sync_changes.push((version, SyncChange::Event(change_event)));
+    }
+    sync_changes.extend(symlink_changes);
+    let optimized_changes = sync_changes;
 
     let change_len = optimized_changes.len();
 
@@ -242,16 +828,24 @@ async fn handle_sync_server_to_client(
         tcp_connection.write(&bytes)?;
     }
 
-    for (i, change_event) in optimized_changes.into_iter().enumerate() {
+    for (i, (version, sync_change)) in optimized_changes.into_iter().enumerate() {
         log::info!("Sending change event {}/{}", i + 1, change_len);
         std::thread::sleep(std::time::Duration::from_millis(SLEEP_TIME));
-        match handle_server_to_client_change_event(
-            tcp_connection,
-            file_handler_config,
-            change_event.1,
-        )
-        .await
-        {
+        let handled = match sync_change {
+            SyncChange::Event(change_event) => {
+                handle_server_to_client_change_event(
+                    tcp_connection,
+                    database,
+                    namespace,
+                    change_event,
+                )
+                .await
+            }
+            SyncChange::Symlink(symlink_event) => {
+                sync_server_to_client::handle_symlink_event(tcp_connection, symlink_event)
+            }
+        };
+        match handled {
             Ok(_) => {}
             Err(err) => {
                 log::error!("Error handling server to client change event: {}", err);
@@ -269,7 +863,7 @@ async fn handle_sync_server_to_client(
             let sv = if i == change_len - 1 {
                 data::ServerVersion::new(server_version)
             } else {
-                data::ServerVersion::new(change_event.0)
+                data::ServerVersion::new(version)
             };
             let transmission =
                 data::Transmission::<errors::ServerTcpError, extra_data::ExtraData>::ServerVersion(
@@ -292,16 +886,226 @@ async fn handle_sync_server_to_client(
     Ok(())
 }
 
+async fn handle_client_to_server_change_event(
+    tcp_connection: &mut Box<protocol::TcpConnection>,
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    access: &acl::AccessControl,
+    config: &config::ServerConfig,
+    ignore_rules: &ignore_rules::IgnoreRules,
+    change_event: data::ChangeEvent,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Err(err) = paths::check_change_event(
+        namespace.storage(),
+        &change_event,
+        &paths::namespace_path_config(namespace, config.path_config()),
+    ) {
+        // Uploads are rejected before their chunks have been read
+        let upload_size = match &change_event {
+            data::ChangeEvent::File(data::FileEvent::Create(file_create)) => file_create.size(),
+            data::ChangeEvent::File(data::FileEvent::Modify(file_modify)) => file_modify.size(),
+            _ => 0,
+        };
+        sync_client_to_server::discard_chunks(
+            tcp_connection,
+            protocol::calculate_num_packets(upload_size),
+        )?;
+        return Err(err.into());
+    }
+
+    match change_event {
+        data::ChangeEvent::File(file_event) => match file_event {
+            data::FileEvent::Create(file_create) => {
+                sync_client_to_server::handle_file_create(
+                    tcp_connection,
+                    database,
+                    namespace,
+                    access,
+                    ignore_rules,
+                    config.upload_config(),
+                    file_create,
+                )
+                .await
+            }
+            data::FileEvent::Delete(file_delete) => {
+                sync_client_to_server::handle_file_delete(
+                    database,
+                    namespace,
+                    access,
+                    config.consistency_config(),
+                    file_delete,
+                )
+                .await
+            }
+            data::FileEvent::Modify(file_modify) => {
+                sync_client_to_server::handle_file_modify(
+                    tcp_connection,
+                    database,
+                    namespace,
+                    access,
+                    ignore_rules,
+                    config.upload_config(),
+                    file_modify,
+                )
+                .await
+            }
+            data::FileEvent::Move(file_move) => {
+                sync_client_to_server::handle_file_move(
+                    tcp_connection,
+                    database,
+                    namespace,
+                    access,
+                    config.consistency_config(),
+                    config.move_config(),
+                    file_move,
+                )
+                .await
+            }
+            data::FileEvent::UndoDelete(_file_undo_delete) => {
+                Err(errors::ServerTcpError::Unsupported("undoing a file delete".to_string()).into())
+            }
+        },
+        data::ChangeEvent::Directory(directory_event) => match directory_event {
+            data::DirectoryEvent::Create(directory_create) => {
+                sync_client_to_server::handle_directory_create(
+                    database,
+                    namespace,
+                    access,
+                    ignore_rules,
+                    directory_create,
+                )
+                .await
+            }
+            data::DirectoryEvent::Delete(directory_delete) => {
+                sync_client_to_server::handle_directory_delete(
+                    database,
+                    namespace,
+                    access,
+                    config.consistency_config(),
+                    directory_delete,
+                )
+                .await
+            }
+            data::DirectoryEvent::Move(directory_move) => {
+                sync_client_to_server::handle_directory_move(
+                    tcp_connection,
+                    database,
+                    namespace,
+                    access,
+                    config.consistency_config(),
+                    config.move_config(),
+                    directory_move,
+                )
+                .await
+            }
+            data::DirectoryEvent::UndoDelete(_directory_undo_delete) => Err(
+                errors::ServerTcpError::Unsupported("undoing a directory delete".to_string())
+                    .into(),
+            ),
+        },
+        _ => Err(errors::ServerTcpError::Unsupported("change event".to_string()).into()),
+    }
+}
+
+async fn handle_client_to_server_symlink_event(
+    tcp_connection: &mut Box<protocol::TcpConnection>,
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    access: &acl::AccessControl,
+    config: &config::ServerConfig,
+    ignore_rules: &ignore_rules::IgnoreRules,
+    symlink_event: symlinks::SymlinkEvent,
+) -> Result<(), Box<dyn std::error::Error>> {
+    paths::check_symlink_event(
+        namespace.storage(),
+        &symlink_event,
+        &paths::namespace_path_config(namespace, config.path_config()),
+    )?;
+
+    match symlink_event {
+        symlinks::SymlinkEvent::Create(symlink_create) => {
+            sync_client_to_server::handle_symlink_create(
+                database,
+                namespace,
+                access,
+                ignore_rules,
+                symlink_create,
+            )
+            .await
+        }
+        symlinks::SymlinkEvent::Delete(symlink_delete) => {
+            sync_client_to_server::handle_symlink_delete(
+                database,
+                namespace,
+                access,
+                config.consistency_config(),
+                symlink_delete,
+            )
+            .await
+        }
+        symlinks::SymlinkEvent::Move(symlink_move) => {
+            sync_client_to_server::handle_symlink_move(
+                tcp_connection,
+                database,
+                namespace,
+                access,
+                config.consistency_config(),
+                config.move_config(),
+                symlink_move,
+            )
+            .await
+        }
+    }
+}
+
+/// Reports a change the server refused to apply back to the client. Errors
+/// that are not a `ServerTcpError`, and unsupported changes, which end the
+/// session, are returned unchanged.
+fn send_rejection(
+    tcp_connection: &mut Box<protocol::TcpConnection>,
+    err: Box<dyn std::error::Error>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let rejection = err.downcast::<errors::ServerTcpError>()?;
+    if matches!(*rejection, errors::ServerTcpError::Unsupported(_)) {
+        return Err(rejection);
+    }
+    log::warn!("Rejected change from client: {}", rejection);
+    let transmission =
+        data::Transmission::<errors::ServerTcpError, extra_data::ExtraData>::Error(*rejection);
+    let bytes = transmission_type_to_bytes(transmission)?;
+    tcp_connection.write(&bytes)?;
+    Ok(())
+}
+
+fn send_snapshot_header(
+    tcp_connection: &mut Box<protocol::TcpConnection>,
+    server_version: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let transmission = data::Transmission::<errors::ServerTcpError, extra_data::ExtraData>::Other(
+        extra_data::ExtraData::Snapshot { server_version },
+    );
+    let bytes = transmission_type_to_bytes(transmission)?;
+    tcp_connection.write(&bytes)?;
+    Ok(())
+}
+
 async fn handle_sync_client_to_server(
     tcp_connection: &mut Box<protocol::TcpConnection>,
-    db_pool: &sqlx::PgPool,
-    file_handler_config: &server_database::ServerFileHandlerConfig,
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    access: &acl::AccessControl,
+    config: &config::ServerConfig,
     sync_client_to_server: data::SyncClientToServer,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let namespace = &namespace.cached();
+    let ignore_rules =
+        ignore_rules::IgnoreRules::for_namespace(namespace, config.ignore_config().action());
+    let path_config = paths::namespace_path_config(namespace, config.path_config());
+
     {
         log::debug!("Handling sync client to server. Checking if client is in sync with server.");
         // Check if client is in sync with the server. If no, sync_server_to_client first
-        let server_version = server_database::get_server_version(db_pool).await?;
+        let server_version = database.get_server_version(namespace.id()).await?;
         if server_version != sync_client_to_server.client_version() {
             log::debug!("Client is not in sync with server. Sending sync_server_to_client first.");
             let transmission =
@@ -329,85 +1133,97 @@ async fn handle_sync_client_to_server(
                 change_num + 1,
                 sync_client_to_server.number_of_changes()
             );
-            let bytes = tcp_connection.read_next_chunk()?;
-            let transmission = bytes_to_transmission_type(bytes)?;
-            match transmission {
-                data::Transmission::ChangeEvent(change_event) => match change_event {
-                    data::ChangeEvent::File(file_event) => match file_event {
-                        data::FileEvent::Create(file_create) => {
-                            sync_client_to_server::handle_file_create(
-                                tcp_connection,
-                                db_pool,
-                                file_handler_config,
-                                file_create,
-                            )
-                            .await?;
-                        }
-                        data::FileEvent::Delete(file_delete) => {
-                            sync_client_to_server::handle_file_delete(
-                                db_pool,
-                                file_handler_config,
-                                file_delete,
-                            )
-                            .await?;
-                        }
-                        data::FileEvent::Modify(file_modify) => {
-                            sync_client_to_server::handle_file_modify(
-                                tcp_connection,
-                                db_pool,
-                                file_handler_config,
-                                file_modify,
-                            )
-                            .await?;
-                        }
-                        data::FileEvent::Move(file_move) => {
-                            sync_client_to_server::handle_file_move(
-                                db_pool,
-                                file_handler_config,
-                                file_move,
-                            )
-                            .await?;
-                        }
-                        data::FileEvent::UndoDelete(_file_undo_delete) => {
-                            unimplemented!("Undo delete file")
-                        }
-                    },
-                    data::ChangeEvent::Directory(directory_event) => match directory_event {
-                        data::DirectoryEvent::Create(directory_create) => {
-                            sync_client_to_server::handle_directory_create(
-                                db_pool,
-                                file_handler_config,
-                                directory_create,
-                            )
-                            .await?;
-                        }
-                        data::DirectoryEvent::Delete(directory_delete) => {
-                            sync_client_to_server::handle_directory_delete(
-                                db_pool,
-                                file_handler_config,
-                                directory_delete,
-                            )
-                            .await?;
-                        }
-                        data::DirectoryEvent::Move(directory_move) => {
-                            sync_client_to_server::handle_directory_move(
-                                db_pool,
-                                file_handler_config,
-                                directory_move,
-                            )
-                            .await?;
-                        }
-                        data::DirectoryEvent::UndoDelete(_directory_undo_delete) => {
-                            unimplemented!("Undo delete directory")
-                        }
-                    },
-                    _ => unimplemented!(),
-                },
-                _ => unimplemented!(),
+            // A file's metadata may precede its change event
+            let mut file_metadata = None;
+            let sync_change = loop {
+                let bytes = tcp_connection.read_next_chunk()?;
+                match bytes_to_transmission_type(bytes)? {
+                    data::Transmission::ChangeEvent(change_event) => {
+                        break SyncChange::Event(paths::canonicalize_change_event(
+                            change_event,
+                            &path_config,
+                        ))
+                    }
+                    data::Transmission::Other(extra_data::ExtraData::Symlink(symlink_event)) => {
+                        break SyncChange::Symlink(paths::canonicalize_symlink_event(
+                            symlink_event,
+                            &path_config,
+                        ))
+                    }
+                    data::Transmission::Other(extra_data::ExtraData::FileMetadata {
+                        path,
+                        metadata,
+                    }) => {
+                        file_metadata = Some((paths::canonical_path(&path, &path_config), metadata))
+                    }
+                    _ => {
+                        return Err(errors::ServerTcpError::Unsupported(
+                            "unexpected transmission during a sync".to_string(),
+                        )
+                        .into())
+                    }
+                }
+            };
+            let uploaded_metadata = {
+                let (handled, uploaded_metadata) = match sync_change {
+                    SyncChange::Event(change_event) => {
+                        let written_path = match &change_event {
+                            data::ChangeEvent::File(data::FileEvent::Create(file_create)) => {
+                                Some(file_create.path().to_string())
+                            }
+                            data::ChangeEvent::File(data::FileEvent::Modify(file_modify)) => {
+                                Some(file_modify.path().to_string())
+                            }
+                            _ => None,
+                        };
+                        let result = handle_client_to_server_change_event(
+                            tcp_connection,
+                            database,
+                            namespace,
+                            access,
+                            config,
+                            &ignore_rules,
+                            change_event,
+                        )
+                        .await;
+                        let uploaded_metadata = match (&result, written_path, file_metadata) {
+                            (Ok(()), Some(written_path), Some((path, metadata)))
+                                if written_path == path
+                                    && !ignore_rules.is_ignored(&path, false) =>
+                            {
+                                Some((path, metadata))
+                            }
+                            _ => None,
+                        };
+                        (result, uploaded_metadata)
+                    }
+                    SyncChange::Symlink(symlink_event) => {
+                        let result = handle_client_to_server_symlink_event(
+                            tcp_connection,
+                            database,
+                            namespace,
+                            access,
+                            config,
+                            &ignore_rules,
+                            symlink_event,
+                        )
+                        .await;
+                        (result, None)
+                    }
+                };
+                if let Err(err) = handled {
+                    send_rejection(tcp_connection, err)?;
+                }
+                uploaded_metadata
+            };
+            if let Some((path, metadata)) = uploaded_metadata {
+                if let Err(err) = metadata::store(database, namespace, &path, &metadata).await {
+                    log::warn!("Failed to store metadata of `{}`: {}", path, err);
+                }
             }
             {
                 log::debug!("Sending new server version to client.");
-                let server_version = server_database::get_server_version(db_pool).await?;
+                let server_version = database.get_server_version(namespace.id()).await?;
                 let transmission = data::Transmission::<
                     errors::ServerTcpError,
                     extra_data::ExtraData,
@@ -425,9 +1241,10 @@ async fn handle_sync_client_to_server(
 
 async fn handle_server_version(
     tcp_connection: &mut Box<protocol::TcpConnection>,
-    db_pool: &sqlx::PgPool,
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let server_version = server_database::get_server_version(db_pool).await?;
+    let server_version = database.get_server_version(namespace.id()).await?;
 
     let transmission =
         data::Transmission::<errors::ServerTcpError, extra_data::ExtraData>::ServerVersion(
@@ -439,3 +1256,38 @@ async fn handle_server_version(
 
     Ok(())
 }
+
+async fn handle_extra_data(
+    tcp_connection: &mut Box<protocol::TcpConnection>,
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    extra_data: extra_data::ExtraData,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match extra_data {
+        extra_data::ExtraData::QuotaRequest => {
+            handle_quota_request(tcp_connection, database, namespace).await?;
+        }
+        other => {
+            log::error!("Unexpected extra data from client: {:?}", other);
+        }
+    }
+    Ok(())
+}
+
+async fn handle_quota_request(
+    tcp_connection: &mut Box<protocol::TcpConnection>,
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let quota = database.get_quota(namespace.name()).await?;
+    let used = quota::usage(database, namespace).await?;
+
+    let transmission = data::Transmission::<errors::ServerTcpError, extra_data::ExtraData>::Other(
+        extra_data::ExtraData::QuotaReport { quota, used },
+    );
+
+    let bytes = transmission_type_to_bytes(transmission)?;
+    tcp_connection.write(&*bytes)?;
+
+    Ok(())
+}
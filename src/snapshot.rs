@@ -0,0 +1,193 @@
+//! Describes the current contents of a namespace's storage as a list of
+//! create events, for clients that cannot be brought up to date from the
+//! change log. A snapshot holds no deletes: the client keeps whatever it
+//! does not list and uploads it (see `extra_data::ExtraData::Snapshot`).
+
+use std::collections::LinkedList;
+use std::io;
+
+use hcs_lib::data;
+
+use crate::storage;
+
+/// Creates for everything stored below `directory` (`""` for the root),
+/// parents before their children, with the size of each file as stored.
+/// Symlinks are skipped.
+pub fn tree_changes(
+    storage: &dyn storage::StorageBackend,
+    directory: &str,
+) -> io::Result<Vec<data::ChangeEvent>> {
+    let mut change_events = Vec::new();
+    walk(storage, directory, &mut change_events)?;
+    Ok(change_events)
+}
+
+fn walk(
+    storage: &dyn storage::StorageBackend,
+    directory: &str,
+    change_events: &mut Vec<data::ChangeEvent>,
+) -> io::Result<()> {
+    if !matches!(storage.stat(directory)?, Some(stat) if stat.is_dir()) {
+        return Ok(());
+    }
+
+    for (name, stat) in storage.list(directory)? {
+        let relative_path = storage::join(directory, &name);
+        if stat.is_dir() {
+            change_events.push(data::ChangeEvent::Directory(data::DirectoryEvent::Create(
+                data::DirectoryCreate::new(relative_path.clone()),
+            )));
+            walk(storage, &relative_path, change_events)?;
+        } else if stat.is_file() {
+            change_events.push(data::ChangeEvent::File(data::FileEvent::Create(
+                data::FileCreate::new(relative_path, stat.size()),
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Whether a client at `client_version` must be sent a snapshot. New clients
+/// have nothing to apply changes to, and the changes a client behind the
+/// compaction horizon is missing may no longer exist.
+pub fn is_required(client_version: i64, compacted_version: i64) -> bool {
+    client_version == 0 || client_version < compacted_version
+}
+
+/// The whole storage as changes tagged with `version`.
+pub fn snapshot_changes(
+    storage: &dyn storage::StorageBackend,
+    version: i64,
+) -> io::Result<LinkedList<(i64, data::ChangeEvent)>> {
+    Ok(tree_changes(storage, "")?
+        .into_iter()
+        .map(|change_event| (version, change_event))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::db::Database;
+    use crate::storage::StorageBackend;
+    use crate::{acl, config, ignore_rules, sync_client_to_server, test_support};
+
+    /// Path of a create, with its size if it is a file.
+    fn describe(change_event: &data::ChangeEvent) -> (&str, Option<u64>) {
+        match change_event {
+            data::ChangeEvent::Directory(data::DirectoryEvent::Create(directory_create)) => {
+                (directory_create.path(), None)
+            }
+            data::ChangeEvent::File(data::FileEvent::Create(file_create)) => {
+                (file_create.path(), Some(file_create.size()))
+            }
+            other => panic!("snapshot holds more than creates: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn clients_behind_the_horizon_need_a_snapshot() {
+        assert!(is_required(0, 0));
+        assert!(is_required(3, 5));
+        assert!(!is_required(5, 5));
+        assert!(!is_required(7, 5));
+    }
+
+    #[test]
+    fn snapshot_lists_the_tree_as_creates_only() {
+        let storage = storage::MemoryStorage::new();
+        storage.create_dir("a/b").unwrap();
+        storage.create_dir("gone").unwrap();
+        for (path, contents) in [("a/b/file", "contents"), ("top", "top"), ("removed", "")] {
+            let mut writer = storage.open_write(path).unwrap();
+            writer.write_all(contents.as_bytes()).unwrap();
+            writer.finish().unwrap();
+        }
+        storage.remove_file("removed").unwrap();
+        storage.remove_dir("gone").unwrap();
+
+        let changes = snapshot_changes(&storage, 4).unwrap();
+        assert!(changes.iter().all(|(version, _)| *version == 4));
+        let entries = changes
+            .iter()
+            .map(|(_, change_event)| describe(change_event))
+            .collect::<Vec<_>>();
+        // Deleted entries are not mentioned at all
+        assert_eq!(
+            entries,
+            [
+                ("a", None),
+                ("a/b", None),
+                ("a/b/file", Some(8)),
+                ("top", Some(3))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn new_clients_keep_and_upload_local_files() {
+        let database = test_support::memory_database().await;
+        let (namespace, storage) = test_support::memory_namespace(&database, "test").await;
+        test_support::write_file(storage.as_ref(), "shared.txt", "shared");
+        let server_version = database
+            .insert_change(
+                namespace.id(),
+                data::ChangeEvent::File(data::FileEvent::Create(data::FileCreate::new(
+                    "shared.txt".to_string(),
+                    6,
+                ))),
+                0,
+            )
+            .await
+            .unwrap();
+
+        // A new device that already holds `notes.txt` gets the snapshot,
+        // which only adds to what it has
+        assert!(is_required(0, 0));
+        let changes = snapshot_changes(storage.as_ref(), 0).unwrap();
+        assert_eq!(
+            changes
+                .iter()
+                .map(|(_, change_event)| test_support::describe(change_event))
+                .collect::<Vec<_>>(),
+            ["create shared.txt"]
+        );
+
+        // It then uploads its local file as a create
+        let (mut tcp_connection, _peer) = test_support::loopback_connection();
+        let access = acl::AccessControl::load(&database, &namespace, None)
+            .await
+            .unwrap();
+        let ignore_rules = ignore_rules::IgnoreRules::new(
+            storage.clone(),
+            namespace.storage_directory(),
+            config::IgnoreAction::Drop,
+        );
+        sync_client_to_server::handle_file_create(
+            &mut tcp_connection,
+            &database,
+            &namespace,
+            &access,
+            &ignore_rules,
+            &config::UploadConfig::default(),
+            data::FileCreate::new("notes.txt".to_string(), 0),
+        )
+        .await
+        .unwrap();
+
+        assert!(storage.stat("notes.txt").unwrap().unwrap().is_file());
+        let changes = database
+            .get_changes(namespace.id(), server_version, i64::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            changes
+                .iter()
+                .map(|(_, change_event)| test_support::describe(change_event))
+                .collect::<Vec<_>>(),
+            ["create notes.txt"]
+        );
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::{io, path};
+
+use super::{ObjectWriter, Stat, StorageBackend};
+
+/// Remembers the `stat` and `list` results of another backend, so checks
+/// that run for every change event of a sync do not go back to remote
+/// storage each time. Changes made through the cache forget what they
+/// touch. Changes made around it, such as by other connections, are not
+/// seen, so a cache should only live for one sync.
+#[derive(Debug)]
+pub struct CachedStorage {
+    backend: Arc<dyn StorageBackend>,
+    stats: Mutex<HashMap<String, Option<Stat>>>,
+    lists: Mutex<HashMap<String, Vec<(String, Stat)>>>,
+}
+
+fn parent_of(path: &str) -> &str {
+    path.rsplit_once('/')
+        .map(|(parent, _)| parent)
+        .unwrap_or("")
+}
+
+fn name_of(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+fn is_at_or_below(path: &str, directory: &str) -> bool {
+    directory.is_empty()
+        || path == directory
+        || (path.starts_with(directory) && path.as_bytes().get(directory.len()) == Some(&b'/'))
+}
+
+impl CachedStorage {
+    pub fn new(backend: Arc<dyn StorageBackend>) -> Self {
+        Self {
+            backend,
+            stats: Mutex::new(HashMap::new()),
+            lists: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A known stat of `path`, from its own entry or its parent's listing.
+    fn cached_stat(&self, path: &str) -> Option<Option<Stat>> {
+        if let Some(stat) = self.stats.lock().unwrap().get(path) {
+            return Some(*stat);
+        }
+        if path.is_empty() {
+            return None;
+        }
+        let lists = self.lists.lock().unwrap();
+        let entries = lists.get(parent_of(path))?;
+        Some(
+            entries
+                .iter()
+                .find(|(name, _)| name == name_of(path))
+                .map(|(_, stat)| *stat),
+        )
+    }
+
+    /// Forgets `path`, everything below it, and the listings it appears in.
+    /// Creating `path` may have created its parents too, so listings are
+    /// forgotten up to the first ancestor known to have existed.
+    fn forget(&self, path: &str) {
+        self.stats
+            .lock()
+            .unwrap()
+            .retain(|cached, _| !is_at_or_below(cached, path));
+        self.lists
+            .lock()
+            .unwrap()
+            .retain(|cached, _| !is_at_or_below(cached, path));
+
+        let mut path = path;
+        while !path.is_empty() {
+            let parent = parent_of(path);
+            let existed = matches!(self.cached_stat(parent), Some(Some(stat)) if stat.is_dir());
+            self.lists.lock().unwrap().remove(parent);
+            if existed {
+                break;
+            }
+            self.stats.lock().unwrap().remove(parent);
+            path = parent;
+        }
+    }
+}
+
+impl StorageBackend for CachedStorage {
+    fn open_write(&self, path: &str) -> io::Result<Box<dyn ObjectWriter>> {
+        self.forget(path);
+        self.backend.open_write(path)
+    }
+
+    fn read_range(&self, path: &str, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        self.backend.read_range(path, offset, len)
+    }
+
+    fn open_read(&self, path: &str) -> io::Result<Box<dyn io::Read + Send + '_>> {
+        self.backend.open_read(path)
+    }
+
+    fn create_dir(&self, path: &str) -> io::Result<()> {
+        self.forget(path);
+        self.backend.create_dir(path)
+    }
+
+    fn rename(&self, from_path: &str, to_path: &str) -> io::Result<()> {
+        self.forget(from_path);
+        self.forget(to_path);
+        self.backend.rename(from_path, to_path)
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        self.forget(path);
+        self.backend.remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        self.forget(path);
+        self.backend.remove_dir(path)
+    }
+
+    fn stat(&self, path: &str) -> io::Result<Option<Stat>> {
+        if let Some(stat) = self.cached_stat(path) {
+            return Ok(stat);
+        }
+        let stat = self.backend.stat(path)?;
+        self.stats.lock().unwrap().insert(path.to_string(), stat);
+        Ok(stat)
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<(String, Stat)>> {
+        if let Some(entries) = self.lists.lock().unwrap().get(path) {
+            return Ok(entries.clone());
+        }
+        let entries = self.backend.list(path)?;
+        self.lists
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), entries.clone());
+        Ok(entries)
+    }
+
+    fn create_symlink(&self, target: &str, path: &str) -> io::Result<()> {
+        self.forget(path);
+        self.backend.create_symlink(target, path)
+    }
+
+    fn read_link(&self, path: &str) -> io::Result<String> {
+        self.backend.read_link(path)
+    }
+
+    fn local_path(&self, path: &str) -> Option<path::PathBuf> {
+        self.backend.local_path(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage, test_support};
+
+    /// Counts the calls that reach the backend.
+    #[derive(Debug, Default)]
+    struct Counting {
+        backend: storage::MemoryStorage,
+        calls: Mutex<usize>,
+    }
+
+    impl Counting {
+        fn calls(&self) -> usize {
+            *self.calls.lock().unwrap()
+        }
+
+        fn count(&self) -> &storage::MemoryStorage {
+            *self.calls.lock().unwrap() += 1;
+            &self.backend
+        }
+    }
+
+    impl StorageBackend for Counting {
+        fn open_write(&self, path: &str) -> io::Result<Box<dyn ObjectWriter>> {
+            self.backend.open_write(path)
+        }
+
+        fn read_range(&self, path: &str, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+            self.backend.read_range(path, offset, len)
+        }
+
+        fn create_dir(&self, path: &str) -> io::Result<()> {
+            self.backend.create_dir(path)
+        }
+
+        fn rename(&self, from_path: &str, to_path: &str) -> io::Result<()> {
+            self.backend.rename(from_path, to_path)
+        }
+
+        fn remove_file(&self, path: &str) -> io::Result<()> {
+            self.backend.remove_file(path)
+        }
+
+        fn remove_dir(&self, path: &str) -> io::Result<()> {
+            self.backend.remove_dir(path)
+        }
+
+        fn stat(&self, path: &str) -> io::Result<Option<Stat>> {
+            self.count().stat(path)
+        }
+
+        fn list(&self, path: &str) -> io::Result<Vec<(String, Stat)>> {
+            self.count().list(path)
+        }
+
+        fn create_symlink(&self, target: &str, path: &str) -> io::Result<()> {
+            self.backend.create_symlink(target, path)
+        }
+
+        fn read_link(&self, path: &str) -> io::Result<String> {
+            self.backend.read_link(path)
+        }
+    }
+
+    fn names(entries: Vec<(String, Stat)>) -> Vec<String> {
+        entries.into_iter().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn lookups_are_answered_from_the_cache() {
+        let counting = Arc::new(Counting::default());
+        counting.create_dir("dir").unwrap();
+        test_support::write_file(counting.as_ref(), "dir/a.txt", "a");
+        let cached = CachedStorage::new(counting.clone());
+
+        assert_eq!(names(cached.list("dir").unwrap()), ["a.txt"]);
+        assert_eq!(names(cached.list("dir").unwrap()), ["a.txt"]);
+        // Entries of a listed directory need no stat of their own
+        assert!(cached.stat("dir/a.txt").unwrap().unwrap().is_file());
+        assert!(cached.stat("dir/missing").unwrap().is_none());
+        assert_eq!(counting.calls(), 1);
+
+        assert!(cached.stat("other").unwrap().is_none());
+        assert!(cached.stat("other").unwrap().is_none());
+        assert_eq!(counting.calls(), 2);
+    }
+
+    #[test]
+    fn changes_through_the_cache_are_seen() {
+        let counting = Arc::new(Counting::default());
+        counting.create_dir("dir").unwrap();
+        let cached = CachedStorage::new(counting.clone());
+        assert_eq!(names(cached.list("").unwrap()), ["dir"]);
+        assert!(cached.list("dir").unwrap().is_empty());
+        assert!(cached.stat("new/sub").unwrap().is_none());
+
+        test_support::write_file(&cached, "dir/a.txt", "a");
+        assert_eq!(names(cached.list("dir").unwrap()), ["a.txt"]);
+        // `dir` itself still exists, so the root listing is kept
+        let calls = counting.calls();
+        assert_eq!(names(cached.list("").unwrap()), ["dir"]);
+        assert_eq!(counting.calls(), calls);
+
+        cached.create_dir("new/sub").unwrap();
+        assert_eq!(names(cached.list("").unwrap()), ["dir", "new"]);
+        assert!(cached.stat("new/sub").unwrap().unwrap().is_dir());
+
+        cached.rename("dir", "new/sub/dir").unwrap();
+        assert!(cached.stat("dir/a.txt").unwrap().is_none());
+        assert_eq!(names(cached.list("new/sub/dir").unwrap()), ["a.txt"]);
+
+        cached.remove_dir("new").unwrap();
+        assert!(cached.list("").unwrap().is_empty());
+        assert!(cached.stat("new/sub/dir/a.txt").unwrap().is_none());
+    }
+}
@@ -0,0 +1,730 @@
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::{fmt, fs, path};
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::stream::{NewStream, StreamBE32, StreamPrimitive};
+use aes_gcm::aead::{KeyInit, Payload};
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::XChaCha20Poly1305;
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use super::{ObjectWriter, Stat, StorageBackend};
+use crate::config;
+
+const MAGIC: &[u8; 4] = b"HCSE";
+const FORMAT_VERSION: u8 = 1;
+
+/// Plaintext bytes per chunk. Every chunk but the last is full.
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const ENCRYPTED_CHUNK_SIZE: u64 = (CHUNK_SIZE + TAG_SIZE) as u64;
+
+/// Magic, format version, cipher and key id, followed by the nonce prefix.
+const FIXED_HEADER_LEN: usize = 10;
+const MAX_HEADER_LEN: usize = FIXED_HEADER_LEN + 19;
+
+/// Suffix of the files `reencrypt` writes next to the file it rewrites. They
+/// are left out of `list`, so a rotation in progress or one that was
+/// interrupted is not seen as stored files.
+const REENCRYPT_SUFFIX: &str = ".hcs-reencrypt";
+
+type KeyId = [u8; 4];
+
+#[derive(Clone)]
+struct Key {
+    id: KeyId,
+    bytes: [u8; 32],
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Key({})", hex::encode(self.id))
+    }
+}
+
+impl Key {
+    fn parse(hex_key: &str) -> io::Result<Self> {
+        let bytes = hex::decode(hex_key.trim())
+            .ok()
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "encryption keys must be 32 hex encoded bytes",
+                )
+            })?;
+        let mut id = KeyId::default();
+        id.copy_from_slice(&Sha256::digest(bytes)[..4]);
+        Ok(Self { id, bytes })
+    }
+
+    fn read(key_file: &path::Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(key_file).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("failed to read key file `{}`: {}", key_file.display(), err),
+            )
+        })?;
+        Self::parse(&contents)
+    }
+}
+
+/// The current key and the previous keys, in that order.
+fn load_keys(encryption_config: &config::EncryptionConfig) -> io::Result<(Key, Vec<Key>)> {
+    let key = match (encryption_config.key(), encryption_config.key_file()) {
+        (Some(key), None) => Key::parse(key)?,
+        (None, Some(key_file)) => Key::read(key_file)?,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "exactly one of `key` and `key_file` must be set",
+            ))
+        }
+    };
+    let previous_keys = encryption_config
+        .previous_key_files()
+        .iter()
+        .map(|key_file| Key::read(key_file))
+        .collect::<io::Result<_>>()?;
+    Ok((key, previous_keys))
+}
+
+fn cipher_id(cipher: config::Cipher) -> u8 {
+    match cipher {
+        config::Cipher::Aes256Gcm => 1,
+        config::Cipher::XChaCha20Poly1305 => 2,
+    }
+}
+
+/// The cipher's nonce, less the counter and last-chunk flag STREAM appends.
+fn nonce_len(cipher: config::Cipher) -> usize {
+    match cipher {
+        config::Cipher::Aes256Gcm => 7,
+        config::Cipher::XChaCha20Poly1305 => 19,
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Header {
+    cipher: config::Cipher,
+    key_id: KeyId,
+    nonce: Vec<u8>,
+}
+
+impl Header {
+    fn new(cipher: config::Cipher, key: &Key) -> Self {
+        let mut nonce = vec![0; nonce_len(cipher)];
+        OsRng.fill_bytes(&mut nonce);
+        Self {
+            cipher,
+            key_id: key.id,
+            nonce,
+        }
+    }
+
+    /// `None` unless `bytes` start with a header, as plaintext files do not.
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < FIXED_HEADER_LEN || &bytes[..4] != MAGIC || bytes[4] != FORMAT_VERSION {
+            return None;
+        }
+        let cipher = match bytes[5] {
+            1 => config::Cipher::Aes256Gcm,
+            2 => config::Cipher::XChaCha20Poly1305,
+            _ => return None,
+        };
+        let nonce = bytes.get(FIXED_HEADER_LEN..FIXED_HEADER_LEN + nonce_len(cipher))?;
+        let mut key_id = KeyId::default();
+        key_id.copy_from_slice(&bytes[6..FIXED_HEADER_LEN]);
+        Some(Self {
+            cipher,
+            key_id,
+            nonce: nonce.to_vec(),
+        })
+    }
+
+    fn len(&self) -> usize {
+        FIXED_HEADER_LEN + self.nonce.len()
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(FORMAT_VERSION);
+        bytes.push(cipher_id(self.cipher));
+        bytes.extend_from_slice(&self.key_id);
+        bytes.extend_from_slice(&self.nonce);
+        bytes
+    }
+}
+
+/// Encrypts and decrypts the chunks of one file. The header is authenticated
+/// along with every chunk.
+enum ChunkCipher {
+    // Boxed for its expanded key schedule
+    Aes256Gcm(Box<StreamBE32<Aes256Gcm>>),
+    XChaCha20Poly1305(StreamBE32<XChaCha20Poly1305>),
+}
+
+impl ChunkCipher {
+    fn new(header: &Header, key: &Key) -> Self {
+        let key = GenericArray::from_slice(&key.bytes);
+        match header.cipher {
+            config::Cipher::Aes256Gcm => Self::Aes256Gcm(Box::new(StreamBE32::from_aead(
+                Aes256Gcm::new(key),
+                GenericArray::from_slice(&header.nonce),
+            ))),
+            config::Cipher::XChaCha20Poly1305 => Self::XChaCha20Poly1305(StreamBE32::from_aead(
+                XChaCha20Poly1305::new(key),
+                GenericArray::from_slice(&header.nonce),
+            )),
+        }
+    }
+
+    fn encrypt(&self, position: u32, last: bool, aad: &[u8], chunk: &[u8]) -> io::Result<Vec<u8>> {
+        let payload = Payload { msg: chunk, aad };
+        match self {
+            Self::Aes256Gcm(stream) => stream.encrypt(position, last, payload),
+            Self::XChaCha20Poly1305(stream) => stream.encrypt(position, last, payload),
+        }
+        .map_err(|_| io::Error::other("failed to encrypt chunk"))
+    }
+
+    fn decrypt(&self, position: u32, last: bool, aad: &[u8], chunk: &[u8]) -> io::Result<Vec<u8>> {
+        let payload = Payload { msg: chunk, aad };
+        match self {
+            Self::Aes256Gcm(stream) => stream.decrypt(position, last, payload),
+            Self::XChaCha20Poly1305(stream) => stream.decrypt(position, last, payload),
+        }
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "failed to decrypt chunk, the file is corrupted",
+            )
+        })
+    }
+}
+
+fn chunk_count(ciphertext_len: u64) -> u64 {
+    ciphertext_len.div_ceil(ENCRYPTED_CHUNK_SIZE).max(1)
+}
+
+fn position(chunk_index: u64) -> io::Result<u32> {
+    u32::try_from(chunk_index).map_err(|_| io::Error::other("file has too many chunks"))
+}
+
+/// Encrypts file contents on their way into `inner` and decrypts them on
+/// their way out. A file is a header naming cipher, key and nonce, followed
+/// by STREAM chunks of `CHUNK_SIZE` plaintext bytes, so any range can be
+/// decrypted on its own. Files without a header were stored before
+/// encryption was enabled and are passed through as they are.
+#[derive(Debug)]
+pub struct EncryptedStorage {
+    inner: Arc<dyn StorageBackend>,
+    cipher: config::Cipher,
+    key: Key,
+    previous_keys: Vec<Key>,
+}
+
+impl EncryptedStorage {
+    pub fn new(
+        inner: Arc<dyn StorageBackend>,
+        encryption_config: &config::EncryptionConfig,
+    ) -> io::Result<Self> {
+        let (key, previous_keys) = load_keys(encryption_config)?;
+        Ok(Self {
+            inner,
+            cipher: encryption_config.cipher(),
+            key,
+            previous_keys,
+        })
+    }
+
+    /// Checks that every key `encryption_config` names can be loaded.
+    pub fn check_keys(encryption_config: &config::EncryptionConfig) -> io::Result<()> {
+        load_keys(encryption_config).map(|_| ())
+    }
+
+    fn key(&self, key_id: KeyId) -> io::Result<&Key> {
+        std::iter::once(&self.key)
+            .chain(&self.previous_keys)
+            .find(|key| key.id == key_id)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("file is encrypted with unknown key {}", hex::encode(key_id)),
+                )
+            })
+    }
+
+    /// `None` if the file at `path` is stored in plaintext.
+    fn header(&self, path: &str) -> io::Result<Option<Header>> {
+        Ok(Header::parse(&self.inner.read_range(
+            path,
+            0,
+            MAX_HEADER_LEN,
+        )?))
+    }
+
+    fn plaintext_stat(&self, path: &str, stat: Stat) -> io::Result<Stat> {
+        if !stat.is_file() {
+            return Ok(stat);
+        }
+        let header = match self.header(path)? {
+            Some(header) => header,
+            None => return Ok(stat),
+        };
+        let ciphertext_len = stat.size().saturating_sub(header.len() as u64);
+        let tags = chunk_count(ciphertext_len) * TAG_SIZE as u64;
+        Ok(Stat::new(stat.kind(), ciphertext_len.saturating_sub(tags)))
+    }
+
+    /// The cipher, header and chunk count of the file at `path`, `None` if it
+    /// is stored as plaintext.
+    fn open_chunks(&self, path: &str) -> io::Result<Option<(ChunkCipher, Vec<u8>, u64)>> {
+        let header = match self.header(path)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let cipher = ChunkCipher::new(&header, self.key(header.key_id)?);
+        let header_bytes = header.to_bytes();
+        let ciphertext_len = match self.inner.stat(path)? {
+            Some(stat) => stat.size().saturating_sub(header_bytes.len() as u64),
+            None => return Err(io::Error::from(io::ErrorKind::NotFound)),
+        };
+        Ok(Some((cipher, header_bytes, chunk_count(ciphertext_len))))
+    }
+
+    /// Re-encrypts the file at `path` with the current key and cipher, unless
+    /// it already uses them. Returns whether the file was rewritten.
+    pub fn reencrypt(&self, path: &str) -> io::Result<bool> {
+        if let Some(header) = self.header(path)? {
+            if header.key_id == self.key.id && header.cipher == self.cipher {
+                return Ok(false);
+            }
+        }
+
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let temporary_path = super::join(parent, &format!(".{}{}", name, REENCRYPT_SUFFIX));
+        let result = self.copy_to(path, &temporary_path);
+        if let Err(err) = result.and_then(|_| self.inner.rename(&temporary_path, path)) {
+            if let Err(remove_err) = self.inner.remove_file(&temporary_path) {
+                log::warn!("Failed to remove `{}`: {}", temporary_path, remove_err);
+            }
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    fn copy_to(&self, path: &str, to_path: &str) -> io::Result<()> {
+        let mut writer = self.open_write(to_path)?;
+        io::copy(&mut self.open_read(path)?, &mut writer)?;
+        writer.finish()
+    }
+}
+
+struct EncryptedWriter {
+    inner: Box<dyn ObjectWriter>,
+    cipher: ChunkCipher,
+    header: Vec<u8>,
+    buffer: Vec<u8>,
+    chunk_index: u64,
+}
+
+impl EncryptedWriter {
+    fn write_chunk(&mut self, chunk: &[u8], last: bool) -> io::Result<()> {
+        let position = position(self.chunk_index)?;
+        let ciphertext = self.cipher.encrypt(position, last, &self.header, chunk)?;
+        self.inner.write_all(&ciphertext)?;
+        self.chunk_index += 1;
+        Ok(())
+    }
+}
+
+impl Write for EncryptedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        // A full chunk is only known not to be the last once more follows
+        while self.buffer.len() > CHUNK_SIZE {
+            let rest = self.buffer.split_off(CHUNK_SIZE);
+            let chunk = std::mem::replace(&mut self.buffer, rest);
+            self.write_chunk(&chunk, false)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ObjectWriter for EncryptedWriter {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        let chunk = std::mem::take(&mut self.buffer);
+        self.write_chunk(&chunk, true)?;
+        self.inner.finish()
+    }
+}
+
+struct EncryptedReader<'a> {
+    inner: Box<dyn io::Read + Send + 'a>,
+    cipher: ChunkCipher,
+    header: Vec<u8>,
+    chunks: u64,
+    chunk_index: u64,
+    /// The last decrypted chunk, of which `consumed` bytes were read.
+    chunk: Vec<u8>,
+    consumed: usize,
+}
+
+impl io::Read for EncryptedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.consumed == self.chunk.len() {
+            if self.chunk_index == self.chunks {
+                return Ok(0);
+            }
+            let mut ciphertext = Vec::with_capacity(ENCRYPTED_CHUNK_SIZE as usize);
+            (&mut self.inner)
+                .take(ENCRYPTED_CHUNK_SIZE)
+                .read_to_end(&mut ciphertext)?;
+            self.chunk = self.cipher.decrypt(
+                position(self.chunk_index)?,
+                self.chunk_index + 1 == self.chunks,
+                &self.header,
+                &ciphertext,
+            )?;
+            self.consumed = 0;
+            self.chunk_index += 1;
+        }
+        let len = buf.len().min(self.chunk.len() - self.consumed);
+        buf[..len].copy_from_slice(&self.chunk[self.consumed..self.consumed + len]);
+        self.consumed += len;
+        Ok(len)
+    }
+}
+
+impl StorageBackend for EncryptedStorage {
+    fn open_write(&self, path: &str) -> io::Result<Box<dyn ObjectWriter>> {
+        let header = Header::new(self.cipher, &self.key);
+        let mut inner = self.inner.open_write(path)?;
+        let header_bytes = header.to_bytes();
+        inner.write_all(&header_bytes)?;
+        Ok(Box::new(EncryptedWriter {
+            inner,
+            cipher: ChunkCipher::new(&header, &self.key),
+            header: header_bytes,
+            buffer: Vec::new(),
+            chunk_index: 0,
+        }))
+    }
+
+    fn read_range(&self, path: &str, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let (cipher, header_bytes, chunks) = match self.open_chunks(path)? {
+            Some(chunks) => chunks,
+            None => return self.inner.read_range(path, offset, len),
+        };
+        let header_len = header_bytes.len() as u64;
+
+        let mut plaintext = Vec::with_capacity(len);
+        let mut chunk_index = offset / CHUNK_SIZE as u64;
+        let mut skip = (offset % CHUNK_SIZE as u64) as usize;
+        while plaintext.len() < len && chunk_index < chunks {
+            let ciphertext = self.inner.read_range(
+                path,
+                header_len + chunk_index * ENCRYPTED_CHUNK_SIZE,
+                ENCRYPTED_CHUNK_SIZE as usize,
+            )?;
+            let chunk = cipher.decrypt(
+                position(chunk_index)?,
+                chunk_index + 1 == chunks,
+                &header_bytes,
+                &ciphertext,
+            )?;
+            let start = skip.min(chunk.len());
+            let end = chunk.len().min(start + len - plaintext.len());
+            plaintext.extend_from_slice(&chunk[start..end]);
+            skip = 0;
+            chunk_index += 1;
+        }
+        Ok(plaintext)
+    }
+
+    /// Reads the header and size once, then decrypts the file as it streams
+    /// in from the inner backend.
+    fn open_read(&self, path: &str) -> io::Result<Box<dyn io::Read + Send + '_>> {
+        let (cipher, header, chunks) = match self.open_chunks(path)? {
+            Some(chunks) => chunks,
+            None => return self.inner.open_read(path),
+        };
+        let mut inner = self.inner.open_read(path)?;
+        io::copy(&mut (&mut inner).take(header.len() as u64), &mut io::sink())?;
+        Ok(Box::new(EncryptedReader {
+            inner,
+            cipher,
+            header,
+            chunks,
+            chunk_index: 0,
+            chunk: Vec::new(),
+            consumed: 0,
+        }))
+    }
+
+    fn create_dir(&self, path: &str) -> io::Result<()> {
+        self.inner.create_dir(path)
+    }
+
+    fn rename(&self, from_path: &str, to_path: &str) -> io::Result<()> {
+        self.inner.rename(from_path, to_path)
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        self.inner.remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        self.inner.remove_dir(path)
+    }
+
+    fn stat(&self, path: &str) -> io::Result<Option<Stat>> {
+        match self.inner.stat(path)? {
+            Some(stat) => Ok(Some(self.plaintext_stat(path, stat)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<(String, Stat)>> {
+        self.inner
+            .list(path)?
+            .into_iter()
+            .filter(|(name, stat)| !(stat.is_file() && name.ends_with(REENCRYPT_SUFFIX)))
+            .map(|(name, stat)| {
+                let stat = self.plaintext_stat(&super::join(path, &name), stat)?;
+                Ok((name, stat))
+            })
+            .collect()
+    }
+
+    fn create_symlink(&self, target: &str, path: &str) -> io::Result<()> {
+        self.inner.create_symlink(target, path)
+    }
+
+    fn read_link(&self, path: &str) -> io::Result<String> {
+        self.inner.read_link(path)
+    }
+
+    /// Files there hold ciphertext, which is fine for metadata and free space
+    /// checks.
+    fn local_path(&self, path: &str) -> Option<path::PathBuf> {
+        self.inner.local_path(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage, test_support};
+
+    const KEY: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+    const OTHER_KEY: &str = "0202020202020202020202020202020202020202020202020202020202020202";
+
+    fn encryption_config(cipher: &str, key: &str) -> config::EncryptionConfig {
+        toml::from_str(&format!("cipher = {:?}\nkey = {:?}", cipher, key)).unwrap()
+    }
+
+    fn encrypted(
+        inner: &Arc<storage::MemoryStorage>,
+        encryption_config: &config::EncryptionConfig,
+    ) -> EncryptedStorage {
+        EncryptedStorage::new(inner.clone(), encryption_config).unwrap()
+    }
+
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn write(storage: &dyn StorageBackend, path: &str, contents: &[u8]) {
+        let mut writer = storage.open_write(path).unwrap();
+        // Odd write sizes, so chunks are assembled from several writes
+        for part in contents.chunks(1000) {
+            writer.write_all(part).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn every_file_has_at_least_one_chunk() {
+        assert_eq!(chunk_count(0), 1);
+        assert_eq!(chunk_count(TAG_SIZE as u64), 1);
+        assert_eq!(chunk_count(ENCRYPTED_CHUNK_SIZE), 1);
+        assert_eq!(chunk_count(ENCRYPTED_CHUNK_SIZE + 1), 2);
+        assert_eq!(chunk_count(3 * ENCRYPTED_CHUNK_SIZE), 3);
+    }
+
+    #[test]
+    fn sizes_are_reported_without_header_and_tags() {
+        for cipher in ["aes-256-gcm", "xchacha20-poly1305"] {
+            let inner = Arc::new(storage::MemoryStorage::new());
+            let storage = encrypted(&inner, &encryption_config(cipher, KEY));
+            for len in [
+                0,
+                1,
+                CHUNK_SIZE - 1,
+                CHUNK_SIZE,
+                CHUNK_SIZE + 1,
+                3 * CHUNK_SIZE,
+            ] {
+                write(&storage, "file", &contents(len));
+
+                let chunks = len.div_ceil(CHUNK_SIZE).max(1);
+                let header_len = FIXED_HEADER_LEN + nonce_len(storage.cipher);
+                let stored = inner.stat("file").unwrap().unwrap().size();
+                assert_eq!(stored as usize, header_len + len + chunks * TAG_SIZE);
+                assert_eq!(storage.stat("file").unwrap().unwrap().size(), len as u64);
+                assert_eq!(storage.list("").unwrap()[0].1.size(), len as u64);
+                assert_eq!(
+                    storage.read_range("file", 0, len + 1).unwrap(),
+                    contents(len)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ranges_are_decrypted_across_chunk_boundaries() {
+        let inner = Arc::new(storage::MemoryStorage::new());
+        let storage = encrypted(&inner, &encryption_config("aes-256-gcm", KEY));
+        let plaintext = contents(2 * CHUNK_SIZE + 10);
+        write(&storage, "file", &plaintext);
+
+        let offset = CHUNK_SIZE - 5;
+        assert_eq!(
+            storage.read_range("file", offset as u64, 20).unwrap(),
+            &plaintext[offset..offset + 20]
+        );
+        let offset = 2 * CHUNK_SIZE;
+        assert_eq!(
+            storage.read_range("file", offset as u64, 100).unwrap(),
+            &plaintext[offset..]
+        );
+        assert!(storage
+            .read_range("file", plaintext.len() as u64, 10)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn files_are_decrypted_as_they_are_read() {
+        let inner = Arc::new(storage::MemoryStorage::new());
+        let storage = encrypted(&inner, &encryption_config("xchacha20-poly1305", KEY));
+        test_support::write_file(inner.as_ref(), "plain", "stored before encryption");
+        for len in [0, 1, CHUNK_SIZE, 2 * CHUNK_SIZE + 10] {
+            write(&storage, "file", &contents(len));
+            let mut plaintext = Vec::new();
+            storage
+                .open_read("file")
+                .unwrap()
+                .read_to_end(&mut plaintext)
+                .unwrap();
+            assert_eq!(plaintext, contents(len));
+        }
+
+        let mut plaintext = String::new();
+        storage
+            .open_read("plain")
+            .unwrap()
+            .read_to_string(&mut plaintext)
+            .unwrap();
+        assert_eq!(plaintext, "stored before encryption");
+    }
+
+    #[test]
+    fn plaintext_files_are_passed_through() {
+        let inner = Arc::new(storage::MemoryStorage::new());
+        test_support::write_file(inner.as_ref(), "old.txt", "stored before encryption");
+        let storage = encrypted(&inner, &encryption_config("xchacha20-poly1305", KEY));
+
+        assert_eq!(storage.stat("old.txt").unwrap().unwrap().size(), 24);
+        assert_eq!(
+            storage.read_range("old.txt", 7, 6).unwrap(),
+            b"before".to_vec()
+        );
+    }
+
+    #[test]
+    fn files_of_unknown_keys_are_not_decrypted() {
+        let inner = Arc::new(storage::MemoryStorage::new());
+        write(
+            &encrypted(&inner, &encryption_config("aes-256-gcm", OTHER_KEY)),
+            "file",
+            b"secret",
+        );
+        let storage = encrypted(&inner, &encryption_config("aes-256-gcm", KEY));
+
+        let err = storage.read_range("file", 0, 6).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn files_are_reencrypted_with_the_current_key() {
+        let key_file =
+            std::env::temp_dir().join(format!("hcs-previous-key-{}", std::process::id()));
+        fs::write(&key_file, OTHER_KEY).unwrap();
+        let inner = Arc::new(storage::MemoryStorage::new());
+        write(
+            &encrypted(&inner, &encryption_config("aes-256-gcm", OTHER_KEY)),
+            "file",
+            &contents(CHUNK_SIZE + 1),
+        );
+        test_support::write_file(inner.as_ref(), "plain", "plain");
+        let rotating: config::EncryptionConfig = toml::from_str(&format!(
+            "key = {:?}\nprevious_key_files = [{:?}]",
+            KEY, key_file
+        ))
+        .unwrap();
+        let storage = encrypted(&inner, &rotating);
+
+        assert!(storage.reencrypt("file").unwrap());
+        assert!(!storage.reencrypt("file").unwrap());
+        assert!(storage.reencrypt("plain").unwrap());
+        fs::remove_file(&key_file).unwrap();
+
+        let storage = encrypted(&inner, &encryption_config("xchacha20-poly1305", KEY));
+        assert_eq!(
+            storage.read_range("file", 0, CHUNK_SIZE + 1).unwrap(),
+            contents(CHUNK_SIZE + 1)
+        );
+        assert_eq!(storage.read_range("plain", 0, 5).unwrap(), b"plain");
+        let names: Vec<_> = inner
+            .list("")
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["file", "plain"]);
+        assert!(Header::parse(&inner.read_range("plain", 0, MAX_HEADER_LEN).unwrap()).is_some());
+    }
+
+    #[test]
+    fn reencrypt_files_are_not_listed() {
+        let inner = Arc::new(storage::MemoryStorage::new());
+        write(
+            &encrypted(&inner, &encryption_config("aes-256-gcm", OTHER_KEY)),
+            "file",
+            b"secret",
+        );
+        test_support::write_file(inner.as_ref(), ".other.hcs-reencrypt", "interrupted");
+        let storage = encrypted(&inner, &encryption_config("aes-256-gcm", KEY));
+
+        let names: Vec<_> = storage
+            .list("")
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["file"]);
+        // The previous key is unknown, so the rewrite fails and is cleaned up
+        assert!(storage.reencrypt("file").is_err());
+        assert!(inner.stat(".file.hcs-reencrypt").unwrap().is_none());
+    }
+}
@@ -0,0 +1,209 @@
+use std::io::{Read, Seek, Write};
+use std::{fs, io, path};
+
+use super::{EntryKind, ObjectWriter, Stat, StorageBackend};
+
+/// Files stored in a directory on the local filesystem.
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    root: path::PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: path::PathBuf) -> Self {
+        Self { root }
+    }
+
+    /// Fails rather than joining a path that could leave the root.
+    fn full_path(&self, path: &str) -> io::Result<path::PathBuf> {
+        let relative = path::Path::new(path);
+        if relative
+            .components()
+            .any(|component| !matches!(component, path::Component::Normal(_)))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("path leaves the storage root: `{}`", path),
+            ));
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+struct LocalWriter(fs::File);
+
+impl Write for LocalWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl ObjectWriter for LocalWriter {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+fn stat_of(metadata: &fs::Metadata) -> Stat {
+    let file_type = metadata.file_type();
+    if file_type.is_symlink() {
+        Stat::new(EntryKind::Symlink, 0)
+    } else if file_type.is_dir() {
+        Stat::new(EntryKind::Directory, 0)
+    } else {
+        Stat::new(EntryKind::File, metadata.len())
+    }
+}
+
+impl StorageBackend for LocalStorage {
+    fn open_write(&self, path: &str) -> io::Result<Box<dyn ObjectWriter>> {
+        Ok(Box::new(LocalWriter(fs::File::create(
+            self.full_path(path)?,
+        )?)))
+    }
+
+    fn read_range(&self, path: &str, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut file = fs::File::open(self.full_path(path)?)?;
+        file.seek(io::SeekFrom::Start(offset))?;
+        let mut buffer = Vec::with_capacity(len);
+        file.take(len as u64).read_to_end(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    fn open_read(&self, path: &str) -> io::Result<Box<dyn io::Read + Send + '_>> {
+        Ok(Box::new(fs::File::open(self.full_path(path)?)?))
+    }
+
+    fn create_dir(&self, path: &str) -> io::Result<()> {
+        fs::create_dir_all(self.full_path(path)?)
+    }
+
+    /// Falls back to copying and deleting when the paths are on different
+    /// filesystems.
+    fn rename(&self, from_path: &str, to_path: &str) -> io::Result<()> {
+        rename_or_copy(&self.full_path(from_path)?, &self.full_path(to_path)?)
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        fs::remove_file(self.full_path(path)?)
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        fs::remove_dir_all(self.full_path(path)?)
+    }
+
+    fn stat(&self, path: &str) -> io::Result<Option<Stat>> {
+        match fs::symlink_metadata(self.full_path(path)?) {
+            Ok(metadata) => Ok(Some(stat_of(&metadata))),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<(String, Stat)>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(self.full_path(path)?)? {
+            let entry = entry?;
+            entries.push((
+                entry.file_name().to_string_lossy().to_string(),
+                stat_of(&entry.metadata()?),
+            ));
+        }
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(entries)
+    }
+
+    #[cfg(unix)]
+    fn create_symlink(&self, target: &str, path: &str) -> io::Result<()> {
+        std::os::unix::fs::symlink(target, self.full_path(path)?)
+    }
+
+    #[cfg(not(unix))]
+    fn create_symlink(&self, _target: &str, _path: &str) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "symlinks are only supported on unix",
+        ))
+    }
+
+    fn read_link(&self, path: &str) -> io::Result<String> {
+        Ok(fs::read_link(self.full_path(path)?)?
+            .to_string_lossy()
+            .to_string())
+    }
+
+    fn local_path(&self, path: &str) -> Option<path::PathBuf> {
+        self.full_path(path).ok()
+    }
+}
+
+/// `fs::rename`, falling back to copying and deleting when `from` and `to` are
+/// on different filesystems.
+pub fn rename_or_copy(from: &path::Path, to: &path::Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            log::debug!(
+                "`{}` and `{}` are on different filesystems, copying instead",
+                from.display(),
+                to.display()
+            );
+            copy_recursive(from, to)?;
+            if fs::symlink_metadata(from)?.is_dir() {
+                fs::remove_dir_all(from)
+            } else {
+                fs::remove_file(from)
+            }
+        }
+        result => result,
+    }
+}
+
+fn copy_recursive(from: &path::Path, to: &path::Path) -> io::Result<()> {
+    let file_type = fs::symlink_metadata(from)?.file_type();
+    if file_type.is_dir() {
+        fs::create_dir(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_recursive(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else if file_type.is_symlink() {
+        copy_symlink(from, to)?;
+    } else {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink(from: &path::Path, to: &path::Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(from)?, to)
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(from: &path::Path, _to: &path::Path) -> io::Result<()> {
+    log::warn!("Not copying symlink `{}`", from.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_outside_the_root_are_rejected() {
+        let storage = LocalStorage::new(path::PathBuf::from("/nonexistent/root"));
+        for path in ["../outside", "dir/../../outside", "/etc/passwd"] {
+            let err = storage.stat(path).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", path);
+        }
+        assert!(storage.local_path("../outside").is_none());
+        assert_eq!(
+            storage.local_path("dir/file"),
+            Some(path::PathBuf::from("/nonexistent/root/dir/file"))
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use super::{EntryKind, ObjectWriter, Stat, StorageBackend};
+
+#[derive(Debug, Clone)]
+enum Node {
+    File(Vec<u8>),
+    Directory,
+    Symlink(String),
+}
+
+impl Node {
+    fn stat(&self) -> Stat {
+        match self {
+            Node::File(contents) => Stat::new(EntryKind::File, contents.len() as u64),
+            Node::Directory => Stat::new(EntryKind::Directory, 0),
+            Node::Symlink(_) => Stat::new(EntryKind::Symlink, 0),
+        }
+    }
+}
+
+type Nodes = Arc<Mutex<BTreeMap<String, Node>>>;
+
+/// Files kept in memory, for tests. The root always exists.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    nodes: Nodes,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+fn not_found(path: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("`{}` does not exist", path),
+    )
+}
+
+fn parent_of(path: &str) -> &str {
+    path.rsplit_once('/')
+        .map(|(parent, _)| parent)
+        .unwrap_or("")
+}
+
+fn is_below(path: &str, directory: &str) -> bool {
+    path.len() > directory.len()
+        && path.starts_with(directory)
+        && path.as_bytes()[directory.len()] == b'/'
+}
+
+fn check_parent(nodes: &BTreeMap<String, Node>, path: &str) -> io::Result<()> {
+    let parent = parent_of(path);
+    match nodes.get(parent) {
+        _ if parent.is_empty() => Ok(()),
+        Some(Node::Directory) => Ok(()),
+        _ => Err(not_found(parent)),
+    }
+}
+
+struct MemoryWriter {
+    path: String,
+    contents: Vec<u8>,
+    nodes: Nodes,
+}
+
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.contents.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ObjectWriter for MemoryWriter {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        let mut nodes = self.nodes.lock().unwrap();
+        nodes.insert(self.path, Node::File(self.contents));
+        Ok(())
+    }
+}
+
+impl StorageBackend for MemoryStorage {
+    fn open_write(&self, path: &str) -> io::Result<Box<dyn ObjectWriter>> {
+        let mut nodes = self.nodes.lock().unwrap();
+        check_parent(&nodes, path)?;
+        nodes.insert(path.to_string(), Node::File(Vec::new()));
+        Ok(Box::new(MemoryWriter {
+            path: path.to_string(),
+            contents: Vec::new(),
+            nodes: self.nodes.clone(),
+        }))
+    }
+
+    fn read_range(&self, path: &str, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let nodes = self.nodes.lock().unwrap();
+        match nodes.get(path) {
+            Some(Node::File(contents)) => {
+                let start = (offset as usize).min(contents.len());
+                let end = start.saturating_add(len).min(contents.len());
+                Ok(contents[start..end].to_vec())
+            }
+            _ => Err(not_found(path)),
+        }
+    }
+
+    fn create_dir(&self, path: &str) -> io::Result<()> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let mut directory = String::new();
+        for component in path.split('/').filter(|component| !component.is_empty()) {
+            directory = super::join(&directory, component);
+            match nodes.get(&directory) {
+                None => {
+                    nodes.insert(directory.clone(), Node::Directory);
+                }
+                Some(Node::Directory) => {}
+                Some(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("`{}` is not a directory", directory),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from_path: &str, to_path: &str) -> io::Result<()> {
+        let mut nodes = self.nodes.lock().unwrap();
+        check_parent(&nodes, to_path)?;
+        let node = nodes
+            .remove(from_path)
+            .ok_or_else(|| not_found(from_path))?;
+        let descendants = nodes
+            .keys()
+            .filter(|path| is_below(path, from_path))
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in descendants {
+            let moved = nodes.remove(&path).expect("listed above");
+            nodes.insert(format!("{}{}", to_path, &path[from_path.len()..]), moved);
+        }
+        nodes.insert(to_path.to_string(), node);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        let mut nodes = self.nodes.lock().unwrap();
+        match nodes.get(path) {
+            Some(Node::File(_)) | Some(Node::Symlink(_)) => {
+                nodes.remove(path);
+                Ok(())
+            }
+            _ => Err(not_found(path)),
+        }
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        let mut nodes = self.nodes.lock().unwrap();
+        if !matches!(nodes.get(path), Some(Node::Directory)) {
+            return Err(not_found(path));
+        }
+        nodes.retain(|other, _| other != path && !is_below(other, path));
+        Ok(())
+    }
+
+    fn stat(&self, path: &str) -> io::Result<Option<Stat>> {
+        if path.is_empty() {
+            return Ok(Some(Stat::new(EntryKind::Directory, 0)));
+        }
+        Ok(self.nodes.lock().unwrap().get(path).map(Node::stat))
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<(String, Stat)>> {
+        let nodes = self.nodes.lock().unwrap();
+        if !path.is_empty() && !matches!(nodes.get(path), Some(Node::Directory)) {
+            return Err(not_found(path));
+        }
+        Ok(nodes
+            .iter()
+            .filter(|(other, _)| parent_of(other) == path && other.as_str() != path)
+            .map(|(other, node)| {
+                let name = other.rsplit('/').next().unwrap_or(other);
+                (name.to_string(), node.stat())
+            })
+            .collect())
+    }
+
+    fn create_symlink(&self, target: &str, path: &str) -> io::Result<()> {
+        let mut nodes = self.nodes.lock().unwrap();
+        check_parent(&nodes, path)?;
+        if nodes.contains_key(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("`{}` already exists", path),
+            ));
+        }
+        nodes.insert(path.to_string(), Node::Symlink(target.to_string()));
+        Ok(())
+    }
+
+    fn read_link(&self, path: &str) -> io::Result<String> {
+        match self.nodes.lock().unwrap().get(path) {
+            Some(Node::Symlink(target)) => Ok(target.clone()),
+            _ => Err(not_found(path)),
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Where a namespace's files live. Paths are relative to the namespace's
+//! root, `/`-separated, with `""` for the root itself. No operation follows
+//! symlinks.
+
+use std::sync::Arc;
+use std::{fmt, io, path};
+
+use crate::config;
+
+mod cached;
+mod encrypted;
+mod local;
+mod memory;
+#[cfg(feature = "s3")]
+mod s3;
+
+pub use cached::CachedStorage;
+pub use encrypted::EncryptedStorage;
+pub use local::{rename_or_copy, LocalStorage};
+pub use memory::MemoryStorage;
+#[cfg(feature = "s3")]
+pub use s3::S3Storage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    kind: EntryKind,
+    size: u64,
+}
+
+impl Stat {
+    pub fn new(kind: EntryKind, size: u64) -> Self {
+        Self { kind, size }
+    }
+
+    pub fn kind(&self) -> EntryKind {
+        self.kind
+    }
+
+    /// Size in bytes. Always `0` for directories and symlinks.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.kind == EntryKind::File
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind == EntryKind::Directory
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.kind == EntryKind::Symlink
+    }
+}
+
+/// Receives the contents of a file being written. The file only has its new
+/// contents once `finish` returns.
+pub trait ObjectWriter: io::Write + Send {
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+pub trait StorageBackend: fmt::Debug + Send + Sync {
+    /// Creates or truncates the file at `path`. Its parent must exist.
+    fn open_write(&self, path: &str) -> io::Result<Box<dyn ObjectWriter>>;
+
+    /// Up to `len` bytes of the file at `path`, starting at `offset`. Fewer
+    /// bytes are returned only at the end of the file.
+    fn read_range(&self, path: &str, offset: u64, len: usize) -> io::Result<Vec<u8>>;
+
+    /// The whole file at `path`, from its start. Backends that fetch ranges
+    /// one request at a time read the file in a single request instead.
+    fn open_read(&self, path: &str) -> io::Result<Box<dyn io::Read + Send + '_>> {
+        Ok(Box::new(RangeReader {
+            storage: self,
+            path: path.to_string(),
+            offset: 0,
+        }))
+    }
+
+    /// Creates the directory at `path` and any missing parents.
+    fn create_dir(&self, path: &str) -> io::Result<()>;
+
+    /// Moves a file, directory or symlink, replacing a file at `to_path`.
+    fn rename(&self, from_path: &str, to_path: &str) -> io::Result<()>;
+
+    /// Removes the file or symlink at `path`.
+    fn remove_file(&self, path: &str) -> io::Result<()>;
+
+    /// Removes the directory at `path` and everything below it.
+    fn remove_dir(&self, path: &str) -> io::Result<()>;
+
+    /// `None` if nothing exists at `path`.
+    fn stat(&self, path: &str) -> io::Result<Option<Stat>>;
+
+    /// Names and stats of the entries directly below the directory at
+    /// `path`, sorted by name.
+    fn list(&self, path: &str) -> io::Result<Vec<(String, Stat)>>;
+
+    fn create_symlink(&self, target: &str, path: &str) -> io::Result<()>;
+
+    fn read_link(&self, path: &str) -> io::Result<String>;
+
+    /// Where `path` lives on the local filesystem, for features that need a
+    /// real file, such as metadata and free space checks. `None` for remote
+    /// backends.
+    fn local_path(&self, _path: &str) -> Option<path::PathBuf> {
+        None
+    }
+}
+
+/// Reads a file through `read_range`, for backends without a reader of their
+/// own.
+struct RangeReader<'a, S: ?Sized> {
+    storage: &'a S,
+    path: String,
+    offset: u64,
+}
+
+impl<S: StorageBackend + ?Sized> io::Read for RangeReader<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = self
+            .storage
+            .read_range(&self.path, self.offset, buf.len())?;
+        buf[..bytes.len()].copy_from_slice(&bytes);
+        self.offset += bytes.len() as u64;
+        Ok(bytes.len())
+    }
+}
+
+/// The backend for a namespace's storage directory, encrypting file
+/// contents if configured.
+pub fn open(
+    storage_directory: &path::Path,
+    storage_config: &config::StorageConfig,
+) -> io::Result<Arc<dyn StorageBackend>> {
+    let backend = open_backend(storage_directory, storage_config)?;
+    match storage_config.encryption() {
+        Some(encryption_config) => Ok(Arc::new(EncryptedStorage::new(backend, encryption_config)?)),
+        None => Ok(backend),
+    }
+}
+
+/// An object store for `s3://bucket/prefix`, the local directory otherwise.
+pub fn open_backend(
+    storage_directory: &path::Path,
+    storage_config: &config::StorageConfig,
+) -> io::Result<Arc<dyn StorageBackend>> {
+    match storage_directory
+        .to_str()
+        .and_then(|location| location.strip_prefix("s3://"))
+    {
+        Some(location) => open_s3(location, storage_config),
+        None => Ok(Arc::new(LocalStorage::new(storage_directory.to_path_buf()))),
+    }
+}
+
+#[cfg(feature = "s3")]
+fn open_s3(
+    location: &str,
+    storage_config: &config::StorageConfig,
+) -> io::Result<Arc<dyn StorageBackend>> {
+    let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+    if bucket.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("`s3://{}` names no bucket", location),
+        ));
+    }
+    Ok(Arc::new(S3Storage::new(
+        bucket.to_string(),
+        prefix.to_string(),
+        storage_config.s3(),
+    )))
+}
+
+#[cfg(not(feature = "s3"))]
+fn open_s3(
+    location: &str,
+    _storage_config: &config::StorageConfig,
+) -> io::Result<Arc<dyn StorageBackend>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "`s3://{}` needs a server built with the `s3` feature",
+            location
+        ),
+    ))
+}
+
+/// `parent/name`, or just `name` below the root.
+pub fn join(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
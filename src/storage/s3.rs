@@ -0,0 +1,871 @@
+use std::future::Future;
+use std::io::{self, Write};
+use std::pin::Pin;
+
+use aws_config::environment::EnvironmentVariableCredentialsProvider;
+use aws_sdk_s3::{config, error::DisplayErrorContext, primitives::ByteStream, types, Client};
+
+use super::{EntryKind, ObjectWriter, Stat, StorageBackend};
+
+/// Object metadata marking an empty object as a symlink. Holds the
+/// percent-encoded target.
+const SYMLINK_TARGET: &str = "hcs-symlink-target";
+
+/// Largest object a single `CopyObject` accepts.
+const MAX_COPY_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+const MAX_PARTS: u64 = 10_000;
+
+/// Files kept as objects below `prefix` in an S3-compatible bucket.
+/// Directories are zero-byte `path/` marker objects, symlinks are empty
+/// objects carrying their target in their metadata.
+///
+/// S3 calls are async while `StorageBackend` is not, so every method blocks
+/// its thread. Calls fail on anything but a multi-threaded tokio runtime.
+#[derive(Debug, Clone)]
+pub struct S3Storage {
+    client: Client,
+    bucket: String,
+    prefix: String,
+    part_size: usize,
+}
+
+impl S3Storage {
+    pub fn new(bucket: String, prefix: String, s3_config: &crate::config::S3Config) -> Self {
+        let mut builder = config::Builder::new()
+            .behavior_version(config::BehaviorVersion::latest())
+            .region(config::Region::new(s3_config.region().to_string()))
+            .credentials_provider(EnvironmentVariableCredentialsProvider::new())
+            .force_path_style(s3_config.path_style());
+        if let Some(endpoint) = s3_config.endpoint() {
+            builder = builder.endpoint_url(endpoint);
+        }
+        Self {
+            client: Client::from_conf(builder.build()),
+            bucket,
+            prefix: prefix.trim_matches('/').to_string(),
+            part_size: s3_config.part_size(),
+        }
+    }
+
+    fn key(&self, path: &str) -> String {
+        match path {
+            "" => self.prefix.clone(),
+            path => super::join(&self.prefix, path),
+        }
+    }
+
+    /// The prefix shared by everything below the directory at `path`.
+    fn directory_key(&self, path: &str) -> String {
+        match self.key(path) {
+            key if key.is_empty() => key,
+            key => format!("{}/", key),
+        }
+    }
+
+    fn head(&self, key: &str) -> io::Result<Option<Stat>> {
+        let result = block_on(
+            self.client
+                .head_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .send(),
+        )?;
+        match result {
+            Ok(output) => Ok(Some(if symlink_target(output.metadata()).is_some() {
+                Stat::new(EntryKind::Symlink, 0)
+            } else {
+                Stat::new(
+                    EntryKind::File,
+                    output.content_length().unwrap_or(0).max(0) as u64,
+                )
+            })),
+            Err(err)
+                if err
+                    .as_service_error()
+                    .map(|err| err.is_not_found())
+                    .unwrap_or(false) =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(s3_error(err)),
+        }
+    }
+
+    /// Whether anything, a marker included, is stored below `path`.
+    fn is_directory(&self, path: &str) -> io::Result<bool> {
+        let output = block_on(
+            self.client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(self.directory_key(path))
+                .max_keys(1)
+                .send(),
+        )?
+        .map_err(s3_error)?;
+        Ok(!output.contents().is_empty())
+    }
+
+    /// Every object below `prefix`, with its size.
+    fn objects_below(&self, prefix: &str) -> io::Result<Vec<(String, u64)>> {
+        let pages = block_on(
+            self.client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(prefix)
+                .into_paginator()
+                .send()
+                .try_collect(),
+        )?
+        .map_err(s3_error)?;
+        Ok(pages
+            .iter()
+            .flat_map(|page| page.contents())
+            .filter_map(|object| {
+                let size = object.size().unwrap_or(0).max(0) as u64;
+                object.key().map(|key| (key.to_string(), size))
+            })
+            .collect())
+    }
+
+    fn put(&self, key: &str, body: Vec<u8>) -> io::Result<()> {
+        block_on(
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .body(ByteStream::from(body))
+                .send(),
+        )?
+        .map_err(s3_error)?;
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        block_on(
+            self.client
+                .delete_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .send(),
+        )?
+        .map_err(s3_error)?;
+        Ok(())
+    }
+
+    /// Server-side copy, in parts for objects too large for a single
+    /// `CopyObject`.
+    fn copy(&self, from_key: &str, to_key: &str, size: u64) -> io::Result<()> {
+        let copy_source = format!("{}/{}", self.bucket, percent_encode(from_key));
+        if size <= MAX_COPY_SIZE {
+            block_on(
+                self.client
+                    .copy_object()
+                    .bucket(&self.bucket)
+                    .key(to_key)
+                    .copy_source(copy_source)
+                    .send(),
+            )?
+            .map_err(s3_error)?;
+            return Ok(());
+        }
+
+        let part_size = (self.part_size as u64).max(size.div_ceil(MAX_PARTS));
+        let mut upload = MultipartUpload::start(self, to_key)?;
+        let mut offset = 0;
+        while offset < size {
+            let end = (offset + part_size).min(size) - 1;
+            let part_number = upload.next_part_number();
+            let output = block_on(
+                self.client
+                    .upload_part_copy()
+                    .bucket(&self.bucket)
+                    .key(to_key)
+                    .upload_id(&upload.upload_id)
+                    .part_number(part_number)
+                    .copy_source(&copy_source)
+                    .copy_source_range(format!("bytes={}-{}", offset, end))
+                    .send(),
+            )?
+            .map_err(s3_error)?;
+            let e_tag = output
+                .copy_part_result()
+                .and_then(|result| result.e_tag())
+                .map(str::to_string);
+            upload.add_part(part_number, e_tag);
+            offset = end + 1;
+        }
+        upload.complete()
+    }
+}
+
+/// Runs an S3 call to completion from the blocking `StorageBackend` API.
+/// Waiting would stall every other task on a current-thread runtime, so the
+/// call fails there instead, as it does outside of a runtime.
+fn block_on<F: Future>(future: F) -> io::Result<F::Output> {
+    let handle = tokio::runtime::Handle::try_current().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "S3 storage can only be used from within a tokio runtime",
+        )
+    })?;
+    match handle.runtime_flavor() {
+        tokio::runtime::RuntimeFlavor::MultiThread => {
+            Ok(tokio::task::block_in_place(|| handle.block_on(future)))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "S3 storage needs a multi-threaded tokio runtime",
+        )),
+    }
+}
+
+fn s3_error(err: impl std::error::Error) -> io::Error {
+    io::Error::other(DisplayErrorContext(&err).to_string())
+}
+
+fn not_found(path: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("`{}` does not exist", path),
+    )
+}
+
+fn symlink_target(metadata: Option<&std::collections::HashMap<String, String>>) -> Option<String> {
+    metadata
+        .and_then(|metadata| metadata.get(SYMLINK_TARGET))
+        .map(|target| percent_decode(target))
+}
+
+/// Encodes everything but unreserved characters and `/`, as copy sources
+/// and metadata values must be plain ASCII.
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// A multipart upload that is aborted unless it completes.
+struct MultipartUpload {
+    client: Client,
+    bucket: String,
+    key: String,
+    upload_id: String,
+    parts: Vec<types::CompletedPart>,
+    completed: bool,
+}
+
+impl MultipartUpload {
+    fn start(storage: &S3Storage, key: &str) -> io::Result<Self> {
+        let output = block_on(
+            storage
+                .client
+                .create_multipart_upload()
+                .bucket(&storage.bucket)
+                .key(key)
+                .send(),
+        )?
+        .map_err(s3_error)?;
+        let upload_id = output
+            .upload_id()
+            .ok_or_else(|| io::Error::other("multipart upload has no upload id"))?;
+        Ok(Self {
+            client: storage.client.clone(),
+            bucket: storage.bucket.clone(),
+            key: key.to_string(),
+            upload_id: upload_id.to_string(),
+            parts: Vec::new(),
+            completed: false,
+        })
+    }
+
+    fn next_part_number(&self) -> i32 {
+        self.parts.len() as i32 + 1
+    }
+
+    fn add_part(&mut self, part_number: i32, e_tag: Option<String>) {
+        self.parts.push(
+            types::CompletedPart::builder()
+                .part_number(part_number)
+                .set_e_tag(e_tag)
+                .build(),
+        );
+    }
+
+    fn upload_part(&mut self, body: Vec<u8>) -> io::Result<()> {
+        let part_number = self.next_part_number();
+        let output = block_on(
+            self.client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(&self.key)
+                .upload_id(&self.upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(body))
+                .send(),
+        )?
+        .map_err(s3_error)?;
+        self.add_part(part_number, output.e_tag().map(str::to_string));
+        Ok(())
+    }
+
+    fn complete(mut self) -> io::Result<()> {
+        let parts = types::CompletedMultipartUpload::builder()
+            .set_parts(Some(std::mem::take(&mut self.parts)))
+            .build();
+        block_on(
+            self.client
+                .complete_multipart_upload()
+                .bucket(&self.bucket)
+                .key(&self.key)
+                .upload_id(&self.upload_id)
+                .multipart_upload(parts)
+                .send(),
+        )?
+        .map_err(s3_error)?;
+        self.completed = true;
+        Ok(())
+    }
+}
+
+impl Drop for MultipartUpload {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+        let result = block_on(
+            self.client
+                .abort_multipart_upload()
+                .bucket(&self.bucket)
+                .key(&self.key)
+                .upload_id(&self.upload_id)
+                .send(),
+        )
+        .and_then(|result| result.map_err(s3_error));
+        if let Err(err) = result {
+            log::warn!(
+                "Failed to abort multipart upload of `{}`: {}",
+                self.key,
+                err
+            );
+        }
+    }
+}
+
+/// Buffers small files for a single `PutObject`. Larger files switch to a
+/// multipart upload once the first part is full.
+struct S3Writer {
+    storage: S3Storage,
+    key: String,
+    buffer: Vec<u8>,
+    upload: Option<MultipartUpload>,
+}
+
+impl S3Writer {
+    fn upload_full_parts(&mut self) -> io::Result<()> {
+        while self.buffer.len() >= self.storage.part_size {
+            let rest = self.buffer.split_off(self.storage.part_size);
+            let part = std::mem::replace(&mut self.buffer, rest);
+            let upload = match &mut self.upload {
+                Some(upload) => upload,
+                None => self
+                    .upload
+                    .insert(MultipartUpload::start(&self.storage, &self.key)?),
+            };
+            upload.upload_part(part)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for S3Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        self.upload_full_parts()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ObjectWriter for S3Writer {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        let buffer = std::mem::take(&mut self.buffer);
+        match self.upload.take() {
+            None => self.storage.put(&self.key, buffer),
+            Some(mut upload) => {
+                if !buffer.is_empty() {
+                    upload.upload_part(buffer)?;
+                }
+                upload.complete()
+            }
+        }
+    }
+}
+
+/// The body of a single `GetObject`, read as it arrives.
+struct S3Reader {
+    body: Pin<Box<dyn tokio::io::AsyncBufRead + Send>>,
+}
+
+impl io::Read for S3Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        block_on(tokio::io::AsyncReadExt::read(&mut self.body, buf))?
+    }
+}
+
+impl StorageBackend for S3Storage {
+    fn open_write(&self, path: &str) -> io::Result<Box<dyn ObjectWriter>> {
+        Ok(Box::new(S3Writer {
+            storage: self.clone(),
+            key: self.key(path),
+            buffer: Vec::new(),
+            upload: None,
+        }))
+    }
+
+    fn read_range(&self, path: &str, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let range = format!("bytes={}-{}", offset, offset + len as u64 - 1);
+        let output = match block_on(
+            self.client
+                .get_object()
+                .bucket(&self.bucket)
+                .key(self.key(path))
+                .range(range)
+                .send(),
+        )? {
+            Ok(output) => output,
+            // Range Not Satisfiable: `offset` is at or past the end
+            Err(err)
+                if err
+                    .raw_response()
+                    .map(|response| response.status().as_u16())
+                    == Some(416) =>
+            {
+                return Ok(Vec::new());
+            }
+            Err(err)
+                if err
+                    .as_service_error()
+                    .map(|err| err.is_no_such_key())
+                    .unwrap_or(false) =>
+            {
+                return Err(not_found(path));
+            }
+            Err(err) => return Err(s3_error(err)),
+        };
+        let body = block_on(output.body.collect())?.map_err(s3_error)?;
+        Ok(body.into_bytes().to_vec())
+    }
+
+    fn open_read(&self, path: &str) -> io::Result<Box<dyn io::Read + Send + '_>> {
+        let output = match block_on(
+            self.client
+                .get_object()
+                .bucket(&self.bucket)
+                .key(self.key(path))
+                .send(),
+        )? {
+            Ok(output) => output,
+            Err(err)
+                if err
+                    .as_service_error()
+                    .map(|err| err.is_no_such_key())
+                    .unwrap_or(false) =>
+            {
+                return Err(not_found(path));
+            }
+            Err(err) => return Err(s3_error(err)),
+        };
+        Ok(Box::new(S3Reader {
+            body: Box::pin(output.body.into_async_read()),
+        }))
+    }
+
+    fn create_dir(&self, path: &str) -> io::Result<()> {
+        let mut directory = String::new();
+        for component in path.split('/').filter(|component| !component.is_empty()) {
+            directory = super::join(&directory, component);
+            self.put(&self.directory_key(&directory), Vec::new())?;
+        }
+        Ok(())
+    }
+
+    /// Copies, then deletes the source. A directory is moved object by object.
+    fn rename(&self, from_path: &str, to_path: &str) -> io::Result<()> {
+        match self.stat(from_path)? {
+            None => Err(not_found(from_path)),
+            Some(stat) if stat.is_dir() => {
+                let from_prefix = self.directory_key(from_path);
+                let to_prefix = self.directory_key(to_path);
+                for (key, size) in self.objects_below(&from_prefix)? {
+                    let to_key = format!("{}{}", to_prefix, &key[from_prefix.len()..]);
+                    self.copy(&key, &to_key, size)?;
+                    self.delete(&key)?;
+                }
+                Ok(())
+            }
+            Some(stat) => {
+                self.copy(&self.key(from_path), &self.key(to_path), stat.size())?;
+                self.delete(&self.key(from_path))
+            }
+        }
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        self.delete(&self.key(path))
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        for (key, _) in self.objects_below(&self.directory_key(path))? {
+            self.delete(&key)?;
+        }
+        Ok(())
+    }
+
+    fn stat(&self, path: &str) -> io::Result<Option<Stat>> {
+        if path.is_empty() {
+            return Ok(Some(Stat::new(EntryKind::Directory, 0)));
+        }
+        if let Some(stat) = self.head(&self.key(path))? {
+            return Ok(Some(stat));
+        }
+        Ok(self
+            .is_directory(path)?
+            .then(|| Stat::new(EntryKind::Directory, 0)))
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<(String, Stat)>> {
+        if !path.is_empty() && !self.is_directory(path)? {
+            return Err(not_found(path));
+        }
+        let prefix = self.directory_key(path);
+        let pages = block_on(
+            self.client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(&prefix)
+                .delimiter("/")
+                .into_paginator()
+                .send()
+                .try_collect(),
+        )?
+        .map_err(s3_error)?;
+
+        let mut entries = Vec::new();
+        for page in &pages {
+            for common_prefix in page.common_prefixes() {
+                if let Some(directory) = common_prefix.prefix() {
+                    let name = directory[prefix.len()..].trim_end_matches('/');
+                    entries.push((name.to_string(), Stat::new(EntryKind::Directory, 0)));
+                }
+            }
+            for object in page.contents() {
+                let key = match object.key() {
+                    Some(key) if key != prefix => key,
+                    _ => continue,
+                };
+                let size = object.size().unwrap_or(0).max(0) as u64;
+                // Listings carry no metadata, and only empty objects can be
+                // symlinks
+                let stat = match size {
+                    0 => self
+                        .head(key)?
+                        .unwrap_or_else(|| Stat::new(EntryKind::File, 0)),
+                    size => Stat::new(EntryKind::File, size),
+                };
+                entries.push((key[prefix.len()..].to_string(), stat));
+            }
+        }
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(entries)
+    }
+
+    fn create_symlink(&self, target: &str, path: &str) -> io::Result<()> {
+        if self.stat(path)?.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("`{}` already exists", path),
+            ));
+        }
+        block_on(
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(self.key(path))
+                .metadata(SYMLINK_TARGET, percent_encode(target))
+                .body(ByteStream::from(Vec::new()))
+                .send(),
+        )?
+        .map_err(s3_error)?;
+        Ok(())
+    }
+
+    fn read_link(&self, path: &str) -> io::Result<String> {
+        let output = match block_on(
+            self.client
+                .head_object()
+                .bucket(&self.bucket)
+                .key(self.key(path))
+                .send(),
+        )? {
+            Ok(output) => output,
+            Err(err)
+                if err
+                    .as_service_error()
+                    .map(|err| err.is_not_found())
+                    .unwrap_or(false) =>
+            {
+                return Err(not_found(path));
+            }
+            Err(err) => return Err(s3_error(err)),
+        };
+        symlink_target(output.metadata()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("`{}` is not a symlink", path),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod stand_in;
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{storage, test_support};
+
+    /// Storage below `ns/` in the stand-in's bucket, uploading in parts of
+    /// `part_size` bytes.
+    fn s3_storage(stand_in: &stand_in::StandIn, part_size: usize) -> S3Storage {
+        std::env::set_var("AWS_ACCESS_KEY_ID", "access");
+        std::env::set_var("AWS_SECRET_ACCESS_KEY", "secret");
+        let s3_config: crate::config::S3Config = toml::from_str(&format!(
+            "endpoint = {:?}\npath_style = true\npart_size = {}",
+            stand_in.endpoint(),
+            part_size
+        ))
+        .unwrap();
+        S3Storage::new("bucket".to_string(), "ns".to_string(), &s3_config)
+    }
+
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn write(storage: &dyn StorageBackend, path: &str, contents: &[u8]) {
+        let mut writer = storage.open_write(path).unwrap();
+        for part in contents.chunks(1000) {
+            writer.write_all(part).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    fn read(storage: &dyn StorageBackend, path: &str) -> Vec<u8> {
+        let mut contents = Vec::new();
+        storage
+            .open_read(path)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        contents
+    }
+
+    fn names(entries: Vec<(String, Stat)>) -> Vec<(String, EntryKind)> {
+        entries
+            .into_iter()
+            .map(|(name, stat)| (name, stat.kind()))
+            .collect()
+    }
+
+    #[test]
+    fn percent_encoding_round_trips() {
+        for value in [
+            "plain/path.txt",
+            "a b%20c",
+            "ü/ß & €",
+            "../target?x=1#y",
+            "",
+        ] {
+            let encoded = percent_encode(value);
+            assert!(encoded.is_ascii());
+            assert!(!encoded.contains(' '));
+            assert_eq!(percent_decode(&encoded), value);
+        }
+        assert_eq!(percent_encode("a b/c"), "a%20b/c");
+        // Stray escapes are kept as they are
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn files_are_written_and_read_in_ranges() {
+        let stand_in = stand_in::StandIn::start();
+        let storage = s3_storage(&stand_in, 4000);
+        write(&storage, "small.txt", b"small file");
+        // Larger than a part, so uploaded in parts
+        write(&storage, "large", &contents(10_000));
+        assert_eq!(stand_in.keys(), ["ns/large", "ns/small.txt"]);
+
+        assert_eq!(storage.read_range("small.txt", 6, 100).unwrap(), b"file");
+        assert_eq!(
+            storage.read_range("large", 3990, 20).unwrap(),
+            &contents(10_000)[3990..4010]
+        );
+        assert!(storage.read_range("large", 10_000, 10).unwrap().is_empty());
+        assert!(storage.read_range("large", 5, 0).unwrap().is_empty());
+        assert_eq!(read(&storage, "large"), contents(10_000));
+
+        let err = storage.read_range("missing", 0, 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let err = storage.open_read("missing").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        assert_eq!(
+            storage.stat("large").unwrap(),
+            Some(Stat::new(EntryKind::File, 10_000))
+        );
+        assert_eq!(storage.stat("missing").unwrap(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn directories_are_listed_from_markers_and_keys() {
+        let stand_in = stand_in::StandIn::start();
+        let storage = s3_storage(&stand_in, 4000);
+        storage.create_dir("a/empty").unwrap();
+        write(&storage, "a/file.txt", b"abc");
+        write(&storage, "a/b/c.txt", b"c");
+        storage.create_symlink("../ü ß.txt", "a/link").unwrap();
+
+        assert_eq!(
+            names(storage.list("").unwrap()),
+            [("a".to_string(), EntryKind::Directory)]
+        );
+        assert_eq!(
+            names(storage.list("a").unwrap()),
+            [
+                ("b".to_string(), EntryKind::Directory),
+                ("empty".to_string(), EntryKind::Directory),
+                ("file.txt".to_string(), EntryKind::File),
+                ("link".to_string(), EntryKind::Symlink),
+            ]
+        );
+        assert!(storage.list("a/empty").unwrap().is_empty());
+        let err = storage.list("missing").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        assert!(storage.stat("a").unwrap().unwrap().is_dir());
+        assert!(storage.stat("a/b").unwrap().unwrap().is_dir());
+        assert!(storage.stat("a/link").unwrap().unwrap().is_symlink());
+        assert_eq!(storage.read_link("a/link").unwrap(), "../ü ß.txt");
+        let err = storage.read_link("a/file.txt").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = storage.create_symlink("x", "a/file.txt").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn renames_and_removes_move_every_object() {
+        let stand_in = stand_in::StandIn::start();
+        let storage = s3_storage(&stand_in, 4000);
+        storage.create_dir("from/sub").unwrap();
+        write(&storage, "from/sub/a b.txt", b"a");
+        write(&storage, "file", b"file");
+
+        storage.rename("file", "renamed").unwrap();
+        assert_eq!(storage.read_range("renamed", 0, 4).unwrap(), b"file");
+        assert_eq!(storage.stat("file").unwrap(), None);
+
+        storage.rename("from", "to").unwrap();
+        assert_eq!(storage.read_range("to/sub/a b.txt", 0, 1).unwrap(), b"a");
+        assert_eq!(storage.stat("from").unwrap(), None);
+        let err = storage.rename("missing", "other").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        storage.remove_file("renamed").unwrap();
+        assert_eq!(storage.stat("renamed").unwrap(), None);
+        storage.remove_dir("to").unwrap();
+        assert!(stand_in.keys().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encrypted_files_are_read_in_one_request() {
+        let stand_in = stand_in::StandIn::start();
+        let encryption_config: crate::config::EncryptionConfig = toml::from_str(
+            "key = \"0101010101010101010101010101010101010101010101010101010101010101\"",
+        )
+        .unwrap();
+        let storage = storage::EncryptedStorage::new(
+            Arc::new(s3_storage(&stand_in, 1 << 20)),
+            &encryption_config,
+        )
+        .unwrap();
+        // Several encrypted chunks
+        write(&storage, "file", &contents(200_000));
+        test_support::write_file(&storage, "notes.txt", "notes");
+
+        assert_eq!(read(&storage, "file"), contents(200_000));
+        // The header, then the whole file
+        assert_eq!(stand_in.requests("GET", "ns/file"), 2);
+        assert_eq!(stand_in.requests("HEAD", "ns/file"), 1);
+        assert_eq!(read(&storage, "notes.txt"), b"notes");
+    }
+
+    #[test]
+    fn calls_fail_outside_of_a_runtime() {
+        let err = block_on(async {}).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn calls_fail_on_a_current_thread_runtime() {
+        let err = block_on(async {}).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn calls_run_on_a_multi_threaded_runtime() {
+        assert_eq!(block_on(async { 1 }).unwrap(), 1);
+    }
+}
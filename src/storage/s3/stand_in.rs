@@ -0,0 +1,425 @@
+//! A MinIO-style stand-in for tests: an HTTP server on a loopback port that
+//! keeps objects in memory and answers the subset of the S3 API `S3Storage`
+//! uses, with path-style addressing.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Write};
+use std::net;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Default)]
+struct Object {
+    body: Vec<u8>,
+    metadata: Vec<(String, String)>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    objects: BTreeMap<String, Object>,
+    uploads: HashMap<String, BTreeMap<u32, Vec<u8>>>,
+    next_upload_id: u32,
+    /// Method and key of every request, in order.
+    requests: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StandIn {
+    endpoint: String,
+    state: Arc<Mutex<State>>,
+}
+
+struct Request {
+    method: String,
+    key: String,
+    query: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn xml(status: u16, body: String) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type".to_string(), "application/xml".to_string())],
+            body: body.into_bytes(),
+        }
+    }
+
+    fn error(status: u16, code: &str) -> Self {
+        Self::xml(
+            status,
+            format!(
+                "<Error><Code>{}</Code><Message>{}</Message></Error>",
+                code, code
+            ),
+        )
+    }
+}
+
+impl StandIn {
+    pub fn start() -> Self {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stand_in = Self {
+            endpoint: format!("http://{}", listener.local_addr().unwrap()),
+            state: Arc::default(),
+        };
+        let state = stand_in.state.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let state = state.clone();
+                std::thread::spawn(move || serve(stream, &state));
+            }
+        });
+        stand_in
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Keys of the stored objects.
+    pub fn keys(&self) -> Vec<String> {
+        self.state.lock().unwrap().objects.keys().cloned().collect()
+    }
+
+    /// Number of requests made with `method` for `key`.
+    pub fn requests(&self, method: &str, key: &str) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .requests
+            .iter()
+            .filter(|request| *request == &(method.to_string(), key.to_string()))
+            .count()
+    }
+}
+
+fn serve(stream: net::TcpStream, state: &Mutex<State>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    while let Some(request) = read_request(&mut reader) {
+        let head = request.method == "HEAD";
+        let response = handle(request, &mut state.lock().unwrap());
+        let mut bytes = format!("HTTP/1.1 {} Stand-in\r\n", response.status);
+        for (name, value) in &response.headers {
+            bytes.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        // HEAD responses carry the object's length instead
+        if !head {
+            bytes.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+        }
+        bytes.push_str("\r\n");
+        let mut bytes = bytes.into_bytes();
+        if !head {
+            bytes.extend_from_slice(&response.body);
+        }
+        if writer.write_all(&bytes).is_err() {
+            return;
+        }
+    }
+}
+
+fn read_request(reader: &mut impl BufRead) -> Option<Request> {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok().filter(|len| *len > 0)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let target = parts.next()?.to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        headers.insert(name.to_ascii_lowercase(), value.trim().to_string());
+    }
+
+    let mut body = Vec::new();
+    if headers.get("transfer-encoding").map(String::as_str) == Some("chunked") {
+        loop {
+            let mut size = String::new();
+            reader.read_line(&mut size).ok()?;
+            let size = usize::from_str_radix(size.trim().split(';').next()?, 16).ok()?;
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk).ok()?;
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    } else if let Some(len) = headers.get("content-length") {
+        body.resize(len.parse().ok()?, 0);
+        reader.read_exact(&mut body).ok()?;
+    }
+    if headers
+        .get("content-encoding")
+        .is_some_and(|encoding| encoding.contains("aws-chunked"))
+    {
+        body = decode_aws_chunked(&body)?;
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    // Path-style: `/bucket/key`
+    let key = path
+        .trim_start_matches('/')
+        .split_once('/')
+        .map(|(_, key)| super::percent_decode(key))
+        .unwrap_or_default();
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (super::percent_decode(name), super::percent_decode(value))
+        })
+        .collect();
+    Some(Request {
+        method,
+        key,
+        query,
+        headers,
+        body,
+    })
+}
+
+/// Bodies sent with `Content-Encoding: aws-chunked`, as checksummed uploads
+/// are.
+fn decode_aws_chunked(mut encoded: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = encoded.windows(2).position(|window| window == b"\r\n")?;
+        let size = std::str::from_utf8(&encoded[..line_end]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        if size == 0 {
+            return Some(body);
+        }
+        let chunk = encoded.get(line_end + 2..line_end + 2 + size)?;
+        body.extend_from_slice(chunk);
+        encoded = encoded.get(line_end + 4 + size..)?;
+    }
+}
+
+fn handle(request: Request, state: &mut State) -> Response {
+    state
+        .requests
+        .push((request.method.clone(), request.key.clone()));
+    match (request.method.as_str(), request.key.as_str()) {
+        ("GET", "") => list(&request, state),
+        ("HEAD", key) => match state.objects.get(key) {
+            Some(object) => {
+                let mut response = Response::new(200);
+                response.headers = metadata_headers(object);
+                response
+                    .headers
+                    .push(("Content-Length".to_string(), object.body.len().to_string()));
+                response
+            }
+            None => Response::new(404),
+        },
+        ("GET", key) => match state.objects.get(key) {
+            Some(object) => get(&request, object),
+            None => Response::error(404, "NoSuchKey"),
+        },
+        ("PUT", key) => {
+            if let Some(upload_id) = request.query.get("uploadId") {
+                let part_number = request.query["partNumber"].parse().unwrap();
+                state
+                    .uploads
+                    .get_mut(upload_id)
+                    .unwrap()
+                    .insert(part_number, request.body);
+                let mut response = Response::new(200);
+                response
+                    .headers
+                    .push(("ETag".to_string(), format!("\"{}\"", part_number)));
+                return response;
+            }
+            let object = match request.headers.get("x-amz-copy-source") {
+                Some(source) => {
+                    let source = super::percent_decode(source);
+                    let (_, source_key) = source.trim_start_matches('/').split_once('/').unwrap();
+                    match state.objects.get(source_key) {
+                        Some(object) => object.clone(),
+                        None => return Response::error(404, "NoSuchKey"),
+                    }
+                }
+                None => Object {
+                    body: request.body,
+                    metadata: request
+                        .headers
+                        .iter()
+                        .filter_map(|(name, value)| {
+                            name.strip_prefix("x-amz-meta-")
+                                .map(|name| (name.to_string(), value.clone()))
+                        })
+                        .collect(),
+                },
+            };
+            let copied = request.headers.contains_key("x-amz-copy-source");
+            state.objects.insert(key.to_string(), object);
+            match copied {
+                true => Response::xml(
+                    200,
+                    "<CopyObjectResult><ETag>\"copy\"</ETag></CopyObjectResult>".to_string(),
+                ),
+                false => Response::new(200),
+            }
+        }
+        ("POST", key) if request.query.contains_key("uploads") => {
+            state.next_upload_id += 1;
+            let upload_id = state.next_upload_id.to_string();
+            state.uploads.insert(upload_id.clone(), BTreeMap::new());
+            Response::xml(
+                200,
+                format!(
+                    "<InitiateMultipartUploadResult><Bucket>bucket</Bucket><Key>{}</Key>\
+                     <UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                    escape(key),
+                    upload_id
+                ),
+            )
+        }
+        ("POST", key) => {
+            let parts = state.uploads.remove(&request.query["uploadId"]).unwrap();
+            let object = Object {
+                body: parts.into_values().flatten().collect(),
+                metadata: Vec::new(),
+            };
+            state.objects.insert(key.to_string(), object);
+            Response::xml(
+                200,
+                format!(
+                    "<CompleteMultipartUploadResult><Key>{}</Key><ETag>\"done\"</ETag>\
+                     </CompleteMultipartUploadResult>",
+                    escape(key)
+                ),
+            )
+        }
+        ("DELETE", key) => {
+            match request.query.get("uploadId") {
+                Some(upload_id) => {
+                    state.uploads.remove(upload_id);
+                }
+                None => {
+                    state.objects.remove(key);
+                }
+            }
+            Response::new(204)
+        }
+        _ => Response::error(400, "NotImplemented"),
+    }
+}
+
+fn metadata_headers(object: &Object) -> Vec<(String, String)> {
+    object
+        .metadata
+        .iter()
+        .map(|(name, value)| (format!("x-amz-meta-{}", name), value.clone()))
+        .collect()
+}
+
+fn get(request: &Request, object: &Object) -> Response {
+    let range = request
+        .headers
+        .get("range")
+        .and_then(|range| range.strip_prefix("bytes="))
+        .and_then(|range| range.split_once('-'));
+    let len = object.body.len();
+    let (status, body) = match range {
+        Some((start, end)) => {
+            let start: usize = start.parse().unwrap();
+            if start >= len {
+                return Response::error(416, "InvalidRange");
+            }
+            let end = end.parse::<usize>().map_or(len, |end| (end + 1).min(len));
+            (206, object.body[start..end].to_vec())
+        }
+        None => (200, object.body.clone()),
+    };
+    Response {
+        status,
+        headers: metadata_headers(object),
+        body,
+    }
+}
+
+fn list(request: &Request, state: &State) -> Response {
+    let prefix = request.query.get("prefix").cloned().unwrap_or_default();
+    let delimiter = request.query.get("delimiter");
+    let max_keys = request
+        .query
+        .get("max-keys")
+        .map_or(usize::MAX, |max_keys| max_keys.parse().unwrap());
+
+    let mut contents = String::new();
+    let mut common_prefixes = Vec::<String>::new();
+    let mut count = 0;
+    for (key, object) in state.objects.range(prefix.clone()..) {
+        if !key.starts_with(&prefix) || count == max_keys {
+            break;
+        }
+        let rest = &key[prefix.len()..];
+        match delimiter.and_then(|delimiter| rest.find(delimiter.as_str())) {
+            Some(end) => {
+                let common_prefix = format!("{}{}", prefix, &rest[..=end]);
+                if common_prefixes.last() != Some(&common_prefix) {
+                    common_prefixes.push(common_prefix);
+                    count += 1;
+                }
+            }
+            None => {
+                contents.push_str(&format!(
+                    "<Contents><Key>{}</Key><Size>{}</Size></Contents>",
+                    escape(key),
+                    object.body.len()
+                ));
+                count += 1;
+            }
+        }
+    }
+    let common_prefixes: String = common_prefixes
+        .iter()
+        .map(|prefix| {
+            format!(
+                "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+                escape(prefix)
+            )
+        })
+        .collect();
+    Response::xml(
+        200,
+        format!(
+            "<ListBucketResult><Name>bucket</Name><Prefix>{}</Prefix><KeyCount>{}</KeyCount>\
+             <IsTruncated>false</IsTruncated>{}{}</ListBucketResult>",
+            escape(&prefix),
+            count,
+            contents,
+            common_prefixes
+        ),
+    )
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,182 @@
+//! Symlinks are synced as entries of their own, carrying their target as an
+//! opaque string. The server never follows a symlink: paths that would
+//! resolve through one are rejected.
+
+use std::io;
+
+use crate::{errors, storage};
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum SymlinkEvent {
+    Create(SymlinkCreate),
+    Delete(SymlinkDelete),
+    Move(SymlinkMove),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SymlinkCreate {
+    path: String,
+    target: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SymlinkDelete {
+    path: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SymlinkMove {
+    from_path: String,
+    to_path: String,
+}
+
+impl SymlinkCreate {
+    pub fn new(path: String, target: String) -> Self {
+        Self { path, target }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+}
+
+impl SymlinkDelete {
+    pub fn new(path: String) -> Self {
+        Self { path }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl SymlinkMove {
+    pub fn new(from_path: String, to_path: String) -> Self {
+        Self { from_path, to_path }
+    }
+
+    pub fn from_path(&self) -> &str {
+        &self.from_path
+    }
+
+    pub fn to_path(&self) -> &str {
+        &self.to_path
+    }
+}
+
+/// Fails if any existing ancestor of `path` is a symlink.
+pub fn check_ancestors(
+    storage: &dyn storage::StorageBackend,
+    path: &str,
+) -> Result<(), errors::ServerTcpError> {
+    let mut ancestor = String::new();
+    let components = path
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .collect::<Vec<_>>();
+    for component in components.iter().take(components.len().saturating_sub(1)) {
+        ancestor = storage::join(&ancestor, component);
+        if is_symlink(storage, &ancestor) {
+            return Err(errors::ServerTcpError::SymlinkInPath(path.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Like `check_ancestors`, but `path` itself must not be a symlink either.
+pub fn check_not_symlink(
+    storage: &dyn storage::StorageBackend,
+    path: &str,
+) -> Result<(), errors::ServerTcpError> {
+    check_ancestors(storage, path)?;
+    if is_symlink(storage, path) {
+        return Err(errors::ServerTcpError::SymlinkInPath(path.to_string()));
+    }
+    Ok(())
+}
+
+pub fn is_symlink(storage: &dyn storage::StorageBackend, path: &str) -> bool {
+    matches!(storage.stat(path), Ok(Some(stat)) if stat.is_symlink())
+}
+
+/// Creates for every symlink stored below `directory` (`""` for the root).
+pub fn tree_symlinks(
+    storage: &dyn storage::StorageBackend,
+    directory: &str,
+) -> io::Result<Vec<SymlinkEvent>> {
+    let mut symlink_events = Vec::new();
+    walk(storage, directory, &mut symlink_events)?;
+    Ok(symlink_events)
+}
+
+fn walk(
+    storage: &dyn storage::StorageBackend,
+    directory: &str,
+    symlink_events: &mut Vec<SymlinkEvent>,
+) -> io::Result<()> {
+    if !matches!(storage.stat(directory)?, Some(stat) if stat.is_dir()) {
+        return Ok(());
+    }
+
+    for (name, stat) in storage.list(directory)? {
+        let relative_path = storage::join(directory, &name);
+        if stat.is_symlink() {
+            let target = storage.read_link(&relative_path)?;
+            symlink_events.push(SymlinkEvent::Create(SymlinkCreate::new(
+                relative_path,
+                target,
+            )));
+        } else if stat.is_dir() {
+            walk(storage, &relative_path, symlink_events)?;
+        }
+    }
+    Ok(())
+}
+
+/// Restricts a symlink change to the paths a client may see, as
+/// `change_filter::filter_changes` does for files.
+pub fn filter_symlink_event<F>(
+    symlink_event: SymlinkEvent,
+    storage: &dyn storage::StorageBackend,
+    is_visible: F,
+) -> Option<SymlinkEvent>
+where
+    F: Fn(&str) -> bool,
+{
+    match symlink_event {
+        SymlinkEvent::Create(ref symlink_create) => {
+            is_visible(symlink_create.path()).then_some(symlink_event)
+        }
+        SymlinkEvent::Delete(ref symlink_delete) => {
+            is_visible(symlink_delete.path()).then_some(symlink_event)
+        }
+        SymlinkEvent::Move(symlink_move) => match (
+            is_visible(symlink_move.from_path()),
+            is_visible(symlink_move.to_path()),
+        ) {
+            (true, true) => Some(SymlinkEvent::Move(symlink_move)),
+            (true, false) => Some(SymlinkEvent::Delete(SymlinkDelete::new(
+                symlink_move.from_path,
+            ))),
+            (false, true) => match storage.read_link(symlink_move.to_path()) {
+                Ok(target) => Some(SymlinkEvent::Create(SymlinkCreate::new(
+                    symlink_move.to_path,
+                    target,
+                ))),
+                Err(err) => {
+                    log::error!(
+                        "Failed to read symlink `{}` for a move into view: {}",
+                        symlink_move.to_path(),
+                        err
+                    );
+                    None
+                }
+            },
+            (false, false) => None,
+        },
+    }
+}
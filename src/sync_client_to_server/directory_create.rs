@@ -1,18 +1,23 @@
-use std::fs;
+use hcs_lib::data;
 
-use hcs_lib::{data, server_database};
+use crate::{acl, db, ignore_rules, symlinks};
 
 pub async fn handle_directory_create(
-    db_pool: &sqlx::PgPool,
-    file_handler_config: &server_database::ServerFileHandlerConfig,
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    access: &acl::AccessControl,
+    ignore_rules: &ignore_rules::IgnoreRules,
     directory_create: data::DirectoryCreate,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let create_path = file_handler_config
-        .storage_directory()
-        .join(directory_create.path());
+    access.check_write(directory_create.path())?;
+    if !ignore_rules.check(directory_create.path(), true)? {
+        log::info!("Dropping ignored directory `{}`", directory_create.path());
+        return Ok(());
+    }
+    symlinks::check_not_symlink(namespace.storage(), directory_create.path())?;
 
-    if !create_path.exists() {
-        fs::create_dir_all(create_path)?;
+    if namespace.storage().stat(directory_create.path())?.is_none() {
+        namespace.storage().create_dir(directory_create.path())?;
     } else {
         log::error!(
             "Directory to create already exists: `{}`. Inserting change regardless.",
@@ -21,7 +26,9 @@ pub async fn handle_directory_create(
     }
 
     let change_event = data::ChangeEvent::Directory(data::DirectoryEvent::Create(directory_create));
-    server_database::insert_change(change_event, db_pool).await?;
+    database
+        .insert_change(namespace.id(), change_event, 0)
+        .await?;
 
     Ok(())
 }
@@ -1,27 +1,131 @@
-use std::fs;
+use hcs_lib::data;
 
-use hcs_lib::{data, server_database};
+use crate::{acl, config, db, errors, quota, symlinks};
 
 pub async fn handle_directory_delete(
-    db_pool: &sqlx::PgPool,
-    file_handler_config: &server_database::ServerFileHandlerConfig,
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    access: &acl::AccessControl,
+    consistency_config: &config::ConsistencyConfig,
     directory_delete: data::DirectoryDelete,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let delete_path = file_handler_config
-        .storage_directory()
-        .join(directory_delete.path());
+    access.check_write_tree(directory_delete.path())?;
+    symlinks::check_ancestors(namespace.storage(), directory_delete.path())?;
 
-    if delete_path.exists() {
-        fs::remove_dir_all(delete_path)?;
+    let mut freed = 0;
+    if let Some(stat) = namespace.storage().stat(directory_delete.path())? {
+        if !stat.is_dir() {
+            return Err(errors::ServerTcpError::PathConflict {
+                path: directory_delete.path().to_string(),
+                existing: directory_delete.path().to_string(),
+            }
+            .into());
+        }
+        freed = quota::storage_usage(namespace.storage(), directory_delete.path())?;
+        namespace.storage().remove_dir(directory_delete.path())?;
     } else {
-        log::error!(
-            "Directory to delete does not exist: `{}`. Inserting change regardless.",
-            directory_delete.path()
-        );
+        match consistency_config.missing_paths() {
+            config::MissingPathAction::Record => log::error!(
+                "Directory to delete does not exist: `{}`. Inserting change regardless.",
+                directory_delete.path()
+            ),
+            config::MissingPathAction::Reject => {
+                return Err(errors::ServerTcpError::PathNotFound(
+                    directory_delete.path().to_string(),
+                )
+                .into());
+            }
+            config::MissingPathAction::Reconcile => {
+                log::info!(
+                    "Directory to delete does not exist: `{}`. Nothing to record.",
+                    directory_delete.path()
+                );
+                return Ok(());
+            }
+        }
     }
 
+    database
+        .remove_checksums(namespace.id(), directory_delete.path())
+        .await?;
+
     let change_event = data::ChangeEvent::Directory(data::DirectoryEvent::Delete(directory_delete));
-    server_database::insert_change(change_event, db_pool).await?;
+    database
+        .insert_change(namespace.id(), change_event, -(freed as i64))
+        .await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::storage::StorageBackend;
+    use crate::test_support;
+
+    #[tokio::test]
+    async fn directories_are_deleted_with_their_contents() {
+        let database = test_support::memory_database().await;
+        let (namespace, storage) = test_support::memory_namespace(&database, "test").await;
+        storage.create_dir("dir/sub").unwrap();
+        test_support::write_file(storage.as_ref(), "dir/sub/a.txt", "12345");
+        test_support::write_file(storage.as_ref(), "b.txt", "123");
+        database
+            .record_checksum(namespace.id(), "dir/sub/a.txt", "checksum")
+            .await
+            .unwrap();
+        assert_eq!(quota::usage(&database, &namespace).await.unwrap(), 8);
+        let access = acl::AccessControl::load(&database, &namespace, None)
+            .await
+            .unwrap();
+
+        handle_directory_delete(
+            &database,
+            &namespace,
+            &access,
+            &config::ConsistencyConfig::default(),
+            data::DirectoryDelete::new("dir".to_string()),
+        )
+        .await
+        .unwrap();
+
+        assert!(storage.stat("dir").unwrap().is_none());
+        assert_eq!(quota::usage(&database, &namespace).await.unwrap(), 3);
+        assert!(database
+            .get_checksums(namespace.id())
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            test_support::recorded_changes(&database, &namespace).await,
+            ["delete dir/"]
+        );
+    }
+
+    #[tokio::test]
+    async fn files_are_not_deleted_as_directories() {
+        let database = test_support::memory_database().await;
+        let (namespace, storage) = test_support::memory_namespace(&database, "test").await;
+        test_support::write_file(storage.as_ref(), "a.txt", "");
+        let access = acl::AccessControl::load(&database, &namespace, None)
+            .await
+            .unwrap();
+
+        let err = handle_directory_delete(
+            &database,
+            &namespace,
+            &access,
+            &config::ConsistencyConfig::default(),
+            data::DirectoryDelete::new("a.txt".to_string()),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<errors::ServerTcpError>(),
+            Some(errors::ServerTcpError::PathConflict { .. })
+        ));
+        assert!(storage.stat("a.txt").unwrap().is_some());
+    }
+}
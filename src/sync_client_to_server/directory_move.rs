@@ -1,30 +1,155 @@
-use std::fs;
+use hcs_lib::{data, protocol};
 
-use hcs_lib::{data, server_database};
+use super::moves;
+use crate::{acl, config, db, errors, symlinks};
 
 pub async fn handle_directory_move(
-    db_pool: &sqlx::PgPool,
-    file_handler_config: &server_database::ServerFileHandlerConfig,
+    tcp_connection: &mut Box<protocol::TcpConnection>,
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    access: &acl::AccessControl,
+    consistency_config: &config::ConsistencyConfig,
+    move_config: &config::MoveConfig,
     directory_move: data::DirectoryMove,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let old_path = file_handler_config
-        .storage_directory()
-        .join(directory_move.from_path());
-    let new_path = file_handler_config
-        .storage_directory()
-        .join(directory_move.to_path());
-
-    if old_path.exists() {
-        fs::rename(old_path, new_path)?;
+    access.check_write_tree(directory_move.from_path())?;
+    access.check_write_tree(directory_move.to_path())?;
+    symlinks::check_ancestors(namespace.storage(), directory_move.from_path())?;
+    symlinks::check_ancestors(namespace.storage(), directory_move.to_path())?;
+
+    let mut to_path = directory_move.to_path().to_string();
+    if namespace
+        .storage()
+        .stat(directory_move.from_path())?
+        .is_some()
+    {
+        let (destination, change_events) = moves::move_entry(
+            database,
+            namespace,
+            move_config,
+            directory_move.from_path(),
+            &to_path,
+        )
+        .await?;
+        for (change_event, usage_delta) in change_events {
+            database
+                .insert_change(namespace.id(), change_event, usage_delta)
+                .await?;
+        }
+        if destination != to_path {
+            log::info!(
+                "Destination `{}` exists, moved to `{}` instead",
+                to_path,
+                destination
+            );
+            moves::notify_renamed(tcp_connection, &to_path, &destination)?;
+            to_path = destination;
+        }
     } else {
-        log::error!(
-            "Directory to move does not exist: `{}`. Inserting change regardless.",
-            directory_move.to_path()
-        );
+        match consistency_config.missing_paths() {
+            config::MissingPathAction::Record => log::error!(
+                "Directory to move does not exist: `{}`. Inserting change regardless.",
+                directory_move.from_path()
+            ),
+            config::MissingPathAction::Reject | config::MissingPathAction::Reconcile => {
+                return Err(errors::ServerTcpError::PathNotFound(
+                    directory_move.from_path().to_string(),
+                )
+                .into());
+            }
+        }
     }
 
-    let change_event = data::ChangeEvent::Directory(data::DirectoryEvent::Move(directory_move));
-    server_database::insert_change(change_event, db_pool).await?;
+    database
+        .move_checksums(namespace.id(), directory_move.from_path(), &to_path)
+        .await?;
+
+    let change_event = data::ChangeEvent::Directory(data::DirectoryEvent::Move(
+        data::DirectoryMove::new(directory_move.from_path().to_string(), to_path),
+    ));
+    database
+        .insert_change(namespace.id(), change_event, 0)
+        .await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::storage::StorageBackend;
+    use crate::test_support;
+
+    #[tokio::test]
+    async fn checksums_move_with_the_directory() {
+        let database = test_support::memory_database().await;
+        let (namespace, storage) = test_support::memory_namespace(&database, "test").await;
+        storage.create_dir("dir/sub").unwrap();
+        test_support::write_file(storage.as_ref(), "dir/sub/a.txt", "");
+        test_support::write_file(storage.as_ref(), "dir.txt", "");
+        for path in ["dir/sub/a.txt", "dir.txt"] {
+            database
+                .record_checksum(namespace.id(), path, "checksum")
+                .await
+                .unwrap();
+        }
+        let (mut tcp_connection, _peer) = test_support::loopback_connection();
+        let access = acl::AccessControl::load(&database, &namespace, None)
+            .await
+            .unwrap();
+
+        handle_directory_move(
+            &mut tcp_connection,
+            &database,
+            &namespace,
+            &access,
+            &config::ConsistencyConfig::default(),
+            &config::MoveConfig::default(),
+            data::DirectoryMove::new("dir".to_string(), "moved".to_string()),
+        )
+        .await
+        .unwrap();
+
+        assert!(storage.stat("dir").unwrap().is_none());
+        assert!(storage.stat("moved/sub/a.txt").unwrap().is_some());
+        let checksums = database.get_checksums(namespace.id()).await.unwrap();
+        let mut paths = checksums.keys().collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(paths, ["dir.txt", "moved/sub/a.txt"]);
+        assert_eq!(
+            test_support::recorded_changes(&database, &namespace).await,
+            ["move dir/ moved/"]
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_directories_are_rejected_on_reconcile() {
+        let database = test_support::memory_database().await;
+        let (namespace, _storage) = test_support::memory_namespace(&database, "test").await;
+        let (mut tcp_connection, _peer) = test_support::loopback_connection();
+        let access = acl::AccessControl::load(&database, &namespace, None)
+            .await
+            .unwrap();
+
+        let err = handle_directory_move(
+            &mut tcp_connection,
+            &database,
+            &namespace,
+            &access,
+            &toml::from_str("missing_paths = \"reconcile\"").unwrap(),
+            &config::MoveConfig::default(),
+            data::DirectoryMove::new("missing".to_string(), "moved".to_string()),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<errors::ServerTcpError>(),
+            Some(errors::ServerTcpError::PathNotFound(path)) if path == "missing"
+        ));
+        assert!(test_support::recorded_changes(&database, &namespace)
+            .await
+            .is_empty());
+    }
+}
@@ -1,27 +1,149 @@
-use std::{fs, io::Write};
+use hcs_lib::{data, protocol};
 
-use hcs_lib::{data, protocol, server_database};
+use super::upload_checks;
+use crate::{acl, config, db, ignore_rules, quota};
 
 pub async fn handle_file_create(
     tcp_connection: &mut Box<protocol::TcpConnection>,
-    db_pool: &sqlx::PgPool,
-    file_handler_config: &server_database::ServerFileHandlerConfig,
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    access: &acl::AccessControl,
+    ignore_rules: &ignore_rules::IgnoreRules,
+    upload_config: &config::UploadConfig,
     file_create: data::FileCreate,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let packets = protocol::calculate_num_packets(file_create.size());
-    let mut file = fs::File::create(
-        file_handler_config
-            .storage_directory()
-            .join(file_create.path()),
-    )?;
-    for _ in 0..packets {
-        log::debug!("Reading next chunk");
-        let buffer = tcp_connection.read_next_chunk()?;
-        log::debug!("Writing next chunk");
-        file.write(buffer)?;
+
+    match ignore_rules.check(file_create.path(), false) {
+        Ok(true) => {}
+        Ok(false) => {
+            log::info!("Dropping ignored file `{}`", file_create.path());
+            upload_checks::discard_chunks(tcp_connection, packets)?;
+            return Ok(());
+        }
+        Err(err) => {
+            upload_checks::discard_chunks(tcp_connection, packets)?;
+            return Err(err.into());
+        }
     }
 
+    let reserved = match upload_checks::check_upload(
+        database,
+        namespace,
+        access,
+        upload_config,
+        file_create.path(),
+        file_create.size(),
+    )
+    .await
+    {
+        Ok(reserved) => reserved,
+        Err(err) => {
+            upload_checks::discard_chunks(tcp_connection, packets)?;
+            return Err(err);
+        }
+    };
+
+    let received =
+        upload_checks::receive_chunks(tcp_connection, namespace, file_create.path(), packets);
+    if received.is_err() {
+        quota::release(database, namespace, reserved).await?;
+    }
+    let sha256 = received?;
+
+    if file_create.path().rsplit('/').next() == Some(ignore_rules::IGNORE_FILE_NAME) {
+        ignore_rules.invalidate(file_create.path());
+    }
+
+    database
+        .record_checksum(namespace.id(), file_create.path(), &sha256)
+        .await?;
+
     let change_event = data::ChangeEvent::File(data::FileEvent::Create(file_create));
-    server_database::insert_change(change_event, db_pool).await?;
+    database
+        .insert_change(namespace.id(), change_event, 0)
+        .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::storage::StorageBackend;
+    use crate::test_support;
+
+    const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[tokio::test]
+    async fn created_files_are_stored_with_their_checksum() {
+        let database = test_support::memory_database().await;
+        let (namespace, storage) = test_support::memory_namespace(&database, "test").await;
+        let (mut tcp_connection, _peer) = test_support::loopback_connection();
+        let access = acl::AccessControl::load(&database, &namespace, None)
+            .await
+            .unwrap();
+        let ignore_rules = ignore_rules::IgnoreRules::new(
+            storage.clone(),
+            namespace.storage_directory(),
+            config::IgnoreAction::Drop,
+        );
+
+        handle_file_create(
+            &mut tcp_connection,
+            &database,
+            &namespace,
+            &access,
+            &ignore_rules,
+            &config::UploadConfig::default(),
+            data::FileCreate::new("a.txt".to_string(), 0),
+        )
+        .await
+        .unwrap();
+
+        assert!(storage.stat("a.txt").unwrap().unwrap().is_file());
+        let checksums = database.get_checksums(namespace.id()).await.unwrap();
+        assert_eq!(checksums["a.txt"], EMPTY_SHA256);
+        assert_eq!(
+            test_support::recorded_changes(&database, &namespace).await,
+            ["create a.txt"]
+        );
+        assert_eq!(
+            database.get_server_version(namespace.id()).await.unwrap(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn ignored_files_are_dropped() {
+        let database = test_support::memory_database().await;
+        let (namespace, storage) = test_support::memory_namespace(&database, "test").await;
+        test_support::write_file(storage.as_ref(), ignore_rules::IGNORE_FILE_NAME, "*.tmp\n");
+        let (mut tcp_connection, _peer) = test_support::loopback_connection();
+        let access = acl::AccessControl::load(&database, &namespace, None)
+            .await
+            .unwrap();
+        let ignore_rules = ignore_rules::IgnoreRules::new(
+            storage.clone(),
+            namespace.storage_directory(),
+            config::IgnoreAction::Drop,
+        );
+
+        handle_file_create(
+            &mut tcp_connection,
+            &database,
+            &namespace,
+            &access,
+            &ignore_rules,
+            &config::UploadConfig::default(),
+            data::FileCreate::new("scratch.tmp".to_string(), 0),
+        )
+        .await
+        .unwrap();
+
+        assert!(storage.stat("scratch.tmp").unwrap().is_none());
+        assert!(test_support::recorded_changes(&database, &namespace)
+            .await
+            .is_empty());
+    }
+}
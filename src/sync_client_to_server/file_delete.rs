@@ -1,27 +1,184 @@
-use std::fs;
+use hcs_lib::data;
 
-use hcs_lib::{data, server_database};
+use crate::{acl, config, db, errors, symlinks};
 
 pub async fn handle_file_delete(
-    db_pool: &sqlx::PgPool,
-    file_handler_config: &server_database::ServerFileHandlerConfig,
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    access: &acl::AccessControl,
+    consistency_config: &config::ConsistencyConfig,
     file_delete: data::FileDelete,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let delete_path = file_handler_config
-        .storage_directory()
-        .join(file_delete.path());
+    access.check_write(file_delete.path())?;
+    symlinks::check_ancestors(namespace.storage(), file_delete.path())?;
 
-    if delete_path.exists() {
-        fs::remove_file(delete_path)?;
+    let mut freed = 0;
+    if let Some(stat) = namespace.storage().stat(file_delete.path())? {
+        if stat.is_dir() {
+            return Err(errors::ServerTcpError::PathConflict {
+                path: file_delete.path().to_string(),
+                existing: file_delete.path().to_string(),
+            }
+            .into());
+        }
+        namespace.storage().remove_file(file_delete.path())?;
+        freed = stat.size();
     } else {
-        log::error!(
-            "File to delete does not exist: `{}`. Inserting change regardless.",
-            file_delete.path()
-        );
+        match consistency_config.missing_paths() {
+            config::MissingPathAction::Record => log::error!(
+                "File to delete does not exist: `{}`. Inserting change regardless.",
+                file_delete.path()
+            ),
+            config::MissingPathAction::Reject => {
+                return Err(
+                    errors::ServerTcpError::PathNotFound(file_delete.path().to_string()).into(),
+                );
+            }
+            config::MissingPathAction::Reconcile => {
+                log::info!(
+                    "File to delete does not exist: `{}`. Nothing to record.",
+                    file_delete.path()
+                );
+                return Ok(());
+            }
+        }
     }
 
+    database
+        .remove_checksums(namespace.id(), file_delete.path())
+        .await?;
+
     let change_event = data::ChangeEvent::File(data::FileEvent::Delete(file_delete));
-    server_database::insert_change(change_event, db_pool).await?;
+    database
+        .insert_change(namespace.id(), change_event, -(freed as i64))
+        .await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::storage::StorageBackend;
+    use crate::{quota, test_support};
+
+    fn consistency_config(missing_paths: &str) -> config::ConsistencyConfig {
+        toml::from_str(&format!("missing_paths = {:?}", missing_paths)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn deletes_free_their_usage() {
+        let database = test_support::memory_database().await;
+        let (namespace, storage) = test_support::memory_namespace(&database, "test").await;
+        test_support::write_file(storage.as_ref(), "a.txt", "12345");
+        test_support::write_file(storage.as_ref(), "b.txt", "123");
+        database
+            .record_checksum(namespace.id(), "a.txt", "checksum")
+            .await
+            .unwrap();
+        assert_eq!(quota::usage(&database, &namespace).await.unwrap(), 8);
+        let access = acl::AccessControl::load(&database, &namespace, None)
+            .await
+            .unwrap();
+
+        handle_file_delete(
+            &database,
+            &namespace,
+            &access,
+            &consistency_config("reject"),
+            data::FileDelete::new("a.txt".to_string()),
+        )
+        .await
+        .unwrap();
+
+        assert!(storage.stat("a.txt").unwrap().is_none());
+        assert_eq!(quota::usage(&database, &namespace).await.unwrap(), 3);
+        assert!(database
+            .get_checksums(namespace.id())
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            test_support::recorded_changes(&database, &namespace).await,
+            ["delete a.txt"]
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_files_follow_the_consistency_config() {
+        let database = test_support::memory_database().await;
+        let (namespace, _storage) = test_support::memory_namespace(&database, "test").await;
+        let access = acl::AccessControl::load(&database, &namespace, None)
+            .await
+            .unwrap();
+        let delete = || data::FileDelete::new("missing.txt".to_string());
+
+        let err = handle_file_delete(
+            &database,
+            &namespace,
+            &access,
+            &consistency_config("reject"),
+            delete(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::ServerTcpError>(),
+            Some(errors::ServerTcpError::PathNotFound(path)) if path == "missing.txt"
+        ));
+
+        handle_file_delete(
+            &database,
+            &namespace,
+            &access,
+            &consistency_config("reconcile"),
+            delete(),
+        )
+        .await
+        .unwrap();
+        assert!(test_support::recorded_changes(&database, &namespace)
+            .await
+            .is_empty());
+
+        handle_file_delete(
+            &database,
+            &namespace,
+            &access,
+            &consistency_config("record"),
+            delete(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            test_support::recorded_changes(&database, &namespace).await,
+            ["delete missing.txt"]
+        );
+    }
+
+    #[tokio::test]
+    async fn directories_are_not_deleted_as_files() {
+        let database = test_support::memory_database().await;
+        let (namespace, storage) = test_support::memory_namespace(&database, "test").await;
+        storage.create_dir("dir").unwrap();
+        let access = acl::AccessControl::load(&database, &namespace, None)
+            .await
+            .unwrap();
+
+        let err = handle_file_delete(
+            &database,
+            &namespace,
+            &access,
+            &consistency_config("record"),
+            data::FileDelete::new("dir".to_string()),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<errors::ServerTcpError>(),
+            Some(errors::ServerTcpError::PathConflict { .. })
+        ));
+        assert!(storage.stat("dir").unwrap().unwrap().is_dir());
+    }
+}
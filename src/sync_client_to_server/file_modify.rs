@@ -1,25 +1,118 @@
-use std::{fs, io::Write};
+use hcs_lib::{data, protocol};
 
-use hcs_lib::{data, protocol, server_database};
+use super::upload_checks;
+use crate::{acl, config, db, ignore_rules, quota};
 
 pub async fn handle_file_modify(
     tcp_connection: &mut Box<protocol::TcpConnection>,
-    db_pool: &sqlx::PgPool,
-    file_handler_config: &server_database::ServerFileHandlerConfig,
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    access: &acl::AccessControl,
+    ignore_rules: &ignore_rules::IgnoreRules,
+    upload_config: &config::UploadConfig,
     file_modify: data::FileModify,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let packets = protocol::calculate_num_packets(file_modify.size());
-    let mut file = fs::File::create(
-        file_handler_config
-            .storage_directory()
-            .join(file_modify.path()),
-    )?;
-    for _ in 0..packets {
-        let buffer = tcp_connection.read_next_chunk()?;
-        file.write(buffer)?;
+
+    match ignore_rules.check(file_modify.path(), false) {
+        Ok(true) => {}
+        Ok(false) => {
+            log::info!("Dropping ignored file `{}`", file_modify.path());
+            upload_checks::discard_chunks(tcp_connection, packets)?;
+            return Ok(());
+        }
+        Err(err) => {
+            upload_checks::discard_chunks(tcp_connection, packets)?;
+            return Err(err.into());
+        }
+    }
+
+    let reserved = match upload_checks::check_upload(
+        database,
+        namespace,
+        access,
+        upload_config,
+        file_modify.path(),
+        file_modify.size(),
+    )
+    .await
+    {
+        Ok(reserved) => reserved,
+        Err(err) => {
+            upload_checks::discard_chunks(tcp_connection, packets)?;
+            return Err(err);
+        }
+    };
+
+    let received =
+        upload_checks::receive_chunks(tcp_connection, namespace, file_modify.path(), packets);
+    if received.is_err() {
+        quota::release(database, namespace, reserved).await?;
     }
+    let sha256 = received?;
+
+    if file_modify.path().rsplit('/').next() == Some(ignore_rules::IGNORE_FILE_NAME) {
+        ignore_rules.invalidate(file_modify.path());
+    }
+
+    database
+        .record_checksum(namespace.id(), file_modify.path(), &sha256)
+        .await?;
 
     let change_event = data::ChangeEvent::File(data::FileEvent::Modify(file_modify));
-    server_database::insert_change(change_event, db_pool).await?;
+    database
+        .insert_change(namespace.id(), change_event, 0)
+        .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::storage::StorageBackend;
+    use crate::test_support;
+
+    #[tokio::test]
+    async fn modified_files_replace_the_stored_contents() {
+        let database = test_support::memory_database().await;
+        let (namespace, storage) = test_support::memory_namespace(&database, "test").await;
+        test_support::write_file(storage.as_ref(), "a.txt", "12345");
+        database
+            .record_checksum(namespace.id(), "a.txt", "checksum")
+            .await
+            .unwrap();
+        // Only the difference in size has to fit
+        database.set_quota("test", 5).await.unwrap();
+        let (mut tcp_connection, _peer) = test_support::loopback_connection();
+        let access = acl::AccessControl::load(&database, &namespace, None)
+            .await
+            .unwrap();
+        let ignore_rules = ignore_rules::IgnoreRules::new(
+            storage.clone(),
+            namespace.storage_directory(),
+            config::IgnoreAction::Drop,
+        );
+
+        handle_file_modify(
+            &mut tcp_connection,
+            &database,
+            &namespace,
+            &access,
+            &ignore_rules,
+            &config::UploadConfig::default(),
+            data::FileModify::new("a.txt".to_string(), 0),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(storage.stat("a.txt").unwrap().unwrap().size(), 0);
+        assert_eq!(quota::usage(&database, &namespace).await.unwrap(), 0);
+        let checksums = database.get_checksums(namespace.id()).await.unwrap();
+        assert_ne!(checksums["a.txt"], "checksum");
+        assert_eq!(
+            test_support::recorded_changes(&database, &namespace).await,
+            ["modify a.txt"]
+        );
+    }
+}
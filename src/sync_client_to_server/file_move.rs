@@ -1,30 +1,206 @@
-use std::fs;
+use hcs_lib::{data, protocol};
 
-use hcs_lib::{data, server_database};
+use super::{moves, reconcile};
+use crate::{acl, config, db, errors, symlinks};
 
 pub async fn handle_file_move(
-    db_pool: &sqlx::PgPool,
-    file_handler_config: &server_database::ServerFileHandlerConfig,
+    tcp_connection: &mut Box<protocol::TcpConnection>,
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    access: &acl::AccessControl,
+    consistency_config: &config::ConsistencyConfig,
+    move_config: &config::MoveConfig,
     file_move: data::FileMove,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let old_path = file_handler_config
-        .storage_directory()
-        .join(file_move.from_path());
-    let new_path = file_handler_config
-        .storage_directory()
-        .join(file_move.to_path());
-
-    if old_path.exists() {
-        fs::rename(old_path, new_path)?;
+    access.check_write(file_move.from_path())?;
+    access.check_write(file_move.to_path())?;
+    symlinks::check_ancestors(namespace.storage(), file_move.from_path())?;
+    symlinks::check_ancestors(namespace.storage(), file_move.to_path())?;
+
+    let mut to_path = file_move.to_path().to_string();
+    if namespace.storage().stat(file_move.from_path())?.is_some() {
+        let (destination, change_events) = moves::move_entry(
+            database,
+            namespace,
+            move_config,
+            file_move.from_path(),
+            &to_path,
+        )
+        .await?;
+        for (change_event, usage_delta) in change_events {
+            database
+                .insert_change(namespace.id(), change_event, usage_delta)
+                .await?;
+        }
+        if destination != to_path {
+            log::info!(
+                "Destination `{}` exists, moved to `{}` instead",
+                to_path,
+                destination
+            );
+            moves::notify_renamed(tcp_connection, &to_path, &destination)?;
+            to_path = destination;
+        }
     } else {
-        log::error!(
-            "File to move does not exist: `{}`. Inserting change regardless.",
-            file_move.to_path()
-        );
+        match consistency_config.missing_paths() {
+            config::MissingPathAction::Record => log::error!(
+                "File to move does not exist: `{}`. Inserting change regardless.",
+                file_move.from_path()
+            ),
+            config::MissingPathAction::Reject => {
+                return Err(errors::ServerTcpError::PathNotFound(
+                    file_move.from_path().to_string(),
+                )
+                .into());
+            }
+            config::MissingPathAction::Reconcile => {
+                log::info!(
+                    "File to move does not exist: `{}`. Asking the client to upload `{}`.",
+                    file_move.from_path(),
+                    file_move.to_path()
+                );
+                reconcile::request_upload(tcp_connection, file_move.to_path())?;
+                return Ok(());
+            }
+        }
     }
 
-    let change_event = data::ChangeEvent::File(data::FileEvent::Move(file_move));
-    server_database::insert_change(change_event, db_pool).await?;
+    database
+        .move_checksums(namespace.id(), file_move.from_path(), &to_path)
+        .await?;
+
+    let change_event = data::ChangeEvent::File(data::FileEvent::Move(data::FileMove::new(
+        file_move.from_path().to_string(),
+        to_path,
+    )));
+    database
+        .insert_change(namespace.id(), change_event, 0)
+        .await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::storage::StorageBackend;
+    use crate::test_support;
+
+    fn move_config(collision: &str) -> config::MoveConfig {
+        toml::from_str(&format!("collision = {:?}", collision)).unwrap()
+    }
+
+    fn file_move(from_path: &str, to_path: &str) -> data::FileMove {
+        data::FileMove::new(from_path.to_string(), to_path.to_string())
+    }
+
+    #[tokio::test]
+    async fn missing_parents_are_created_before_the_move() {
+        let database = test_support::memory_database().await;
+        let (namespace, storage) = test_support::memory_namespace(&database, "test").await;
+        test_support::write_file(storage.as_ref(), "a.txt", "");
+        database
+            .record_checksum(namespace.id(), "a.txt", "checksum")
+            .await
+            .unwrap();
+        let (mut tcp_connection, _peer) = test_support::loopback_connection();
+        let access = acl::AccessControl::load(&database, &namespace, None)
+            .await
+            .unwrap();
+
+        handle_file_move(
+            &mut tcp_connection,
+            &database,
+            &namespace,
+            &access,
+            &config::ConsistencyConfig::default(),
+            &move_config("reject"),
+            file_move("a.txt", "x/y/a.txt"),
+        )
+        .await
+        .unwrap();
+
+        assert!(storage.stat("a.txt").unwrap().is_none());
+        assert!(storage.stat("x/y/a.txt").unwrap().unwrap().is_file());
+        let checksums = database.get_checksums(namespace.id()).await.unwrap();
+        assert_eq!(checksums.keys().collect::<Vec<_>>(), ["x/y/a.txt"]);
+        assert_eq!(
+            test_support::recorded_changes(&database, &namespace).await,
+            ["create x/", "create x/y/", "move a.txt x/y/a.txt"]
+        );
+    }
+
+    #[tokio::test]
+    async fn collisions_follow_the_move_config() {
+        let database = test_support::memory_database().await;
+        let (namespace, storage) = test_support::memory_namespace(&database, "test").await;
+        test_support::write_file(storage.as_ref(), "a.txt", "a");
+        test_support::write_file(storage.as_ref(), "b.txt", "b");
+        let (mut tcp_connection, _peer) = test_support::loopback_connection();
+        let access = acl::AccessControl::load(&database, &namespace, None)
+            .await
+            .unwrap();
+
+        let err = handle_file_move(
+            &mut tcp_connection,
+            &database,
+            &namespace,
+            &access,
+            &config::ConsistencyConfig::default(),
+            &move_config("reject"),
+            file_move("a.txt", "b.txt"),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::ServerTcpError>(),
+            Some(errors::ServerTcpError::DestinationExists(path)) if path == "b.txt"
+        ));
+        assert!(storage.stat("a.txt").unwrap().is_some());
+
+        handle_file_move(
+            &mut tcp_connection,
+            &database,
+            &namespace,
+            &access,
+            &config::ConsistencyConfig::default(),
+            &move_config("rename"),
+            file_move("a.txt", "b.txt"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(storage.read_range("b (1).txt", 0, 1).unwrap(), b"a");
+        assert_eq!(storage.read_range("b.txt", 0, 1).unwrap(), b"b");
+        assert_eq!(
+            test_support::recorded_changes(&database, &namespace).await,
+            ["move a.txt b (1).txt"]
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_files_are_requested_on_reconcile() {
+        let database = test_support::memory_database().await;
+        let (namespace, _storage) = test_support::memory_namespace(&database, "test").await;
+        let (mut tcp_connection, _peer) = test_support::loopback_connection();
+        let access = acl::AccessControl::load(&database, &namespace, None)
+            .await
+            .unwrap();
+
+        handle_file_move(
+            &mut tcp_connection,
+            &database,
+            &namespace,
+            &access,
+            &toml::from_str("missing_paths = \"reconcile\"").unwrap(),
+            &move_config("reject"),
+            file_move("missing.txt", "b.txt"),
+        )
+        .await
+        .unwrap();
+
+        assert!(test_support::recorded_changes(&database, &namespace)
+            .await
+            .is_empty());
+    }
+}
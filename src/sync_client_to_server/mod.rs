@@ -5,6 +5,12 @@ mod file_create;
 mod file_delete;
 mod file_modify;
 mod file_move;
+mod moves;
+mod reconcile;
+mod symlink_create;
+mod symlink_delete;
+mod symlink_move;
+mod upload_checks;
 
 pub use directory_create::handle_directory_create;
 pub use directory_delete::handle_directory_delete;
@@ -13,3 +19,7 @@ pub use file_create::handle_file_create;
 pub use file_delete::handle_file_delete;
 pub use file_modify::handle_file_modify;
 pub use file_move::handle_file_move;
+pub use symlink_create::handle_symlink_create;
+pub use symlink_delete::handle_symlink_delete;
+pub use symlink_move::handle_symlink_move;
+pub use upload_checks::discard_chunks;
@@ -0,0 +1,350 @@
+use std::{fs, io, path, time};
+
+use hcs_lib::{data, protocol};
+
+use crate::{config, db, errors, extra_data, quota, serve::transmission_type_to_bytes, storage};
+
+/// Moves `from_path` to `to_path` in the namespace's storage, creating
+/// missing parents and applying the collision policy. Returns the path the
+/// entry ended up at, which only differs from `to_path` under the rename
+/// policy, and the changes to record before the move itself, each with the
+/// change in usage it brings.
+pub async fn move_entry(
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    move_config: &config::MoveConfig,
+    from_path: &str,
+    to_path: &str,
+) -> Result<(String, Vec<(data::ChangeEvent, i64)>), Box<dyn std::error::Error>> {
+    let storage = namespace.storage();
+    let mut change_events = Vec::new();
+    let is_file = matches!(storage.stat(from_path)?, Some(stat) if stat.is_file());
+    let mut destination = to_path.to_string();
+    // Where the overwritten destination was kept, to put it back if the move
+    // fails
+    let mut kept = None;
+
+    let collision = match move_config.collision() {
+        // No name can be made up in place of an encrypted one
+        config::CollisionPolicy::Rename if namespace.is_end_to_end() => {
+            config::CollisionPolicy::Reject
+        }
+        collision => collision,
+    };
+    match storage.stat(to_path)? {
+        Some(stat) => match collision {
+            config::CollisionPolicy::Reject => {
+                return Err(errors::ServerTcpError::DestinationExists(to_path.to_string()).into());
+            }
+            config::CollisionPolicy::Overwrite => {
+                let Some(local_path) = storage.local_path(to_path) else {
+                    log::warn!(
+                        "Not overwriting `{}`: previous versions can only be kept on local storage",
+                        to_path
+                    );
+                    return Err(
+                        errors::ServerTcpError::DestinationExists(to_path.to_string()).into(),
+                    );
+                };
+                let freed = match stat.is_dir() {
+                    true => quota::storage_usage(storage, to_path)?,
+                    false => stat.size(),
+                };
+                let version_path = keep_version(namespace, move_config, to_path, &local_path)?;
+                kept = Some((local_path, version_path));
+                let change_event = if stat.is_dir() {
+                    data::ChangeEvent::Directory(data::DirectoryEvent::Delete(
+                        data::DirectoryDelete::new(to_path.to_string()),
+                    ))
+                } else {
+                    data::ChangeEvent::File(data::FileEvent::Delete(data::FileDelete::new(
+                        to_path.to_string(),
+                    )))
+                };
+                change_events.push((change_event, -(freed as i64)));
+            }
+            config::CollisionPolicy::Rename => {
+                destination = free_path(storage, to_path, is_file)?;
+            }
+        },
+        None => {
+            change_events.extend(
+                create_parents(storage, to_path)?
+                    .into_iter()
+                    .map(|change_event| (change_event, 0)),
+            );
+        }
+    }
+
+    if let Err(err) = storage.rename(from_path, &destination) {
+        if let Some((local_path, version_path)) = &kept {
+            if let Err(restore_err) = storage::rename_or_copy(version_path, local_path) {
+                log::error!(
+                    "Failed to restore `{}` from `{}`: {}",
+                    to_path,
+                    version_path.display(),
+                    restore_err
+                );
+            }
+        }
+        return Err(err.into());
+    }
+    if let Some((_, version_path)) = kept {
+        log::info!(
+            "Kept previous `{}` as `{}` before overwriting it",
+            to_path,
+            version_path.display()
+        );
+        database.remove_checksums(namespace.id(), to_path).await?;
+    }
+    Ok((destination, change_events))
+}
+
+/// Tells the client its move was stored under a different name.
+pub fn notify_renamed(
+    tcp_connection: &mut Box<protocol::TcpConnection>,
+    requested_path: &str,
+    path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let transmission = data::Transmission::<errors::ServerTcpError, extra_data::ExtraData>::Other(
+        extra_data::ExtraData::MoveRenamed {
+            requested_path: requested_path.to_string(),
+            path: path.to_string(),
+        },
+    );
+    let bytes = transmission_type_to_bytes(transmission)?;
+    tcp_connection.write(&bytes)?;
+    Ok(())
+}
+
+/// Creates the missing ancestors of `path`, returning a create for each one,
+/// outermost first.
+fn create_parents(
+    storage: &dyn storage::StorageBackend,
+    path: &str,
+) -> io::Result<Vec<data::ChangeEvent>> {
+    let mut missing = Vec::new();
+    let mut parent = path::Path::new(path).parent();
+    while let Some(directory) = parent {
+        let directory_path = directory.to_string_lossy().to_string();
+        if directory_path.is_empty() || storage.stat(&directory_path)?.is_some() {
+            break;
+        }
+        missing.push(directory_path);
+        parent = directory.parent();
+    }
+
+    let mut change_events = Vec::with_capacity(missing.len());
+    for directory in missing.into_iter().rev() {
+        storage.create_dir(&directory)?;
+        change_events.push(data::ChangeEvent::Directory(data::DirectoryEvent::Create(
+            data::DirectoryCreate::new(directory),
+        )));
+    }
+    Ok(change_events)
+}
+
+/// Moves the existing `path`, stored at `local_path`, to
+/// `<versions_directory>/<namespace>/<path>.<unix time>`, numbered like
+/// `free_path` if that is taken.
+fn keep_version(
+    namespace: &db::namespaces::Namespace,
+    move_config: &config::MoveConfig,
+    path: &str,
+    local_path: &path::Path,
+) -> io::Result<path::PathBuf> {
+    let timestamp = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let versions_directory = move_config.versions_directory().join(namespace.name());
+    let version_name = format!("{}.{}", path, timestamp);
+    let version_path = std::iter::once(version_name.clone())
+        .chain(numbered_paths(&version_name, false))
+        .map(|name| versions_directory.join(name))
+        .find(|candidate| fs::symlink_metadata(candidate).is_err())
+        .expect("ran out of candidate names");
+    if let Some(parent) = version_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    storage::rename_or_copy(local_path, &version_path)?;
+    Ok(version_path)
+}
+
+/// `name (1).ext`, `name (2).ext`, ... next to `path`. The extension is only
+/// kept apart for files, so a directory `photos.d` becomes `photos.d (1)`.
+fn numbered_paths(path: &str, is_file: bool) -> impl Iterator<Item = String> {
+    let (parent, name) = match path.rsplit_once('/') {
+        Some((parent, name)) => (parent.to_string(), name),
+        None => (String::new(), path),
+    };
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if is_file && !stem.is_empty() => {
+            (stem.to_string(), format!(".{}", extension))
+        }
+        _ => (name.to_string(), String::new()),
+    };
+    (1..).map(move |n| storage::join(&parent, &format!("{} ({}){}", stem, n, extension)))
+}
+
+/// The first numbered path next to `path` that does not exist yet.
+fn free_path(
+    storage: &dyn storage::StorageBackend,
+    path: &str,
+    is_file: bool,
+) -> io::Result<String> {
+    for candidate in numbered_paths(path, is_file) {
+        if storage.stat(&candidate)?.is_none() {
+            return Ok(candidate);
+        }
+    }
+    unreachable!("ran out of candidate names")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::db::Database;
+    use crate::storage::StorageBackend;
+    use crate::test_support;
+
+    #[test]
+    fn only_files_keep_their_extension() {
+        let numbered = |path, is_file| numbered_paths(path, is_file).take(2).collect::<Vec<_>>();
+        assert_eq!(
+            numbered("dir/a.txt", true),
+            ["dir/a (1).txt", "dir/a (2).txt"]
+        );
+        assert_eq!(
+            numbered("dir/photos.d", false),
+            ["dir/photos.d (1)", "dir/photos.d (2)"]
+        );
+        assert_eq!(numbered(".profile", true), [".profile (1)", ".profile (2)"]);
+        assert_eq!(numbered("Makefile", true), ["Makefile (1)", "Makefile (2)"]);
+    }
+
+    #[test]
+    fn free_path_skips_taken_names() {
+        let storage = storage::MemoryStorage::new();
+        storage.create_dir("dir").unwrap();
+        test_support::write_file(&storage, "dir/a.txt", "");
+        test_support::write_file(&storage, "dir/a (1).txt", "");
+        assert_eq!(
+            free_path(&storage, "dir/a.txt", true).unwrap(),
+            "dir/a (2).txt"
+        );
+        assert_eq!(
+            free_path(&storage, "dir/a.txt", false).unwrap(),
+            "dir/a.txt (1)"
+        );
+    }
+
+    #[test]
+    fn kept_versions_do_not_replace_each_other() {
+        let root = std::env::temp_dir().join(format!("hcs-keep-version-{}", std::process::id()));
+        let storage_directory = root.join("storage");
+        fs::create_dir_all(&storage_directory).unwrap();
+        let storage = Arc::new(storage::LocalStorage::new(storage_directory.clone()));
+        let namespace = db::namespaces::Namespace::with_storage(
+            (
+                1,
+                "test".to_string(),
+                storage_directory.to_string_lossy().to_string(),
+                false,
+            ),
+            storage.clone(),
+        );
+        let move_config: config::MoveConfig =
+            toml::from_str(&format!("versions_directory = {:?}", root.join("versions"))).unwrap();
+
+        let local_path = storage_directory.join("a.txt");
+        test_support::write_file(storage.as_ref(), "a.txt", "first");
+        let first = keep_version(&namespace, &move_config, "a.txt", &local_path).unwrap();
+        test_support::write_file(storage.as_ref(), "a.txt", "second");
+        let second = keep_version(&namespace, &move_config, "a.txt", &local_path).unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(fs::read_to_string(&first).unwrap(), "first");
+        assert_eq!(fs::read_to_string(&second).unwrap(), "second");
+        assert!(storage.stat("a.txt").unwrap().is_none());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_overwrites_restore_the_destination() {
+        let root = std::env::temp_dir().join(format!("hcs-restore-version-{}", std::process::id()));
+        let storage_directory = root.join("storage");
+        fs::create_dir_all(&storage_directory).unwrap();
+        let database = test_support::memory_database().await;
+        let row = database
+            .insert_namespace("test", &storage_directory.to_string_lossy(), false)
+            .await
+            .unwrap();
+        let storage = Arc::new(storage::LocalStorage::new(storage_directory.clone()));
+        let namespace = db::namespaces::Namespace::with_storage(row, storage.clone());
+        database
+            .record_checksum(namespace.id(), "b.txt", "checksum")
+            .await
+            .unwrap();
+        let move_config: config::MoveConfig = toml::from_str(&format!(
+            "collision = \"overwrite\"\nversions_directory = {:?}",
+            root.join("versions")
+        ))
+        .unwrap();
+        test_support::write_file(storage.as_ref(), "b.txt", "b");
+
+        // The source is gone, so the rename after keeping `b.txt` fails
+        move_entry(&database, &namespace, &move_config, "a.txt", "b.txt")
+            .await
+            .unwrap_err();
+
+        assert_eq!(storage.read_range("b.txt", 0, 1).unwrap(), b"b");
+        assert!(fs::read_dir(root.join("versions").join("test"))
+            .unwrap()
+            .next()
+            .is_none());
+        let checksums = database.get_checksums(namespace.id()).await.unwrap();
+        assert_eq!(checksums["b.txt"], "checksum");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn overwrites_need_local_storage() {
+        let database = test_support::memory_database().await;
+        let (namespace, storage) = test_support::memory_namespace(&database, "test").await;
+        test_support::write_file(storage.as_ref(), "a.txt", "a");
+        test_support::write_file(storage.as_ref(), "b.txt", "b");
+        let move_config: config::MoveConfig = toml::from_str("collision = \"overwrite\"").unwrap();
+
+        let err = move_entry(&database, &namespace, &move_config, "a.txt", "b.txt")
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<errors::ServerTcpError>(),
+            Some(errors::ServerTcpError::DestinationExists(path)) if path == "b.txt"
+        ));
+        assert_eq!(storage.read_range("a.txt", 0, 1).unwrap(), b"a");
+        assert_eq!(storage.read_range("b.txt", 0, 1).unwrap(), b"b");
+    }
+
+    #[tokio::test]
+    async fn renamed_moves_end_up_next_to_the_destination() {
+        let database = test_support::memory_database().await;
+        let (namespace, storage) = test_support::memory_namespace(&database, "test").await;
+        storage.create_dir("dir").unwrap();
+        storage.create_dir("other").unwrap();
+        let move_config: config::MoveConfig = toml::from_str("collision = \"rename\"").unwrap();
+
+        let (destination, change_events) =
+            move_entry(&database, &namespace, &move_config, "dir", "other")
+                .await
+                .unwrap();
+
+        assert_eq!(destination, "other (1)");
+        assert!(change_events.is_empty());
+        assert!(storage.stat("other (1)").unwrap().unwrap().is_dir());
+    }
+}
@@ -0,0 +1,15 @@
+use hcs_lib::{data, protocol};
+
+use crate::{errors, extra_data, serve::transmission_type_to_bytes};
+
+pub fn request_upload(
+    tcp_connection: &mut Box<protocol::TcpConnection>,
+    path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let transmission = data::Transmission::<errors::ServerTcpError, extra_data::ExtraData>::Other(
+        extra_data::ExtraData::UploadRequest(path.to_string()),
+    );
+    let bytes = transmission_type_to_bytes(transmission)?;
+    tcp_connection.write(&bytes)?;
+    Ok(())
+}
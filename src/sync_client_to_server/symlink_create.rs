@@ -0,0 +1,32 @@
+use crate::{acl, db, errors, ignore_rules, symlinks};
+
+pub async fn handle_symlink_create(
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    access: &acl::AccessControl,
+    ignore_rules: &ignore_rules::IgnoreRules,
+    symlink_create: symlinks::SymlinkCreate,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = symlink_create.path();
+    access.check_write(path)?;
+    if !ignore_rules.check(path, false)? {
+        log::info!("Dropping ignored symlink `{}`", path);
+        return Ok(());
+    }
+    symlinks::check_ancestors(namespace.storage(), path)?;
+
+    match namespace.storage().stat(path)? {
+        Some(stat) if stat.is_symlink() => namespace.storage().remove_file(path)?,
+        Some(_) => return Err(errors::ServerTcpError::DestinationExists(path.to_string()).into()),
+        None => {}
+    }
+    namespace
+        .storage()
+        .create_symlink(symlink_create.target(), path)?;
+
+    let symlink_event = symlinks::SymlinkEvent::Create(symlink_create);
+    database
+        .insert_symlink_change(namespace.id(), &symlink_event)
+        .await?;
+    Ok(())
+}
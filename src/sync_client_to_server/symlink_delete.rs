@@ -0,0 +1,40 @@
+use crate::{acl, config, db, errors, symlinks};
+
+pub async fn handle_symlink_delete(
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    access: &acl::AccessControl,
+    consistency_config: &config::ConsistencyConfig,
+    symlink_delete: symlinks::SymlinkDelete,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = symlink_delete.path();
+    access.check_write(path)?;
+    symlinks::check_ancestors(namespace.storage(), path)?;
+
+    match namespace.storage().stat(path)? {
+        Some(stat) if stat.is_symlink() => namespace.storage().remove_file(path)?,
+        Some(_) => return Err(errors::ServerTcpError::NotASymlink(path.to_string()).into()),
+        None => match consistency_config.missing_paths() {
+            config::MissingPathAction::Record => log::error!(
+                "Symlink to delete does not exist: `{}`. Inserting change regardless.",
+                path
+            ),
+            config::MissingPathAction::Reject => {
+                return Err(errors::ServerTcpError::PathNotFound(path.to_string()).into());
+            }
+            config::MissingPathAction::Reconcile => {
+                log::info!(
+                    "Symlink to delete does not exist: `{}`. Nothing to record.",
+                    path
+                );
+                return Ok(());
+            }
+        },
+    }
+
+    let symlink_event = symlinks::SymlinkEvent::Delete(symlink_delete);
+    database
+        .insert_symlink_change(namespace.id(), &symlink_event)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,68 @@
+use hcs_lib::protocol;
+
+use super::{moves, reconcile};
+use crate::{acl, config, db, errors, symlinks};
+
+pub async fn handle_symlink_move(
+    tcp_connection: &mut Box<protocol::TcpConnection>,
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    access: &acl::AccessControl,
+    consistency_config: &config::ConsistencyConfig,
+    move_config: &config::MoveConfig,
+    symlink_move: symlinks::SymlinkMove,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let from_path = symlink_move.from_path();
+    access.check_write(from_path)?;
+    access.check_write(symlink_move.to_path())?;
+    symlinks::check_ancestors(namespace.storage(), from_path)?;
+    symlinks::check_ancestors(namespace.storage(), symlink_move.to_path())?;
+
+    let mut to_path = symlink_move.to_path().to_string();
+    if symlinks::is_symlink(namespace.storage(), from_path) {
+        let (destination, change_events) =
+            moves::move_entry(database, namespace, move_config, from_path, &to_path).await?;
+        for (change_event, usage_delta) in change_events {
+            database
+                .insert_change(namespace.id(), change_event, usage_delta)
+                .await?;
+        }
+        if destination != to_path {
+            log::info!(
+                "Destination `{}` exists, moved to `{}` instead",
+                to_path,
+                destination
+            );
+            moves::notify_renamed(tcp_connection, &to_path, &destination)?;
+            to_path = destination;
+        }
+    } else if namespace.storage().stat(from_path)?.is_some() {
+        return Err(errors::ServerTcpError::NotASymlink(from_path.to_string()).into());
+    } else {
+        match consistency_config.missing_paths() {
+            config::MissingPathAction::Record => log::error!(
+                "Symlink to move does not exist: `{}`. Inserting change regardless.",
+                from_path
+            ),
+            config::MissingPathAction::Reject => {
+                return Err(errors::ServerTcpError::PathNotFound(from_path.to_string()).into());
+            }
+            config::MissingPathAction::Reconcile => {
+                log::info!(
+                    "Symlink to move does not exist: `{}`. Asking the client to upload `{}`.",
+                    from_path,
+                    to_path
+                );
+                reconcile::request_upload(tcp_connection, &to_path)?;
+                return Ok(());
+            }
+        }
+    }
+
+    let symlink_event =
+        symlinks::SymlinkEvent::Move(symlinks::SymlinkMove::new(from_path.to_string(), to_path));
+    database
+        .insert_symlink_change(namespace.id(), &symlink_event)
+        .await?;
+    Ok(())
+}
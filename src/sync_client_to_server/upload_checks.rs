@@ -0,0 +1,92 @@
+use std::io::{self, Write};
+
+use hcs_lib::protocol;
+use sha2::{Digest, Sha256};
+
+use crate::{acl, config, db, errors, quota, symlinks};
+
+/// Runs every check that must pass before the first chunk of an upload to
+/// `path` is written, and reserves room for it in the namespace's quota.
+/// Returns the reservation, to be released if the upload does not complete.
+pub async fn check_upload(
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+    access: &acl::AccessControl,
+    upload_config: &config::UploadConfig,
+    path: &str,
+    size: u64,
+) -> Result<i64, Box<dyn std::error::Error>> {
+    access.check_write(path)?;
+    symlinks::check_not_symlink(namespace.storage(), path)?;
+    check_file_size(upload_config, size)?;
+    check_free_space(namespace, upload_config, size)?;
+    quota::reserve(database, namespace, path, size).await
+}
+
+fn check_file_size(
+    upload_config: &config::UploadConfig,
+    size: u64,
+) -> Result<(), errors::ServerTcpError> {
+    match upload_config.max_file_size() {
+        Some(max_file_size) if size > max_file_size => Err(errors::ServerTcpError::FileTooLarge {
+            max_file_size,
+            requested: size,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Only applies to storage on the local filesystem.
+fn check_free_space(
+    namespace: &db::namespaces::Namespace,
+    upload_config: &config::UploadConfig,
+    size: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let storage_directory = match namespace.storage().local_path("") {
+        Some(storage_directory) => storage_directory,
+        None => return Ok(()),
+    };
+    let available = fs2::available_space(storage_directory)?;
+    let reserve = upload_config.free_space_reserve();
+    if size.saturating_add(reserve) > available {
+        return Err(errors::ServerTcpError::InsufficientSpace {
+            available,
+            reserve,
+            requested: size,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Reads and drops the chunks of a rejected upload so the connection stays in
+/// step with the client.
+pub fn discard_chunks(
+    tcp_connection: &mut Box<protocol::TcpConnection>,
+    packets: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for _ in 0..packets {
+        tcp_connection.read_next_chunk()?;
+    }
+    Ok(())
+}
+
+/// Writes the chunks of an upload to `path` and returns their SHA-256.
+pub fn receive_chunks(
+    tcp_connection: &mut Box<protocol::TcpConnection>,
+    namespace: &db::namespaces::Namespace,
+    path: &str,
+    packets: u64,
+) -> io::Result<String> {
+    let mut writer = namespace.storage().open_write(path)?;
+    let mut hasher = Sha256::new();
+    for _ in 0..packets {
+        log::debug!("Reading next chunk");
+        let buffer = tcp_connection.read_next_chunk()?;
+        log::debug!("Writing next chunk");
+        writer.write_all(buffer)?;
+        hasher.update(buffer);
+    }
+    writer.finish()?;
+    Ok(hex::encode(hasher.finalize()))
+}
@@ -0,0 +1,189 @@
+use std::path;
+
+use crate::errors;
+
+/// Include and exclude glob patterns a client registered to sync only part
+/// of a namespace. A path is synced if it or one of its parent directories
+/// matches an include pattern (or there are none), and neither it nor a parent
+/// matches an exclude pattern. Directories an include pattern may match paths
+/// below are synced as well, so included paths have their parents created.
+#[derive(Debug, Clone)]
+pub struct SyncFilter {
+    include_patterns: Vec<String>,
+    exclude_patterns: Vec<String>,
+    include: globset::GlobSet,
+    exclude: globset::GlobSet,
+    /// The include patterns, split into their components.
+    include_components: Vec<Vec<Component>>,
+}
+
+#[derive(Debug, Clone)]
+enum Component {
+    /// `**`, any number of path components.
+    AnyDepth,
+    Glob(globset::GlobMatcher),
+}
+
+fn build_glob(pattern: &str) -> Result<globset::Glob, errors::ServerTcpError> {
+    globset::GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map_err(|err| errors::ServerTcpError::InvalidSyncFilter(err.to_string()))
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<globset::GlobSet, errors::ServerTcpError> {
+    let mut builder = globset::GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(build_glob(pattern.trim_matches('/'))?);
+    }
+    builder
+        .build()
+        .map_err(|err| errors::ServerTcpError::InvalidSyncFilter(err.to_string()))
+}
+
+fn split_pattern(pattern: &str) -> Result<Vec<Component>, errors::ServerTcpError> {
+    pattern
+        .trim_matches('/')
+        .split('/')
+        .map(|component| match component {
+            "**" => Ok(Component::AnyDepth),
+            _ => Ok(Component::Glob(build_glob(component)?.compile_matcher())),
+        })
+        .collect()
+}
+
+/// Whether `pattern` may match a path below the directory made of `path`.
+fn matches_below(pattern: &[Component], path: &[&str]) -> bool {
+    match (pattern.first(), path.first()) {
+        (None, _) => false,
+        (Some(_), None) => true,
+        (Some(Component::AnyDepth), Some(_)) => {
+            matches_below(&pattern[1..], path) || matches_below(pattern, &path[1..])
+        }
+        (Some(Component::Glob(glob)), Some(name)) => {
+            glob.is_match(name) && matches_below(&pattern[1..], &path[1..])
+        }
+    }
+}
+
+impl SyncFilter {
+    pub fn new(
+        include_patterns: Vec<String>,
+        exclude_patterns: Vec<String>,
+    ) -> Result<Self, errors::ServerTcpError> {
+        Ok(Self {
+            include: build_glob_set(&include_patterns)?,
+            exclude: build_glob_set(&exclude_patterns)?,
+            include_components: include_patterns
+                .iter()
+                .map(|pattern| split_pattern(pattern))
+                .collect::<Result<_, _>>()?,
+            include_patterns,
+            exclude_patterns,
+        })
+    }
+
+    /// Filter that syncs everything.
+    pub fn all() -> Self {
+        Self::new(Vec::new(), Vec::new()).unwrap()
+    }
+
+    pub fn include_patterns(&self) -> &[String] {
+        &self.include_patterns
+    }
+
+    pub fn exclude_patterns(&self) -> &[String] {
+        &self.exclude_patterns
+    }
+
+    pub fn matches(&self, path: &str, is_dir: bool) -> bool {
+        let trimmed = path.trim_matches('/');
+        let path = path::Path::new(trimmed);
+        let included = self.include_patterns.is_empty()
+            || path
+                .ancestors()
+                .any(|ancestor| self.include.is_match(ancestor))
+            || (is_dir && self.contains_included(trimmed));
+        included
+            && !path
+                .ancestors()
+                .any(|ancestor| self.exclude.is_match(ancestor))
+    }
+
+    /// Whether an include pattern may match a path below `directory`.
+    fn contains_included(&self, directory: &str) -> bool {
+        let components = directory.split('/').collect::<Vec<_>>();
+        self.include_components
+            .iter()
+            .any(|pattern| matches_below(pattern, &components))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sync_filter(include: &[&str], exclude: &[&str]) -> SyncFilter {
+        let patterns = |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect();
+        SyncFilter::new(patterns(include), patterns(exclude)).unwrap()
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        let filter = SyncFilter::all();
+        assert!(filter.matches("a", false));
+        assert!(filter.matches("a/b/c.txt", false));
+    }
+
+    #[test]
+    fn include_matches_the_path_or_a_parent() {
+        let filter = sync_filter(&["/photos/"], &[]);
+        assert!(filter.matches("photos", false));
+        assert!(filter.matches("photos/2023/a.jpg", false));
+        assert!(!filter.matches("photos2", false));
+        assert!(!filter.matches("documents/photos", false));
+    }
+
+    #[test]
+    fn wildcards_do_not_cross_separators() {
+        let filter = sync_filter(&["*.txt"], &[]);
+        assert!(filter.matches("notes.txt", false));
+        assert!(!filter.matches("dir/notes.txt", false));
+
+        let filter = sync_filter(&["**/*.txt"], &[]);
+        assert!(filter.matches("dir/notes.txt", false));
+        assert!(filter.matches("notes.txt", false));
+    }
+
+    #[test]
+    fn parents_of_included_paths_are_matched() {
+        let filter = sync_filter(&["docs/**/*.md"], &["docs/private"]);
+        assert!(filter.matches("docs", true));
+        assert!(filter.matches("docs/guide/api", true));
+        assert!(filter.matches("docs/guide/api/index.md", false));
+        assert!(!filter.matches("docs/guide/notes.txt", false));
+        assert!(!filter.matches("docs", false));
+        assert!(!filter.matches("src", true));
+        assert!(!filter.matches("docs/private", true));
+
+        let filter = sync_filter(&["projects/*/README"], &[]);
+        assert!(filter.matches("projects/app", true));
+        assert!(!filter.matches("projects/app/src", true));
+    }
+
+    #[test]
+    fn exclude_wins_over_include() {
+        let filter = sync_filter(&["projects"], &["projects/*/target", "**/.cache"]);
+        assert!(filter.matches("projects/app/src/main.rs", false));
+        assert!(!filter.matches("projects/app/target", false));
+        assert!(!filter.matches("projects/app/target/debug/app", false));
+        assert!(!filter.matches("projects/.cache/x", false));
+        assert!(!filter.matches("other", false));
+    }
+
+    #[test]
+    fn invalid_pattern_is_rejected() {
+        let err = SyncFilter::new(vec!["[".to_string()], Vec::new()).unwrap_err();
+        assert!(matches!(err, errors::ServerTcpError::InvalidSyncFilter(_)));
+    }
+}
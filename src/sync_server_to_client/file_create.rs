@@ -1,21 +1,38 @@
-use std::fs;
-
 use std::io::Read;
 
-use crate::{errors, extra_data, serve::transmission_type_to_bytes};
-use hcs_lib::{data, protocol, server_database};
+use crate::{db, errors, extra_data, metadata, serve::transmission_type_to_bytes, symlinks};
+use hcs_lib::{data, protocol};
 
 pub fn handle_file_create(
     tcp_connection: &mut Box<protocol::TcpConnection>,
-    file_handler_config: &server_database::ServerFileHandlerConfig,
+    namespace: &db::namespaces::Namespace,
     mut file_create: data::FileCreate,
+    metadata: Option<metadata::FileMetadata>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let file_path = file_handler_config
-        .storage_directory()
-        .join(file_create.path());
-    let file_size = fs::metadata(&file_path)?.len();
+    let storage = namespace.storage();
+    let path = file_create.path().to_string();
+    symlinks::check_not_symlink(storage, file_create.path())?;
+    let file_size = match storage.stat(file_create.path())? {
+        Some(stat) => stat.size(),
+        None => {
+            return Err(errors::ServerTcpError::PathNotFound(file_create.path().to_string()).into())
+        }
+    };
 
     file_create.set_size(file_size);
+    if let Some(metadata) = metadata {
+        // Send metadata ahead of the change event, for the client to apply
+        // once it has written the file
+        let transmission =
+            data::Transmission::<errors::ServerTcpError, extra_data::ExtraData>::Other(
+                extra_data::ExtraData::FileMetadata {
+                    path: file_create.path().to_string(),
+                    metadata,
+                },
+            );
+        let bytes = transmission_type_to_bytes(transmission)?;
+        tcp_connection.write(&*bytes)?;
+    }
     {
         // Send change event to client
         let change_event = data::ChangeEvent::File(data::FileEvent::Create(file_create));
@@ -30,11 +47,13 @@ pub fn handle_file_create(
     {
         // Read the file buffer by buffer, write into tcp stream.
         let packets = protocol::calculate_num_packets(file_size);
-        let mut file = fs::File::open(&file_path)?;
-        let mut buffer = vec![0; protocol::BUFFER_SIZE];
+        let mut reader = storage.open_read(&path)?;
         for _ in 0..packets {
-            let bytes_read = file.read(&mut buffer)?;
-            tcp_connection.write(&buffer[..bytes_read])?;
+            let mut buffer = Vec::with_capacity(protocol::BUFFER_SIZE);
+            (&mut reader)
+                .take(protocol::BUFFER_SIZE as u64)
+                .read_to_end(&mut buffer)?;
+            tcp_connection.write(&buffer)?;
         }
     }
     Ok(())
@@ -5,6 +5,7 @@ mod file_create;
 mod file_delete;
 mod file_modify;
 mod file_move;
+mod symlink;
 
 pub use directory_create::handle_directory_create;
 pub use directory_delete::handle_directory_delete;
@@ -13,3 +14,4 @@ pub use file_create::handle_file_create;
 pub use file_delete::handle_file_delete;
 pub use file_modify::handle_file_modify;
 pub use file_move::handle_file_move;
+pub use symlink::handle_symlink_event;
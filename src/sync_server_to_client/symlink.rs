@@ -0,0 +1,17 @@
+use hcs_lib::{data, protocol};
+
+use crate::{errors, extra_data, serve::transmission_type_to_bytes, symlinks};
+
+pub fn handle_symlink_event(
+    tcp_connection: &mut Box<protocol::TcpConnection>,
+    symlink_event: symlinks::SymlinkEvent,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Send symlink event to client
+    let transmission = data::Transmission::<errors::ServerTcpError, extra_data::ExtraData>::Other(
+        extra_data::ExtraData::Symlink(symlink_event),
+    );
+    let bytes = transmission_type_to_bytes(transmission)?;
+    tcp_connection.write(&*bytes)?;
+
+    Ok(())
+}
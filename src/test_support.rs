@@ -0,0 +1,91 @@
+//! Helpers shared by unit tests.
+
+use std::io::Write;
+use std::net;
+use std::sync::Arc;
+
+use hcs_lib::{data, protocol};
+
+use crate::{db, storage};
+
+/// A migrated database that only lives as long as the returned value.
+pub async fn memory_database() -> db::SqliteDatabase {
+    let database = db::SqliteDatabase::open("sqlite::memory:", 1)
+        .await
+        .unwrap();
+    db::Database::migrate(&database).await.unwrap();
+    database
+}
+
+/// A new namespace named `name`, stored in memory.
+pub async fn memory_namespace(
+    database: &dyn db::Database,
+    name: &str,
+) -> (db::namespaces::Namespace, Arc<storage::MemoryStorage>) {
+    let row = database
+        .insert_namespace(name, &format!("/nonexistent/{}", name), false)
+        .await
+        .unwrap();
+    let storage = Arc::new(storage::MemoryStorage::new());
+    let namespace = db::namespaces::Namespace::with_storage(row, storage.clone());
+    (namespace, storage)
+}
+
+pub fn write_file(storage: &dyn storage::StorageBackend, path: &str, contents: &str) {
+    let mut writer = storage.open_write(path).unwrap();
+    writer.write_all(contents.as_bytes()).unwrap();
+    writer.finish().unwrap();
+}
+
+/// A connection over loopback. The peer's end is returned with it so the
+/// connection stays open, nothing is ever sent from it.
+pub fn loopback_connection() -> (Box<protocol::TcpConnection>, net::TcpStream) {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    (protocol::TcpConnection::new(stream), peer)
+}
+
+/// Kind and paths of a change, for comparing recorded changes.
+pub fn describe(change_event: &data::ChangeEvent) -> String {
+    match change_event {
+        data::ChangeEvent::File(data::FileEvent::Create(file_create)) => {
+            format!("create {}", file_create.path())
+        }
+        data::ChangeEvent::File(data::FileEvent::Modify(file_modify)) => {
+            format!("modify {}", file_modify.path())
+        }
+        data::ChangeEvent::File(data::FileEvent::Delete(file_delete)) => {
+            format!("delete {}", file_delete.path())
+        }
+        data::ChangeEvent::File(data::FileEvent::Move(file_move)) => {
+            format!("move {} {}", file_move.from_path(), file_move.to_path())
+        }
+        data::ChangeEvent::Directory(data::DirectoryEvent::Create(directory_create)) => {
+            format!("create {}/", directory_create.path())
+        }
+        data::ChangeEvent::Directory(data::DirectoryEvent::Delete(directory_delete)) => {
+            format!("delete {}/", directory_delete.path())
+        }
+        data::ChangeEvent::Directory(data::DirectoryEvent::Move(directory_move)) => format!(
+            "move {}/ {}/",
+            directory_move.from_path(),
+            directory_move.to_path()
+        ),
+        other => format!("{:?}", other),
+    }
+}
+
+/// Every change recorded in the namespace, oldest first.
+pub async fn recorded_changes(
+    database: &dyn db::Database,
+    namespace: &db::namespaces::Namespace,
+) -> Vec<String> {
+    database
+        .get_changes(namespace.id(), 0, i64::MAX)
+        .await
+        .unwrap()
+        .iter()
+        .map(|(_, change_event)| describe(change_event))
+        .collect()
+}